@@ -0,0 +1,185 @@
+//! 追加UVの数の変更に合わせて頂点とUVモーフを組み替える
+
+use PMXUtil::types::{Frame, FrameInner, Morph, MorphKinds, Vertex};
+
+///追加UVの数を減らすと失われるデータ
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct UvDiscard {
+    ///消えるチャンネルに0以外の値を持つ頂点の数
+    pub vertices: usize,
+    ///消えるチャンネルを操作するUVモーフの名前
+    pub morphs: Vec<String>,
+}
+impl UvDiscard {
+    pub fn is_empty(&self) -> bool {
+        self.vertices == 0 && self.morphs.is_empty()
+    }
+}
+
+///追加UVモーフが操作するチャンネル。追加UV1が0
+pub fn morph_channel(morph: &MorphKinds) -> Option<usize> {
+    match morph {
+        MorphKinds::UV1(_) => Some(0),
+        MorphKinds::UV2(_) => Some(1),
+        MorphKinds::UV3(_) => Some(2),
+        MorphKinds::UV4(_) => Some(3),
+        _ => None,
+    }
+}
+
+///追加UVを`count`個にしたときに失われるデータ
+pub fn discarded(vertices: &[Vertex], morphs: &[Morph], count: u8) -> UvDiscard {
+    let count = count as usize;
+    UvDiscard {
+        vertices: vertices
+            .iter()
+            .filter(|vertex| {
+                vertex.add_uv[count.min(4)..]
+                    .iter()
+                    .any(|channel| channel.iter().any(|&value| value != 0.0))
+            })
+            .count(),
+        morphs: morphs
+            .iter()
+            .filter(|morph| {
+                morph_channel(&morph.morph_data).is_some_and(|channel| channel >= count)
+            })
+            .map(|morph| morph.name.clone())
+            .collect(),
+    }
+}
+
+///追加UVを`from`個から`to`個にする
+///
+/// 増減したチャンネルの値は0にし、使わなくなったチャンネルのUVモーフは削除する。
+/// 削除したモーフはグループモーフ・フリップモーフと表示枠からも取り除き、残りの参照を付け替える
+pub fn resize_additional_uv(
+    vertices: &mut [Vertex],
+    morphs: &mut Vec<Morph>,
+    frames: &mut [Frame],
+    from: u8,
+    to: u8,
+) {
+    let channels = (from.min(to) as usize).min(4)..(from.max(to) as usize).min(4);
+    for vertex in vertices.iter_mut() {
+        for channel in channels.clone() {
+            vertex.add_uv[channel] = [0.0; 4];
+        }
+    }
+    let mut new_index = Vec::with_capacity(morphs.len());
+    let mut kept = 0;
+    for morph in morphs.iter() {
+        if morph_channel(&morph.morph_data).is_some_and(|channel| channel >= to as usize) {
+            new_index.push(-1);
+        } else {
+            new_index.push(kept);
+            kept += 1;
+        }
+    }
+    if kept == morphs.len() as i32 {
+        return;
+    }
+    let index = |old: i32| match new_index.get(old as usize) {
+        Some(&new) if old >= 0 => new,
+        _ => -1,
+    };
+    let mut position = 0;
+    morphs.retain(|_| {
+        position += 1;
+        new_index[position - 1] >= 0
+    });
+    for morph in morphs.iter_mut() {
+        match &mut morph.morph_data {
+            MorphKinds::Group(offsets) => {
+                offsets.retain(|offset| index(offset.index) >= 0);
+                for offset in offsets.iter_mut() {
+                    offset.index = index(offset.index);
+                }
+            }
+            MorphKinds::Flip(offsets) => {
+                offsets.retain(|offset| index(offset.index) >= 0);
+                for offset in offsets.iter_mut() {
+                    offset.index = index(offset.index);
+                }
+            }
+            _ => {}
+        }
+    }
+    for frame in frames.iter_mut() {
+        frame.inners.retain(|inner| match inner {
+            FrameInner::Morph(morph) => index(*morph) >= 0,
+            FrameInner::Bone(_) => true,
+        });
+        for inner in frame.inners.iter_mut() {
+            if let FrameInner::Morph(morph) = inner {
+                *morph = index(*morph);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_resize_additional_uv() {
+    use crate::vertex_edit::test_vertex;
+    use PMXUtil::types::{ControlPanel, GroupMorph, UVMorph, VertexWeight};
+    let vertex = |add_uv: [[f32; 4]; 4]| Vertex {
+        add_uv,
+        ..test_vertex([0.0; 3], VertexWeight::BDEF1(0))
+    };
+    let morph = |name: &str, morph_data: MorphKinds| Morph {
+        name: name.to_string(),
+        english_name: String::new(),
+        control_panel: ControlPanel::BottomRight,
+        morph_data,
+    };
+    let uv = vec![UVMorph {
+        index: 0,
+        offset: [1.0; 4],
+    }];
+    let group = |index: i32| GroupMorph {
+        index,
+        morph_factor: 1.0,
+    };
+    let mut vertices = vec![
+        vertex([[1.0; 4], [0.0; 4], [0.0; 4], [0.0; 4]]),
+        vertex([[1.0; 4], [2.0; 4], [0.0; 4], [0.0; 4]]),
+    ];
+    let mut morphs = vec![
+        morph("uv1", MorphKinds::UV1(uv.clone())),
+        morph("uv2", MorphKinds::UV2(uv.clone())),
+        morph("uv", MorphKinds::UV(uv)),
+        morph(
+            "group",
+            MorphKinds::Group(vec![group(0), group(1), group(2)]),
+        ),
+    ];
+    let mut frames = vec![Frame {
+        name: "表情".to_string(),
+        name_en: String::new(),
+        is_special: false,
+        inners: vec![
+            FrameInner::Morph(1),
+            FrameInner::Morph(2),
+            FrameInner::Bone(0),
+        ],
+    }];
+
+    let discard = discarded(&vertices, &morphs, 1);
+    assert_eq!(discard.vertices, 1);
+    assert_eq!(discard.morphs, vec!["uv2".to_string()]);
+    assert!(discarded(&vertices, &morphs, 2).is_empty());
+
+    resize_additional_uv(&mut vertices, &mut morphs, &mut frames, 2, 1);
+    assert_eq!(vertices[1].add_uv[1], [0.0; 4]);
+    assert_eq!(vertices[1].add_uv[0], [1.0; 4]);
+    let names: Vec<&str> = morphs.iter().map(|morph| morph.name.as_str()).collect();
+    assert_eq!(names, vec!["uv1", "uv", "group"]);
+    assert_eq!(
+        morphs[2].morph_data,
+        MorphKinds::Group(vec![group(0), group(1)])
+    );
+    assert_eq!(
+        frames[0].inners,
+        vec![FrameInner::Morph(1), FrameInner::Bone(0)]
+    );
+}
@@ -0,0 +1,324 @@
+//! ボーンの並べ替え・削除に合わせてボーンインデックスの参照を付け替える
+
+use crate::pose::{weight_influences, Pose};
+use crate::vertex_edit::{collapse_weights, influences};
+use PMXUtil::types::{
+    Bone, ConnectionDisplayMode, Frame, FrameInner, Morph, MorphKinds, Rigid,
+    RotateAndTranslateInherits, Vertex, VertexWeight,
+};
+
+///ボーンの新しい並び
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneRemap {
+    ///新しい並びでの元のインデックス。含まれないボーンは削除される
+    order: Vec<usize>,
+    ///元のインデックスから新しいインデックスへ。削除したボーンは残っている祖先(なければ-1)
+    new_index: Vec<i32>,
+    ///削除したボーン
+    removed: Vec<bool>,
+}
+
+impl BoneRemap {
+    pub fn from_order(bones: &[Bone], order: Vec<usize>) -> Self {
+        let mut direct = vec![-1; bones.len()];
+        for (new, &old) in order.iter().enumerate() {
+            direct[old] = new as i32;
+        }
+        let removed: Vec<bool> = direct.iter().map(|&index| index < 0).collect();
+        let new_index = (0..bones.len())
+            .map(|old| {
+                let mut current = old as i32;
+                for _ in 0..bones.len() {
+                    match direct.get(current as usize) {
+                        Some(&new) if current >= 0 && new >= 0 => return new,
+                        Some(_) if current >= 0 => current = bones[current as usize].parent,
+                        _ => return -1,
+                    }
+                }
+                -1
+            })
+            .collect();
+        Self {
+            order,
+            new_index,
+            removed,
+        }
+    }
+    pub fn is_identity(&self) -> bool {
+        self.order.len() == self.new_index.len()
+            && self.order.iter().enumerate().all(|(new, &old)| new == old)
+    }
+    ///元のインデックスを新しいインデックスにする。範囲外や-1はそのまま-1
+    pub fn index(&self, old: i32) -> i32 {
+        match self.new_index.get(old as usize) {
+            Some(&new) if old >= 0 => new,
+            _ => -1,
+        }
+    }
+    fn is_removed(&self, old: i32) -> bool {
+        old >= 0 && self.removed.get(old as usize).copied().unwrap_or(false)
+    }
+    ///新しい並びに合わせて要素を並べ替える
+    pub fn permute<T: Clone>(&self, values: &[T]) -> Vec<T> {
+        self.order.iter().map(|&old| values[old].clone()).collect()
+    }
+    pub fn apply_bones(&self, bones: &mut Vec<Bone>) {
+        *bones = self.permute(bones);
+        for bone in bones.iter_mut() {
+            bone.parent = self.index(bone.parent);
+            if let ConnectionDisplayMode::OtherBone(index) = &mut bone.connection_display_mode {
+                *index = if self.is_removed(*index) {
+                    -1
+                } else {
+                    self.index(*index)
+                };
+            }
+            //付与親を削除したら付与は外す。祖先へ付け替えると別の付与や自己付与になる
+            let inherits = &mut bone.inherits.rotate_and_translate;
+            match inherits {
+                RotateAndTranslateInherits::None => {}
+                RotateAndTranslateInherits::Both(parent, _)
+                | RotateAndTranslateInherits::Rotate(parent, _)
+                | RotateAndTranslateInherits::Translate(parent, _) => {
+                    if self.is_removed(*parent) {
+                        *inherits = RotateAndTranslateInherits::None;
+                    } else {
+                        *parent = self.index(*parent);
+                    }
+                }
+            }
+            if let Some(ik_info) = &mut bone.ik_info {
+                ik_info.ik_target_bone_index = if self.is_removed(ik_info.ik_target_bone_index) {
+                    -1
+                } else {
+                    self.index(ik_info.ik_target_bone_index)
+                };
+                ik_info
+                    .ik_links
+                    .retain(|link| !self.is_removed(link.ik_bone_index));
+                for link in ik_info.ik_links.iter_mut() {
+                    link.ik_bone_index = self.index(link.ik_bone_index);
+                }
+            }
+        }
+    }
+    ///削除したボーンのウェイトは残っている祖先へ移す
+    ///
+    /// 祖先も残っていないウェイトは外し、残りのボーンで正規化し直す
+    pub fn apply_vertices(&self, vertices: &mut [Vertex]) {
+        for vertex in vertices.iter_mut() {
+            match &mut vertex.weight_type {
+                VertexWeight::BDEF1(index) => *index = self.index(*index),
+                VertexWeight::BDEF2 {
+                    bone_index_1,
+                    bone_index_2,
+                    ..
+                }
+                | VertexWeight::SDEF {
+                    bone_index_1,
+                    bone_index_2,
+                    ..
+                } => {
+                    *bone_index_1 = self.index(*bone_index_1);
+                    *bone_index_2 = self.index(*bone_index_2);
+                }
+                VertexWeight::BDEF4 {
+                    bone_index_1,
+                    bone_index_2,
+                    bone_index_3,
+                    bone_index_4,
+                    ..
+                }
+                | VertexWeight::QDEF {
+                    bone_index_1,
+                    bone_index_2,
+                    bone_index_3,
+                    bone_index_4,
+                    ..
+                } => {
+                    *bone_index_1 = self.index(*bone_index_1);
+                    *bone_index_2 = self.index(*bone_index_2);
+                    *bone_index_3 = self.index(*bone_index_3);
+                    *bone_index_4 = self.index(*bone_index_4);
+                }
+            }
+            if weight_influences(&vertex.weight_type)
+                .iter()
+                .any(|&(index, weight)| index < 0 && weight > 0.0)
+            {
+                collapse_weights(vertex);
+            }
+        }
+    }
+    ///ウェイトを持つボーンがすべて消え、移す祖先もない頂点の数
+    pub fn orphaned_vertices(&self, vertices: &[Vertex]) -> usize {
+        vertices
+            .iter()
+            .filter(|vertex| {
+                let influences = influences(&vertex.weight_type);
+                !influences.is_empty() && influences.iter().all(|&(index, _)| self.index(index) < 0)
+            })
+            .count()
+    }
+    ///削除したボーンのボーンモーフ要素は取り除く
+    pub fn apply_morphs(&self, morphs: &mut [Morph]) {
+        for morph in morphs.iter_mut() {
+            if let MorphKinds::Bone(offsets) = &mut morph.morph_data {
+                offsets.retain(|offset| !self.is_removed(offset.index));
+                for offset in offsets.iter_mut() {
+                    offset.index = self.index(offset.index);
+                }
+            }
+        }
+    }
+    ///削除したボーンは表示枠から取り除く
+    pub fn apply_frames(&self, frames: &mut [Frame]) {
+        for frame in frames.iter_mut() {
+            frame.inners.retain(|inner| match inner {
+                FrameInner::Bone(index) => !self.is_removed(*index),
+                FrameInner::Morph(_) => true,
+            });
+            for inner in frame.inners.iter_mut() {
+                if let FrameInner::Bone(index) = inner {
+                    *index = self.index(*index);
+                }
+            }
+        }
+    }
+    ///削除したボーンの剛体はボーンから外す。祖先へ付け替えると物理で祖先が動いてしまう
+    pub fn apply_rigids(&self, rigids: &mut [Rigid]) {
+        for rigid in rigids.iter_mut() {
+            rigid.bone_index = if self.is_removed(rigid.bone_index) {
+                -1
+            } else {
+                self.index(rigid.bone_index)
+            };
+        }
+    }
+    pub fn apply_pose(&self, pose: &mut Pose) {
+        pose.bones = self.permute(&pose.bones);
+        pose.ik_enabled = self.permute(&pose.ik_enabled);
+    }
+}
+
+///親が子より前に来る並びを返す。元の並びをなるべく保つ
+///
+/// 循環している親は無視する
+pub fn parent_first_order(bones: &[Bone]) -> Vec<usize> {
+    let mut emitted = vec![false; bones.len()];
+    let mut order = Vec::with_capacity(bones.len());
+    for index in 0..bones.len() {
+        //まだ出していない祖先を根元側から出す
+        let mut chain = vec![];
+        let mut current = index as i32;
+        while current >= 0
+            && (current as usize) < bones.len()
+            && !emitted[current as usize]
+            && !chain.contains(&(current as usize))
+        {
+            chain.push(current as usize);
+            current = bones[current as usize].parent;
+        }
+        for &index in chain.iter().rev() {
+            emitted[index] = true;
+            order.push(index);
+        }
+    }
+    order
+}
+
+#[test]
+fn test_remap_bones() {
+    use crate::vertex_edit::test_vertex;
+    use PMXUtil::types::{BoneIKInfo, IKLink};
+    let bone = |parent: i32| Bone {
+        parent,
+        ..Bone::default()
+    };
+    //2の親が後ろにある
+    let mut bones = vec![bone(-1), bone(2), bone(0), bone(1)];
+    bones[0].ik_info = Some(BoneIKInfo {
+        ik_target_bone_index: 3,
+        ik_iter_count: 40,
+        ik_limit_angle: 2.0,
+        ik_links: vec![
+            IKLink {
+                ik_bone_index: 1,
+                angle_limit: None,
+            },
+            IKLink {
+                ik_bone_index: 2,
+                angle_limit: None,
+            },
+        ],
+    });
+    let order = parent_first_order(&bones);
+    assert_eq!(order, vec![0, 2, 1, 3]);
+    let remap = BoneRemap::from_order(&bones, order);
+    assert!(!remap.is_identity());
+    let mut remapped = bones.clone();
+    remap.apply_bones(&mut remapped);
+    let parents: Vec<i32> = remapped.iter().map(|bone| bone.parent).collect();
+    assert_eq!(parents, vec![-1, 0, 1, 2]);
+
+    //1を削除すると子の3は祖先の2へ付け替わり、IKリンクからは消える
+    let remap = BoneRemap::from_order(&bones, vec![0, 2, 3]);
+    remap.apply_bones(&mut bones);
+    assert_eq!(bones[2].parent, 1);
+    let ik_info = bones[0].ik_info.as_ref().unwrap();
+    assert_eq!(ik_info.ik_target_bone_index, 2);
+    assert_eq!(ik_info.ik_links.len(), 1);
+    assert_eq!(ik_info.ik_links[0].ik_bone_index, 1);
+
+    //削除したボーンを付与親に持つ付与と、削除したボーンの剛体は外れる
+    let mut bones = vec![bone(-1), bone(0), bone(0), bone(1)];
+    bones[2].inherits.rotate_and_translate = RotateAndTranslateInherits::Rotate(1, 1.0);
+    bones[3].inherits.rotate_and_translate = RotateAndTranslateInherits::Both(2, 0.5);
+    let rigid = |bone_index: i32| Rigid {
+        name: String::new(),
+        name_en: String::new(),
+        bone_index,
+        group: 0,
+        un_collision_group_flag: 0,
+        form: PMXUtil::types::RigidForm::Sphere,
+        size: [1.0; 3],
+        position: [0.0; 3],
+        rotation: [0.0; 3],
+        mass: 1.0,
+        move_resist: 0.5,
+        rotation_resist: 0.5,
+        repulsion: 0.0,
+        friction: 0.5,
+        calc_method: PMXUtil::types::RigidCalcMethod::Dynamic,
+    };
+    let mut rigids = vec![rigid(1), rigid(3)];
+    let remap = BoneRemap::from_order(&bones, vec![0, 2, 3]);
+    remap.apply_bones(&mut bones);
+    remap.apply_rigids(&mut rigids);
+    assert_eq!(
+        bones[1].inherits.rotate_and_translate,
+        RotateAndTranslateInherits::None
+    );
+    assert_eq!(
+        bones[2].inherits.rotate_and_translate,
+        RotateAndTranslateInherits::Both(1, 0.5)
+    );
+    assert_eq!(rigids[0].bone_index, -1);
+    assert_eq!(rigids[1].bone_index, 2);
+
+    //ルートを削除するとそのウェイトの移し先がない
+    let vertex = |weight_type: VertexWeight| test_vertex([0.0; 3], weight_type);
+    let mut vertices = vec![
+        vertex(VertexWeight::BDEF1(0)),
+        vertex(VertexWeight::BDEF2 {
+            bone_index_1: 0,
+            bone_index_2: 1,
+            bone_weight_1: 0.5,
+        }),
+    ];
+    let remap = BoneRemap::from_order(&bones, vec![1, 2]);
+    assert_eq!(remap.orphaned_vertices(&vertices), 1);
+    //一部のウェイトだけ移し先がなければ外して残りのボーンに寄せる
+    remap.apply_vertices(&mut vertices[1..]);
+    assert_eq!(vertices[1].weight_type, VertexWeight::BDEF1(0));
+}
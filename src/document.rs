@@ -0,0 +1,203 @@
+//! 読み込んだモデル1つ分のデータ
+//!
+//! 各タブはコピーを持たずにこれを直接読み書きし、変えた部分を`notify`で知らせる。
+//! メインループが1フレームに1回`take_changes`で取り出して、依存するビューを更新する
+
+use crate::global_model_state::{BoneTree, BoneTreeReport};
+use crate::index_size::IndexSizes;
+use std::io::Read;
+use std::path::Path;
+use PMXUtil::reader::ModelInfoStage;
+use PMXUtil::types::{
+    Bone, Encode, Face, Frame, Header, Joint, Material, ModelInfo, Morph, Rigid, SoftBody, Vertex,
+};
+use PMXUtil::writer::{WritePMXErrors, Writer};
+
+///前回取り出してから変わった部分
+#[derive(Debug, Copy, Clone, Default, PartialEq, Eq)]
+pub struct DocumentChanges {
+    pub header: bool,
+    pub model_info: bool,
+    pub vertices: bool,
+    ///ボーンの設定。追加や並べ替えも含む
+    pub bones: bool,
+    ///ボーンの並べ替えに伴うモーフ・表示枠・剛体・ジョイントの付け替え
+    pub bone_references: bool,
+    ///モーフの追加や削除。表示枠の付け替えも含む
+    pub morphs: bool,
+}
+impl DocumentChanges {
+    pub const MODEL_INFO: DocumentChanges = DocumentChanges {
+        model_info: true,
+        ..DocumentChanges::NONE
+    };
+    pub const VERTICES: DocumentChanges = DocumentChanges {
+        vertices: true,
+        ..DocumentChanges::NONE
+    };
+    pub const BONES: DocumentChanges = DocumentChanges {
+        bones: true,
+        ..DocumentChanges::NONE
+    };
+    ///ボーンの構造が変わり、ボーンを参照するデータもすべて付け替えた
+    pub const BONE_STRUCTURE: DocumentChanges = DocumentChanges {
+        vertices: true,
+        bones: true,
+        bone_references: true,
+        ..DocumentChanges::NONE
+    };
+    ///追加UVの数が変わり、頂点とUVモーフを組み替えた
+    pub const ADDITIONAL_UV: DocumentChanges = DocumentChanges {
+        header: true,
+        vertices: true,
+        morphs: true,
+        ..DocumentChanges::NONE
+    };
+    pub const NONE: DocumentChanges = DocumentChanges {
+        header: false,
+        model_info: false,
+        vertices: false,
+        bones: false,
+        bone_references: false,
+        morphs: false,
+    };
+    pub fn is_empty(&self) -> bool {
+        *self == DocumentChanges::NONE
+    }
+    pub fn merge(&mut self, other: DocumentChanges) {
+        self.header |= other.header;
+        self.model_info |= other.model_info;
+        self.vertices |= other.vertices;
+        self.bones |= other.bones;
+        self.bone_references |= other.bone_references;
+        self.morphs |= other.morphs;
+    }
+}
+
+pub struct PmxDocument {
+    pub header: Header,
+    pub model_info: ModelInfo,
+    pub vertices: Vec<Vertex>,
+    pub faces: Vec<Face>,
+    pub textures: Vec<String>,
+    pub materials: Vec<Material>,
+    pub bones: Vec<Bone>,
+    pub morphs: Vec<Morph>,
+    pub frames: Vec<Frame>,
+    pub rigids: Vec<Rigid>,
+    pub joints: Vec<Joint>,
+    pub soft_bodies: Vec<SoftBody>,
+    ///ボーンの親子関係。ボーンの親や並びを変えたら組み立て直す
+    pub bone_tree: BoneTree,
+    ///ボーン木を組み立てたときに見つかった不正な親子関係
+    pub tree_report: BoneTreeReport,
+    changes: DocumentChanges,
+}
+impl PmxDocument {
+    pub fn read<R: Read>(pmx: ModelInfoStage<R>) -> Self {
+        let header = pmx.get_header();
+        let (model_info, loader) = pmx.read();
+        let (vertices, loader) = loader.read();
+        let (faces, loader) = loader.read();
+        let (textures, loader) = loader.read();
+        let (materials, loader) = loader.read();
+        let (bones, loader) = loader.read();
+        let (morphs, loader) = loader.read();
+        let (frames, loader) = loader.read();
+        let (rigids, loader) = loader.read();
+        let (joints, loader) = loader.read();
+        let soft_bodies = loader.map(|loader| loader.read()).unwrap_or_default();
+        let (bone_tree, tree_report) = BoneTree::from_bones(&bones);
+        Self {
+            header,
+            model_info,
+            vertices,
+            faces,
+            textures,
+            materials,
+            bones,
+            morphs,
+            frames,
+            rigids,
+            joints,
+            soft_bodies,
+            bone_tree,
+            tree_report,
+            changes: DocumentChanges::NONE,
+        }
+    }
+    ///変えた部分を依存するビューへ知らせる
+    pub fn notify(&mut self, changes: DocumentChanges) {
+        self.changes.merge(changes);
+    }
+    pub fn take_changes(&mut self) -> DocumentChanges {
+        std::mem::take(&mut self.changes)
+    }
+    ///ボーンの親子関係からボーン木を組み立て直す
+    pub fn rebuild_bone_tree(&mut self) {
+        let (tree, report) = BoneTree::from_bones(&self.bones);
+        self.bone_tree = tree;
+        self.tree_report = report;
+    }
+    ///保存したときにヘッダーへ書かれる要素数
+    pub fn index_sizes(&self) -> IndexSizes {
+        IndexSizes {
+            counts: [
+                self.vertices.len(),
+                self.textures.len(),
+                self.materials.len(),
+                self.bones.len(),
+                self.morphs.len(),
+                self.rigids.len(),
+            ],
+        }
+    }
+    ///文字列はすべて`encode`で書く。インデックスサイズとバージョンは要素から選び直される
+    pub fn save<P: AsRef<Path>>(&self, path: P, encode: Encode) -> Result<(), WritePMXErrors> {
+        let mut writer = Writer::begin_writer(matches!(encode, Encode::Utf16Le));
+        writer.set_model_info(&self.model_info);
+        //5以上は読み込んだ時点で壊れているので4として書く
+        writer
+            .set_additional_uv(self.header.additional_uv.min(4))
+            .ok();
+        writer.add_vertices(&self.vertices);
+        writer.add_faces(&self.faces);
+        writer.add_textures(&self.textures);
+        writer.add_materials(&self.materials);
+        writer.add_bones(&self.bones);
+        writer.add_morphs(&self.morphs);
+        writer.add_frames(&self.frames);
+        writer.add_rigid_bodies(&self.rigids);
+        writer.add_joints(&self.joints);
+        writer.add_soft_bodies(&self.soft_bodies);
+        writer.write_to_path(path)
+    }
+}
+
+///保存先の既定のファイル名。モデル名のうちファイル名に使えない文字は`_`にする
+pub fn default_file_name(model_name: &str) -> String {
+    let name: String = model_name
+        .chars()
+        .map(|c| {
+            if c.is_control() || matches!(c, '\\' | '/' | ':' | '*' | '?' | '"' | '<' | '>' | '|') {
+                '_'
+            } else {
+                c
+            }
+        })
+        .collect();
+    //Windowsでは末尾の空白とピリオドが消える
+    let name = name.trim().trim_end_matches('.');
+    if name.is_empty() {
+        "model.pmx".to_owned()
+    } else {
+        format!("{}.pmx", name)
+    }
+}
+
+#[test]
+fn test_default_file_name() {
+    assert_eq!(default_file_name("未来アカリ"), "未来アカリ.pmx");
+    assert_eq!(default_file_name("../a:b*?\n"), ".._a_b___.pmx");
+    assert_eq!(default_file_name(" ... "), "model.pmx");
+}
@@ -1,131 +1,251 @@
-use std::collections::BTreeMap;
 use std::fmt::Debug;
 
-use PMXUtil::types::{Bone, ModelInfo};
+use PMXUtil::types::Bone;
 
-///モデルについての情報を表す
-#[derive(Debug)]
-pub(crate) struct Model {
-    ///モデル名
-    name: String,
-    ///ボーン木
-    pub(crate) bone_tree: Option<BoneTree>,
-}
-#[derive(Debug)]
+///親子関係を隣接リストで持つボーンの森
+///
+/// 兄弟を双方向リストでつなぐので、親の付け替えは定数時間でできる。
+/// 親のないボーンは仮の根(-1)の子として扱う
+#[derive(Debug, Clone, Default)]
 pub struct BoneTree {
-    pub(crate) id: i32,
-    pub(crate) child: BTreeMap<i32, BoneTree>,
+    parents: Vec<i32>,
+    first_child: Vec<i32>,
+    last_child: Vec<i32>,
+    next_sibling: Vec<i32>,
+    previous_sibling: Vec<i32>,
+    first_root: i32,
+    last_root: i32,
+}
+///ボーン木を組み立てたときに見つかった不正な親子関係
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct BoneTreeReport {
+    ///親のインデックスが範囲外か自分自身のボーン
+    pub orphans: Vec<i32>,
+    ///親をたどると循環するボーン。循環ごとに最小のインデックスを根として扱う
+    pub cycles: Vec<i32>,
+}
+impl BoneTreeReport {
+    pub fn is_empty(&self) -> bool {
+        self.orphans.is_empty() && self.cycles.is_empty()
+    }
 }
 
 impl BoneTree {
-    pub fn from_iter<'a>(iter: impl Iterator<Item = &'a Bone> + Clone) -> Self {
-        let root = iter
-            .clone()
+    pub fn from_bones(bones: &[Bone]) -> (Self, BoneTreeReport) {
+        let parents: Vec<i32> = bones.iter().map(|bone| bone.parent).collect();
+        Self::build(&parents)
+    }
+    ///親のインデックスから森を作る
+    ///
+    /// 親が後ろにあるボーンも拾い、親が不正なボーンや循環は根に付けて報告する
+    pub fn build(parents: &[i32]) -> (Self, BoneTreeReport) {
+        let count = parents.len();
+        let mut report = BoneTreeReport::default();
+        let mut parents: Vec<i32> = parents
+            .iter()
             .enumerate()
-            .find(|(_, bone)| bone.parent == -1)
-            .unwrap();
-        let mut root_node = BoneTree {
-            id: root.0 as i32,
-            child: Default::default(),
+            .map(|(index, &parent)| {
+                if parent == -1 {
+                    -1
+                } else if parent < 0 || parent as usize >= count || parent as usize == index {
+                    report.orphans.push(index as i32);
+                    -1
+                } else {
+                    parent
+                }
+            })
+            .collect();
+        //0:未訪問 1:たどっている途中 2:根に着いた
+        let mut state = vec![0u8; count];
+        for start in 0..count {
+            let mut path = vec![];
+            let mut current = start as i32;
+            while current >= 0 && state[current as usize] == 0 {
+                state[current as usize] = 1;
+                path.push(current as usize);
+                current = parents[current as usize];
+            }
+            if current >= 0 && state[current as usize] == 1 {
+                //今回の経路上に戻ってきたので循環
+                let position = path
+                    .iter()
+                    .position(|&index| index == current as usize)
+                    .unwrap();
+                let cycle = &path[position..];
+                let root = *cycle.iter().min().unwrap();
+                parents[root] = -1;
+                report
+                    .cycles
+                    .extend(cycle.iter().map(|&index| index as i32));
+            }
+            for index in path {
+                state[index] = 2;
+            }
+        }
+        report.cycles.sort_unstable();
+
+        let mut tree = Self {
+            parents: vec![-1; count],
+            first_child: vec![-1; count],
+            last_child: vec![-1; count],
+            next_sibling: vec![-1; count],
+            previous_sibling: vec![-1; count],
+            first_root: -1,
+            last_root: -1,
         };
-        for (index, bone) in iter.enumerate() {
-            root_node.append(index as i32, bone.clone());
+        for (index, &parent) in parents.iter().enumerate() {
+            tree.link(index, parent);
         }
-        root_node
+        (tree, report)
+    }
+    fn len(&self) -> usize {
+        self.parents.len()
     }
-    pub fn append(&mut self, child_index: i32, bone_info: Bone) {
-        if self.id == bone_info.parent {
-            //親IDが自分と一致したなら子供に入れる
-            println!("{} : inserted", child_index);
-            self.child.insert(
-                child_index,
-                BoneTree {
-                    id: child_index,
-                    child: Default::default(),
-                },
-            );
+    ///木の上での親。根なら-1
+    pub fn parent(&self, index: usize) -> i32 {
+        self.parents[index]
+    }
+    ///子を並び順に返す。`-1`なら根のボーン
+    pub fn children(&self, index: i32) -> impl Iterator<Item = usize> + '_ {
+        let first = if index < 0 {
+            self.first_root
         } else {
-            //子供の中からidと追加したい要素の親idが一致するものを探す
-            if let Some((_, tree)) = self
-                .child
-                .iter_mut()
-                .find(|(id, _)| **id == bone_info.parent)
-            {
-                println!("{} : inserted", child_index);
-                tree.child.insert(
-                    child_index,
-                    BoneTree {
-                        id: child_index,
-                        child: Default::default(),
-                    },
-                );
-            } else {
-                //一致しない場合更に子供の中に持っているものがないか調べる
-                let _: Vec<_> = self
-                    .child
-                    .values_mut()
-                    .map(|tree| tree.append(child_index, bone_info.clone()))
-                    .collect();
+            self.first_child[index as usize]
+        };
+        std::iter::successors(Some(first).filter(|&child| child >= 0), move |&child| {
+            Some(self.next_sibling[child as usize]).filter(|&next| next >= 0)
+        })
+        .map(|child| child as usize)
+    }
+    ///`ancestor`自身か、その子孫なら true
+    pub fn is_descendant(&self, index: usize, ancestor: usize) -> bool {
+        let mut current = index as i32;
+        while current >= 0 {
+            if current as usize == ancestor {
+                return true;
             }
+            current = self.parents[current as usize];
         }
+        false
     }
-    pub fn dump_tree(&self, indent_level: usize, data_source: &[Bone]) -> String {
-        let name = if self.id == -1 {
-            "Root"
+    fn head(&mut self, parent: i32) -> (&mut i32, &mut i32) {
+        if parent < 0 {
+            (&mut self.first_root, &mut self.last_root)
         } else {
-            &data_source[self.id as usize].name
-        };
-        let mut dump_text = format!("{}:{}\n", self.id, name);
-        for sub_tree in self.child.values() {
-            dump_text += &"\t".repeat(indent_level);
-            dump_text += &sub_tree.dump_tree(indent_level + 1, data_source);
+            let parent = parent as usize;
+            (&mut self.first_child[parent], &mut self.last_child[parent])
         }
-        dump_text
     }
-}
-impl Model {
-    pub fn new(model_info: ModelInfo) -> Self {
-        Self {
-            name: model_info.name.clone(),
-            bone_tree: None,
+    ///兄弟の末尾につなぐ
+    fn link(&mut self, index: usize, parent: i32) {
+        self.parents[index] = parent;
+        let (first, last) = self.head(parent);
+        let previous = *last;
+        *last = index as i32;
+        if previous < 0 {
+            *first = index as i32;
+        } else {
+            self.next_sibling[previous as usize] = index as i32;
         }
+        self.previous_sibling[index] = previous;
+        self.next_sibling[index] = -1;
     }
-    ///親のインデックスを見ながらツリーを作っていく
-    ///
-    pub fn load_bones(&mut self, bones: &[Bone]) {
-        let root = bones
-            .iter()
-            .enumerate()
-            .find(|(_, bone)| bone.parent == -1)
-            .unwrap();
-        let mut root_node = BoneTree {
-            id: root.0 as i32,
-            child: Default::default(),
-        };
-        for (index, bone) in bones.iter().enumerate() {
-            root_node.append(index as i32, bone.clone());
+    fn unlink(&mut self, index: usize) {
+        let (previous, next) = (self.previous_sibling[index], self.next_sibling[index]);
+        let parent = self.parents[index];
+        let (first, last) = self.head(parent);
+        if previous < 0 {
+            *first = next;
+        }
+        if next < 0 {
+            *last = previous;
         }
-        self.bone_tree.replace(root_node);
+        if previous >= 0 {
+            self.next_sibling[previous as usize] = next;
+        }
+        if next >= 0 {
+            self.previous_sibling[next as usize] = previous;
+        }
+    }
+    ///親を付け替える。子孫の下に付けようとしたときは何もせず false
+    pub fn reparent(&mut self, index: usize, new_parent: i32) -> bool {
+        if new_parent >= self.len() as i32
+            || new_parent >= 0 && self.is_descendant(new_parent as usize, index)
+        {
+            return false;
+        }
+        self.unlink(index);
+        self.link(index, new_parent.max(-1));
+        true
+    }
+    ///ボーンを末尾に追加する
+    pub fn push(&mut self, parent: i32) {
+        let index = self.len();
+        self.parents.push(-1);
+        self.first_child.push(-1);
+        self.last_child.push(-1);
+        self.next_sibling.push(-1);
+        self.previous_sibling.push(-1);
+        let parent = if parent < index as i32 { parent } else { -1 };
+        self.link(index, parent);
+    }
+    ///親から子への順(前順)でボーンを列挙する
+    pub fn preorder(&self) -> Vec<usize> {
+        let mut nodes = Vec::with_capacity(self.len());
+        let mut stack: Vec<usize> = self.children(-1).collect();
+        stack.reverse();
+        while let Some(node) = stack.pop() {
+            nodes.push(node);
+            let start = stack.len();
+            stack.extend(self.children(node as i32));
+            stack[start..].reverse();
+        }
+        nodes
+    }
+    pub fn dump_tree(&self, data_source: &[Bone]) -> String {
+        let mut dump_text = "-1:Root\n".to_owned();
+        let mut stack: Vec<(usize, usize)> = self.children(-1).map(|root| (root, 1)).collect();
+        stack.reverse();
+        while let Some((node, depth)) = stack.pop() {
+            dump_text += &"\t".repeat(depth);
+            dump_text += &format!("{}:{}\n", node, data_source[node].name);
+            let start = stack.len();
+            stack.extend(self.children(node as i32).map(|child| (child, depth + 1)));
+            stack[start..].reverse();
+        }
+        dump_text
     }
 }
 #[test]
+#[ignore = "PMX_PATHにモデルのパスが必要"]
 fn test_load_bone() {
     let env = std::env::var("PMX_PATH").unwrap();
     println!("{:?}", env);
-    let pmx = PMXUtil::pmx_loader::PMXLoader::open(env);
-    let (model_info, loader) = pmx.read_pmx_model_info();
-    let bones = loader
-        .read_pmx_vertices()
-        .1
-        .read_pmx_faces()
-        .1
-        .read_texture_list()
-        .1
-        .read_pmx_materials()
-        .1
-        .read_pmx_bones()
-        .0;
-    let mut model = Model::new(model_info);
-    model.load_bones(&bones);
-    println!("{}", model.bone_tree.dump_tree(0, &bones));
+    let pmx = PMXUtil::reader::ModelInfoStage::open(env).unwrap();
+    let document = crate::document::PmxDocument::read(pmx);
+    println!("{:?}", document.tree_report);
+    println!("{}", document.bone_tree.dump_tree(&document.bones));
+    //すべてのボーンが木にちょうど1回ずつ現れる
+    let mut preorder = document.bone_tree.preorder();
+    preorder.sort_unstable();
+    assert_eq!(preorder, (0..document.bones.len()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_bone_forest() {
+    //0と3が根、1の親は後ろの2、4と5は循環、6の親は範囲外
+    let parents = [-1, 2, 0, -1, 5, 4, 99];
+    let (mut tree, report) = BoneTree::build(&parents);
+    assert_eq!(tree.children(-1).collect::<Vec<_>>(), vec![0, 3, 4, 6]);
+    assert_eq!(tree.preorder(), vec![0, 2, 1, 3, 4, 5, 6]);
+    assert_eq!(report.orphans, vec![6]);
+    assert_eq!(report.cycles, vec![4, 5]);
+
+    //子孫の下へは付け替えられない
+    assert!(!tree.reparent(0, 1));
+    assert!(tree.reparent(2, 3));
+    assert_eq!(tree.children(0).count(), 0);
+    assert_eq!(tree.children(3).collect::<Vec<_>>(), vec![2]);
+    tree.push(1);
+    assert_eq!(tree.preorder(), vec![0, 3, 2, 1, 7, 4, 5, 6]);
 }
@@ -0,0 +1,334 @@
+//! 編集の取り消しとやり直し
+//!
+//! 各ビューは変更の前後を`Step`として溜め、メインループがまとめて`History`へ積む。
+//! ポーズは表示用なので単独では積まないが、ボーンの構造を戻すときはボーンに合わせて戻す
+
+use crate::pose::Pose;
+use PMXUtil::types::{Bone, Frame, ModelInfo, Morph, Rigid, Vertex};
+
+///同じ対象の編集がこの間隔(秒)以内に続けば1つにまとめる
+const MERGE_SECONDS: f64 = 1.0;
+///取り消せる段階の数
+const HISTORY_LIMIT: usize = 200;
+
+///ボーンと、ボーンを参照しているデータ
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneState {
+    pub bones: Vec<Bone>,
+    pub pose: Pose,
+    ///ボーンの並びを変えたときだけ持つ
+    pub references: Option<BoneReferences>,
+}
+
+///ボーンの番号を参照しているデータ。頂点は`Vertices`で戻す
+#[derive(Debug, Clone, PartialEq)]
+pub struct BoneReferences {
+    pub morphs: Vec<Morph>,
+    pub frames: Vec<Frame>,
+    pub rigids: Vec<Rigid>,
+}
+
+///追加UVの数と、それに合わせて組み替えるモーフ
+#[derive(Debug, Clone, PartialEq)]
+pub struct UvState {
+    pub additional_uv: u8,
+    pub morphs: Vec<Morph>,
+    pub frames: Vec<Frame>,
+}
+
+///1つの変更の前後
+#[derive(Debug, Clone, PartialEq)]
+pub enum Change {
+    ///1つのボーンの設定
+    Bone {
+        index: usize,
+        before: Box<Bone>,
+        after: Box<Bone>,
+    },
+    ///ボーンの追加、削除、並べ替えや親の付け替え
+    Bones {
+        before: Box<BoneState>,
+        after: Box<BoneState>,
+    },
+    ///変わった頂点だけを持つ
+    Vertices {
+        indices: Vec<usize>,
+        before: Vec<Vertex>,
+        after: Vec<Vertex>,
+    },
+    Info {
+        before: Box<ModelInfo>,
+        after: Box<ModelInfo>,
+    },
+    ///追加UVの数。頂点の値は`Vertices`で戻す
+    AdditionalUv {
+        before: Box<UvState>,
+        after: Box<UvState>,
+    },
+}
+impl Change {
+    ///頂点の変更前と変更後を比べる。変わっていなければNone
+    pub fn vertices(before: &[Vertex], after: &[Vertex]) -> Option<Change> {
+        let indices: Vec<usize> = before
+            .iter()
+            .zip(after.iter())
+            .enumerate()
+            .filter(|(_, (before, after))| before != after)
+            .map(|(index, _)| index)
+            .collect();
+        if indices.is_empty() {
+            return None;
+        }
+        Some(Change::Vertices {
+            before: indices.iter().map(|&index| before[index].clone()).collect(),
+            after: indices.iter().map(|&index| after[index].clone()).collect(),
+            indices,
+        })
+    }
+    ///続けて行った`next`を合わせて1つの変更にする。合わせられなければ`next`を返す
+    fn merge(&mut self, next: Change) -> Result<(), Change> {
+        match (self, next) {
+            (
+                Change::Bone { index, after, .. },
+                Change::Bone {
+                    index: next_index,
+                    after: next_after,
+                    ..
+                },
+            ) if *index == next_index => *after = next_after,
+            //並べ替えを挟むと参照を戻せなくなるので、まとめるのはボーンだけの変更同士
+            (
+                Change::Bones { before, after },
+                Change::Bones {
+                    before: next_before,
+                    after: next_after,
+                },
+            ) if before.references.is_none() && next_before.references.is_none() => {
+                *after = next_after
+            }
+            (
+                Change::Info { after, .. },
+                Change::Info {
+                    after: next_after, ..
+                },
+            ) => *after = next_after,
+            (
+                Change::Vertices {
+                    indices,
+                    before,
+                    after,
+                },
+                Change::Vertices {
+                    indices: next_indices,
+                    before: next_before,
+                    after: next_after,
+                },
+            ) => {
+                let changes = next_indices.into_iter().zip(next_before).zip(next_after);
+                for ((index, next_before), next_after) in changes {
+                    match indices.iter().position(|&current| current == index) {
+                        Some(position) => after[position] = next_after,
+                        None => {
+                            indices.push(index);
+                            before.push(next_before);
+                            after.push(next_after);
+                        }
+                    }
+                }
+            }
+            (_, next) => return Err(next),
+        }
+        Ok(())
+    }
+}
+
+///取り消しの単位
+#[derive(Debug, Clone, PartialEq)]
+pub struct Step {
+    pub label: String,
+    ///適用した順
+    pub changes: Vec<Change>,
+    merge_key: Option<String>,
+}
+impl Step {
+    pub fn new(label: impl Into<String>, change: Change) -> Self {
+        Self {
+            label: label.into(),
+            changes: vec![change],
+            merge_key: None,
+        }
+    }
+    ///続けて適用した変更を加える。ボーンの並べ替えに伴う頂点の付け替えなど
+    pub fn with(mut self, change: Change) -> Self {
+        self.changes.push(change);
+        self
+    }
+    ///同じ`key`の編集が続けば1つの段階にまとめる。ドラッグやテキスト入力用
+    pub fn merging(mut self, key: impl Into<String>) -> Self {
+        self.merge_key = Some(key.into());
+        self
+    }
+}
+
+struct Entry {
+    step: Step,
+    ///最後に編集した時刻
+    time: f64,
+    ///これ以上まとめない
+    sealed: bool,
+}
+
+///履歴の段階の移動
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum HistoryJump {
+    Undo(usize),
+    Redo(usize),
+}
+
+#[derive(Default)]
+pub struct History {
+    undo: Vec<Entry>,
+    ///次にやり直すものが末尾
+    redo: Vec<Step>,
+}
+impl History {
+    pub fn new() -> Self {
+        Self::default()
+    }
+    ///`time`は入力の時刻(秒)
+    pub fn push(&mut self, step: Step, time: f64) {
+        self.redo.clear();
+        if let Some(last) = self.undo.last_mut() {
+            let mergeable = !last.sealed
+                && step.merge_key.is_some()
+                && last.step.merge_key == step.merge_key
+                && time - last.time <= MERGE_SECONDS;
+            if mergeable {
+                for change in step.changes {
+                    let merged = match last.step.changes.last_mut() {
+                        Some(current) => current.merge(change),
+                        None => Err(change),
+                    };
+                    if let Err(change) = merged {
+                        last.step.changes.push(change);
+                    }
+                }
+                last.time = time;
+                return;
+            }
+        }
+        self.undo.push(Entry {
+            step,
+            time,
+            sealed: false,
+        });
+        if self.undo.len() > HISTORY_LIMIT {
+            self.undo.remove(0);
+        }
+    }
+    ///ドラッグなどが終わったので、次の編集は別の段階にする
+    pub fn seal(&mut self) {
+        if let Some(last) = self.undo.last_mut() {
+            last.sealed = true;
+        }
+    }
+    ///取り消す段階。変更を逆順に`before`へ戻す
+    pub fn undo(&mut self) -> Option<&Step> {
+        let entry = self.undo.pop()?;
+        self.redo.push(entry.step);
+        self.redo.last()
+    }
+    ///やり直す段階。変更を順に`after`にする
+    pub fn redo(&mut self) -> Option<&Step> {
+        let step = self.redo.pop()?;
+        self.undo.push(Entry {
+            step,
+            time: 0.0,
+            sealed: true,
+        });
+        self.undo.last().map(|entry| &entry.step)
+    }
+    pub fn can_undo(&self) -> bool {
+        !self.undo.is_empty()
+    }
+    pub fn can_redo(&self) -> bool {
+        !self.redo.is_empty()
+    }
+    ///履歴の一覧。選んだ段階までの移動を返す
+    pub fn display(&self, ui: &mut egui::Ui) -> Option<HistoryJump> {
+        let mut jump = None;
+        ui.horizontal(|ui| {
+            if ui
+                .add_enabled(self.can_undo(), egui::Button::new("元に戻す"))
+                .clicked()
+            {
+                jump = Some(HistoryJump::Undo(1));
+            }
+            if ui
+                .add_enabled(self.can_redo(), egui::Button::new("やり直し"))
+                .clicked()
+            {
+                jump = Some(HistoryJump::Redo(1));
+            }
+        });
+        egui::ScrollArea::vertical().show(ui, |ui| {
+            if ui
+                .selectable_label(self.undo.is_empty(), "(読み込み時)")
+                .clicked()
+            {
+                jump = Some(HistoryJump::Undo(self.undo.len()));
+            }
+            let done = self.undo.len();
+            for (position, entry) in self.undo.iter().enumerate() {
+                let current = position + 1 == done;
+                if ui.selectable_label(current, &entry.step.label).clicked() {
+                    jump = Some(HistoryJump::Undo(done - position - 1));
+                }
+            }
+            for (position, step) in self.redo.iter().rev().enumerate() {
+                let label = egui::RichText::new(&step.label).weak();
+                if ui.selectable_label(false, label).clicked() {
+                    jump = Some(HistoryJump::Redo(position + 1));
+                }
+            }
+        });
+        jump.filter(|&jump| jump != HistoryJump::Undo(0))
+    }
+}
+
+#[test]
+fn test_history() {
+    let bone = |x: f32| {
+        Box::new(Bone {
+            position: [x, 0.0, 0.0],
+            ..Bone::default()
+        })
+    };
+    let edit = |from: f32, to: f32| {
+        Step::new(
+            "移動",
+            Change::Bone {
+                index: 0,
+                before: bone(from),
+                after: bone(to),
+            },
+        )
+        .merging("bone 0")
+    };
+    let mut history = History::new();
+    //ドラッグ中の編集は1つにまとまる
+    history.push(edit(0.0, 1.0), 0.0);
+    history.push(edit(1.0, 2.0), 0.1);
+    history.seal();
+    history.push(edit(2.0, 3.0), 0.2);
+    assert_eq!(history.undo.len(), 2);
+    assert_eq!(history.undo[0].step, edit(0.0, 2.0));
+
+    assert_eq!(history.undo(), Some(&edit(2.0, 3.0)));
+    assert_eq!(history.undo(), Some(&edit(0.0, 2.0)));
+    assert_eq!(history.undo(), None);
+    assert_eq!(history.redo(), Some(&edit(0.0, 2.0)));
+    //新しい編集でやり直しは消える
+    history.push(edit(2.0, 5.0), 1.0);
+    assert!(!history.can_redo());
+}
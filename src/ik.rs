@@ -0,0 +1,258 @@
+//! MMD互換のCCD IK
+
+use crate::math::{
+    cross, dot, length, normalize, quat_conjugate, quat_from_axis_angle, quat_from_euler, quat_mul,
+    quat_normalize, quat_rotate, quat_to_euler, sub, Quat, Vec3, QUAT_IDENTITY,
+};
+use crate::pose::PoseEvaluator;
+use PMXUtil::types::{Bone, BoneIKInfo};
+
+///ターゲットがここまで近づいたら打ち切る
+const IK_TOLERANCE: f32 = 1.0e-4;
+
+///IKボーン1本分のCCDを解く
+///
+/// `ik_target_bone_index`のボーン(エフェクタ)をIKボーンの位置へ近づけるように、
+/// リンクを先端側から順に回転させる。結果はリンクの`ik_rotation`に入る
+pub fn solve_ccd(evaluator: &mut PoseEvaluator, ik_bone_index: usize) {
+    let bones = evaluator.bones;
+    let ik_info = match &bones[ik_bone_index].ik_info {
+        Some(ik_info) => ik_info,
+        None => return,
+    };
+    let bone_count = bones.len();
+    let effector = ik_info.ik_target_bone_index as usize;
+    if effector >= bone_count
+        || ik_info
+            .ik_links
+            .iter()
+            .any(|link| link.ik_bone_index < 0 || link.ik_bone_index as usize >= bone_count)
+    {
+        return;
+    }
+    for link in &ik_info.ik_links {
+        evaluator.states[link.ik_bone_index as usize].ik_rotation = QUAT_IDENTITY;
+        evaluator.update_subtree(link.ik_bone_index as usize);
+    }
+    let target = evaluator.states[ik_bone_index].global_position;
+    for _ in 0..ik_info.ik_iter_count.max(1) {
+        for link in &ik_info.ik_links {
+            let link_index = link.ik_bone_index as usize;
+            let link_state = evaluator.states[link_index];
+            let effector_position = evaluator.states[effector].global_position;
+            //リンクのローカル座標系での向き
+            let inverse = quat_conjugate(link_state.global_rotation);
+            let to_effector = normalize(quat_rotate(
+                inverse,
+                sub(effector_position, link_state.global_position),
+            ));
+            let to_target = normalize(quat_rotate(
+                inverse,
+                sub(target, link_state.global_position),
+            ));
+            let angle = dot(to_effector, to_target).clamp(-1.0, 1.0).acos();
+            if angle < 1.0e-5 {
+                continue;
+            }
+            let angle = angle.min(ik_info.ik_limit_angle.max(f32::EPSILON));
+            let axis = cross(to_effector, to_target);
+            if length(axis) < 1.0e-8 {
+                continue;
+            }
+            let axis = normalize(axis);
+            let before = link_state.local_rotation();
+            let local = quat_mul(before, quat_from_axis_angle(axis, angle));
+            let local = match &link.angle_limit {
+                Some((lower, upper)) => limit_rotation(before, local, axis, angle, *lower, *upper),
+                None => quat_normalize(local),
+            };
+            evaluator.states[link_index].ik_rotation = quat_mul(
+                quat_mul(quat_conjugate(link_state.rotation), local),
+                quat_conjugate(link_state.grant_rotation),
+            );
+            evaluator.update_subtree(link_index);
+        }
+        let effector_position = evaluator.states[effector].global_position;
+        if length(sub(effector_position, target)) < IK_TOLERANCE {
+            break;
+        }
+    }
+}
+
+///リンクの角度制限を適用した回転を返す
+///
+/// 1軸だけに範囲がある制限(ひざ)ではその軸回りの回転に置き換えてから制限する
+fn limit_rotation(
+    before: Quat,
+    rotated: Quat,
+    axis: Vec3,
+    angle: f32,
+    lower: Vec3,
+    upper: Vec3,
+) -> Quat {
+    let limited_axes: Vec<usize> = (0..3)
+        .filter(|&i| lower[i] != 0.0 || upper[i] != 0.0)
+        .collect();
+    let mut euler = quat_to_euler(rotated);
+    if let [single] = limited_axes.as_slice() {
+        //ひざ: 回転軸を制限軸へ射影して向きだけ使う
+        let mut unit = [0.0; 3];
+        unit[*single] = 1.0;
+        let sign = if dot(axis, unit) >= 0.0 { 1.0 } else { -1.0 };
+        let mut limited = [0.0; 3];
+        limited[*single] = quat_to_euler(before)[*single] + sign * angle;
+        euler = limited;
+    }
+    for ((value, lower), upper) in euler.iter_mut().zip(lower.iter()).zip(upper.iter()) {
+        *value = value.max(lower.min(*upper)).min(upper.max(*lower));
+    }
+    quat_from_euler(euler)
+}
+
+///IKの設定の誤り
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IkChainError {
+    InvalidTarget,
+    ///リンク番号
+    InvalidLink(usize),
+    DuplicatedLink(usize),
+    ///ターゲット(または直前のリンク)の祖先でないリンク
+    NotAncestor(usize),
+}
+
+impl std::fmt::Display for IkChainError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            IkChainError::InvalidTarget => write!(f, "ターゲットのボーンがありません"),
+            IkChainError::InvalidLink(link) => write!(f, "リンク{}のボーンがありません", link),
+            IkChainError::DuplicatedLink(link) => write!(f, "リンク{}が重複しています", link),
+            IkChainError::NotAncestor(link) => {
+                write!(f, "リンク{}が前のボーンの親方向にありません", link)
+            }
+        }
+    }
+}
+
+///`descendant`から親をたどって`ancestor`に着くか
+fn is_ancestor(bones: &[Bone], ancestor: usize, descendant: usize) -> bool {
+    let mut current = bones[descendant].parent;
+    for _ in 0..bones.len() {
+        if current < 0 || current as usize >= bones.len() {
+            return false;
+        }
+        if current as usize == ancestor {
+            return true;
+        }
+        current = bones[current as usize].parent;
+    }
+    false
+}
+
+///リンクがターゲットから根元へ向かう祖先の並びになっているか調べる
+pub fn validate_chain(bones: &[Bone], ik_info: &BoneIKInfo) -> Vec<IkChainError> {
+    let mut errors = vec![];
+    let target = ik_info.ik_target_bone_index;
+    let mut previous = if target >= 0 && (target as usize) < bones.len() {
+        Some(target as usize)
+    } else {
+        errors.push(IkChainError::InvalidTarget);
+        None
+    };
+    for (link, ik_link) in ik_info.ik_links.iter().enumerate() {
+        let index = ik_link.ik_bone_index;
+        if index < 0 || index as usize >= bones.len() {
+            errors.push(IkChainError::InvalidLink(link));
+            previous = None;
+            continue;
+        }
+        let index = index as usize;
+        if ik_info.ik_links[..link]
+            .iter()
+            .any(|other| other.ik_bone_index as usize == index)
+        {
+            errors.push(IkChainError::DuplicatedLink(link));
+        } else if previous.is_some_and(|previous| !is_ancestor(bones, index, previous)) {
+            errors.push(IkChainError::NotAncestor(link));
+        }
+        previous = Some(index);
+    }
+    errors
+}
+
+#[test]
+fn test_ccd_reaches_target() {
+    use crate::pose::Pose;
+    use PMXUtil::types::{Bone, BoneIKInfo, IKLink};
+    //0:足 1:ひざ 2:足首 3:足IK
+    let bone = |position: [f32; 3], parent: i32| Bone {
+        position,
+        parent,
+        ..Bone::default()
+    };
+    let mut bones = vec![
+        bone([0.0, 10.0, 0.0], -1),
+        bone([0.0, 5.0, 0.0], 0),
+        bone([0.0, 0.0, 0.0], 1),
+        bone([0.0, 2.0, -2.0], -1),
+    ];
+    bones[3].ik_info = Some(BoneIKInfo {
+        ik_target_bone_index: 2,
+        ik_iter_count: 40,
+        ik_limit_angle: 2.0,
+        ik_links: vec![
+            IKLink {
+                ik_bone_index: 1,
+                angle_limit: Some(([-std::f32::consts::PI, 0.0, 0.0], [-0.008, 0.0, 0.0])),
+            },
+            IKLink {
+                ik_bone_index: 0,
+                angle_limit: None,
+            },
+        ],
+    });
+    let pose = Pose::new(bones.len(), 0);
+    let tree = crate::global_model_state::BoneTree::from_bones(&bones).0;
+    let mut evaluator = PoseEvaluator::new(&bones, &tree);
+    let states = evaluator.evaluate(&pose);
+    let effector = states[2].global_position;
+    assert!(length(sub(effector, [0.0, 2.0, -2.0])) < 1.0e-2);
+    //ひざはX軸回りにしか曲がらない
+    let knee = quat_to_euler(states[1].local_rotation());
+    assert!(knee[1].abs() < 1.0e-3 && knee[2].abs() < 1.0e-3);
+    assert!(knee[0] <= 0.0);
+}
+
+#[test]
+fn test_validate_chain() {
+    use PMXUtil::types::IKLink;
+    let bone = |parent: i32| Bone {
+        parent,
+        ..Bone::default()
+    };
+    let bones = vec![bone(-1), bone(0), bone(1), bone(-1)];
+    let link = |ik_bone_index: i32| IKLink {
+        ik_bone_index,
+        angle_limit: None,
+    };
+    let mut ik_info = BoneIKInfo {
+        ik_target_bone_index: 2,
+        ik_iter_count: 40,
+        ik_limit_angle: 2.0,
+        ik_links: vec![link(1), link(0)],
+    };
+    assert!(validate_chain(&bones, &ik_info).is_empty());
+    ik_info.ik_links = vec![link(0), link(1), link(0), link(9)];
+    assert_eq!(
+        validate_chain(&bones, &ik_info),
+        vec![
+            IkChainError::NotAncestor(1),
+            IkChainError::DuplicatedLink(2),
+            IkChainError::InvalidLink(3)
+        ]
+    );
+    ik_info.ik_target_bone_index = -1;
+    assert_eq!(
+        validate_chain(&bones, &ik_info)[0],
+        IkChainError::InvalidTarget
+    );
+}
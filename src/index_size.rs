@@ -0,0 +1,120 @@
+//! PMXのヘッダーに書くインデックスのサイズ
+//!
+//! 保存時はPMXUtilのWriterが要素数から選ぶので、同じ基準で表示と警告に使う
+
+///インデックス1つのバイト数
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Ord, PartialOrd)]
+pub enum IndexSize {
+    One,
+    Two,
+    Four,
+}
+impl IndexSize {
+    pub fn bytes(self) -> u8 {
+        match self {
+            IndexSize::One => 1,
+            IndexSize::Two => 2,
+            IndexSize::Four => 4,
+        }
+    }
+}
+
+///ヘッダーにインデックスサイズを持つ要素
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum IndexKind {
+    Vertex,
+    Texture,
+    Material,
+    Bone,
+    Morph,
+    Rigid,
+}
+impl IndexKind {
+    pub const ALL: [IndexKind; 6] = [
+        IndexKind::Vertex,
+        IndexKind::Texture,
+        IndexKind::Material,
+        IndexKind::Bone,
+        IndexKind::Morph,
+        IndexKind::Rigid,
+    ];
+    pub fn label(self) -> &'static str {
+        match self {
+            IndexKind::Vertex => "頂点",
+            IndexKind::Texture => "テクスチャ",
+            IndexKind::Material => "材質",
+            IndexKind::Bone => "ボーン",
+            IndexKind::Morph => "モーフ",
+            IndexKind::Rigid => "剛体",
+        }
+    }
+    ///`count`個の要素を指せる最小のサイズ
+    ///
+    /// 頂点は符号なし、それ以外は-1(なし)を表すため符号付き
+    pub fn size(self, count: usize) -> IndexSize {
+        let (one, two) = match self {
+            IndexKind::Vertex => (u8::MAX as usize, u16::MAX as usize),
+            _ => (i8::MAX as usize, i16::MAX as usize),
+        };
+        if count <= one {
+            IndexSize::One
+        } else if count <= two {
+            IndexSize::Two
+        } else {
+            IndexSize::Four
+        }
+    }
+}
+
+///種類ごとの要素数。並びは`IndexKind::ALL`と同じ
+#[derive(Debug, Copy, Clone, Default, Eq, PartialEq)]
+pub struct IndexSizes {
+    pub counts: [usize; 6],
+}
+impl IndexSizes {
+    pub fn iter(&self) -> impl Iterator<Item = (IndexKind, usize, IndexSize)> + '_ {
+        IndexKind::ALL
+            .iter()
+            .zip(self.counts.iter())
+            .map(|(&kind, &count)| (kind, count, kind.size(count)))
+    }
+    ///`previous`からサイズが変わった種類の警告
+    pub fn warnings(&self, previous: &IndexSizes) -> Vec<String> {
+        self.iter()
+            .zip(previous.iter())
+            .filter(|((_, _, size), (_, _, previous))| size != previous)
+            .map(|((kind, count, size), (_, _, previous))| {
+                format!(
+                    "{}数が{}になり、{}インデックスのサイズが{}から{}バイトに変わります",
+                    kind.label(),
+                    count,
+                    kind.label(),
+                    previous.bytes(),
+                    size.bytes()
+                )
+            })
+            .collect()
+    }
+}
+
+#[test]
+fn test_index_size() {
+    assert_eq!(IndexKind::Vertex.size(255), IndexSize::One);
+    assert_eq!(IndexKind::Vertex.size(256), IndexSize::Two);
+    assert_eq!(IndexKind::Vertex.size(65535), IndexSize::Two);
+    assert_eq!(IndexKind::Vertex.size(65536), IndexSize::Four);
+    assert_eq!(IndexKind::Bone.size(127), IndexSize::One);
+    assert_eq!(IndexKind::Bone.size(128), IndexSize::Two);
+    assert_eq!(IndexKind::Morph.size(32768), IndexSize::Four);
+
+    let before = IndexSizes {
+        counts: [100, 0, 0, 127, 0, 0],
+    };
+    let after = IndexSizes {
+        counts: [200, 0, 0, 128, 0, 0],
+    };
+    assert!(after.warnings(&after).is_empty());
+    let warnings = after.warnings(&before);
+    assert_eq!(warnings.len(), 1);
+    assert!(warnings[0].starts_with("ボーン数が128"));
+}
@@ -1,10 +1,28 @@
+mod additional_uv;
+mod bone_remap;
+mod document;
 mod global_model_state;
+mod history;
+mod ik;
+mod index_size;
+mod math;
+mod mirror;
 mod model_selector;
+mod physics;
 mod pmx_renderer;
+mod pose;
+mod text_check;
 mod ui;
+mod vertex_edit;
+mod viewport;
+mod vpd;
+mod weight_check;
+mod weight_paint;
 
 use std::iter;
 
+use crate::document::PmxDocument;
+use crate::history::{Change, History, HistoryJump};
 use crate::ui::{EguiBoneView, PMXInfoView, PMXVertexView, TabKind, Tabs};
 
 use egui_wgpu_backend::wgpu::CommandEncoderDescriptor;
@@ -12,10 +30,12 @@ use egui_wgpu_backend::{wgpu, RenderPass, ScreenDescriptor};
 use egui_winit::winit;
 
 use crate::model_selector::ModelSelector;
+use crate::viewport::Viewport;
 use egui::{FontData, FullOutput};
 use egui_winit::winit::event::WindowEvent;
 use egui_winit::winit::event_loop::ControlFlow;
 use std::io::Read;
+use std::path::{Path, PathBuf};
 use std::process::exit;
 use std::sync::{Arc, RwLock};
 use PMXUtil::reader::ModelInfoStage;
@@ -23,30 +43,108 @@ use PMXUtil::reader::ModelInfoStage;
 const INITIAL_WIDTH: u32 = 1280;
 const INITIAL_HEIGHT: u32 = 720;
 
+type ModelDataView = (
+    PMXInfoView,
+    PMXVertexView,
+    EguiBoneView,
+    Tabs,
+    Viewport,
+    History,
+    PmxDocument,
+);
+
+///`source`は読み込んだファイルのパスで、保存先の既定値にする
 fn create_new_model_tab<R: Read>(
     pmx: ModelInfoStage<R>,
-) -> (String, (PMXInfoView, PMXVertexView, EguiBoneView, Tabs)) {
-    let header = pmx.get_header();
-    let (model_info, loader) = pmx.read();
-    let (vertices, loader) = loader.read();
-    let (bones, loader) = loader.read().1.read().1.read().1.read();
-    let pmx_info_view = PMXInfoView::new(header.clone(), model_info.clone());
-    let pmx_vertex_view = PMXVertexView::new(vertices, header, &bones);
-    let bone_view = EguiBoneView::new(&bones);
+    source: Option<PathBuf>,
+) -> (String, ModelDataView) {
+    let document = PmxDocument::read(pmx);
+    let pmx_info_view = PMXInfoView::new(&document, source);
+    let bone_view = EguiBoneView::new(&document);
     (
-        model_info.name,
+        document.model_info.name.clone(),
         (
             pmx_info_view,
-            pmx_vertex_view,
+            PMXVertexView::new(),
             bone_view,
             Tabs(TabKind::Info),
+            Viewport::new(),
+            History::new(),
+            document,
         ),
     )
 }
 
+///Ctrl+Zで取り消し、Ctrl+YかCtrl+Shift+Zでやり直し。テキスト入力中はテキスト側に任せる
+fn history_shortcut(ctx: &egui::Context) -> Option<HistoryJump> {
+    if ctx.wants_keyboard_input() {
+        return None;
+    }
+    let input = ctx.input();
+    if !input.modifiers.command {
+        return None;
+    }
+    if input.key_pressed(egui::Key::Y) || (input.modifiers.shift && input.key_pressed(egui::Key::Z))
+    {
+        Some(HistoryJump::Redo(1))
+    } else if input.key_pressed(egui::Key::Z) {
+        Some(HistoryJump::Undo(1))
+    } else {
+        None
+    }
+}
+
+///履歴の段階を移動し、変更前後の値を各ビューへ戻す
+fn jump_history(view: &mut ModelDataView, jump: HistoryJump) {
+    let (info_view, vertex_view, bone_view, _, _, history, document) = view;
+    let (undo, count) = match jump {
+        HistoryJump::Undo(count) => (true, count),
+        HistoryJump::Redo(count) => (false, count),
+    };
+    for _ in 0..count {
+        let step = if undo { history.undo() } else { history.redo() };
+        let step = match step {
+            Some(step) => step,
+            None => break,
+        };
+        let changes: Vec<&Change> = if undo {
+            step.changes.iter().rev().collect()
+        } else {
+            step.changes.iter().collect()
+        };
+        for change in changes {
+            match change {
+                Change::Bone {
+                    index,
+                    before,
+                    after,
+                } => bone_view.restore_bone(document, *index, if undo { before } else { after }),
+                Change::Bones { before, after } => {
+                    bone_view.restore(document, if undo { before } else { after })
+                }
+                Change::Vertices {
+                    indices,
+                    before,
+                    after,
+                } => vertex_view.restore_vertices(
+                    document,
+                    indices,
+                    if undo { before } else { after },
+                ),
+                Change::Info { before, after } => {
+                    info_view.restore(document, if undo { before } else { after })
+                }
+                Change::AdditionalUv { before, after } => {
+                    info_view.restore_uv(document, if undo { before } else { after })
+                }
+            }
+        }
+    }
+}
+
 /// A simple egui + wgpu + winit based example.
 fn main() {
-    let mut model_data_views: Vec<(PMXInfoView, PMXVertexView, EguiBoneView, Tabs)> = Vec::new();
+    let mut model_data_views: Vec<ModelDataView> = Vec::new();
 
     let event_loop = winit::event_loop::EventLoop::new();
     let window = winit::window::WindowBuilder::new()
@@ -138,25 +236,68 @@ fn main() {
             egui_ctx.begin_frame(input);
             if let Some(model_data_view) = model_data_views.get_mut(model_number) {
                 model_data_view.3.display_tabs(&egui_ctx);
+                let mut jump = history_shortcut(&egui_ctx);
+                egui::Window::new("履歴")
+                    .default_width(200.0)
+                    .show(&egui_ctx, |ui| {
+                        if let Some(selected) = model_data_view.5.display(ui) {
+                            jump = Some(selected);
+                        }
+                    });
+                model_data_view.0.display_size_warnings(&egui_ctx);
 
                 egui::CentralPanel::default().show(&egui_ctx, |ui| match model_data_view.3 .0 {
                     TabKind::Info => {
-                        model_data_view.0.display(ui);
+                        model_data_view.0.display(ui, &mut model_data_view.6);
                     }
                     TabKind::Vertex => {
-                        model_data_view.1.display(ui);
+                        model_data_view.1.display(ui, &mut model_data_view.6);
                     }
                     TabKind::Bone => {
-                        model_data_view.2.display(ui);
+                        model_data_view.2.display(ui, &mut model_data_view.6);
                     }
 
-                    TabKind::View => {}
+                    TabKind::View => {
+                        model_data_view.4.display(
+                            ui,
+                            &mut model_data_view.6,
+                            &mut model_data_view.2,
+                            &mut model_data_view.1,
+                        );
+                    }
                     TabKind::TextureView => {}
                     TabKind::Shader => {}
                     _ => {}
                 });
-                if let Some(header) = model_data_view.0.query_updated_header() {
-                    model_data_view.1.update_header(header)
+                let time = egui_ctx.input().time;
+                let steps = model_data_view
+                    .0
+                    .query_steps()
+                    .into_iter()
+                    .chain(model_data_view.1.query_steps())
+                    .chain(model_data_view.2.query_steps());
+                for step in steps {
+                    model_data_view.5.push(step, time);
+                }
+                if egui_ctx.input().pointer.any_released() {
+                    model_data_view.5.seal();
+                }
+                if let Some(jump) = jump {
+                    jump_history(model_data_view, jump);
+                }
+                if let Some(bone) = model_data_view.1.query_requested_bone() {
+                    model_data_view.2.select_bone(&model_data_view.6, bone);
+                    model_data_view.3 .0 = TabKind::Bone;
+                }
+                let changes = model_data_view.6.take_changes();
+                if !changes.is_empty() {
+                    model_data_view
+                        .2
+                        .document_changed(&model_data_view.6, changes);
+                    model_data_view.4.document_changed(changes);
+                    model_data_view
+                        .0
+                        .document_changed(&model_data_view.6, changes);
                 }
             }
             egui::TopBottomPanel::bottom("model_selector").show(&egui_ctx, |ui| {
@@ -244,7 +385,12 @@ fn main() {
                                                     pmx_file,
                                                 )
                                             {
-                                                let (name, data) = create_new_model_tab(reader);
+                                                //zipの中へは書けないので、zipと同じフォルダを既定の保存先にする
+                                                let source = Path::new(&pmx_path)
+                                                    .file_name()
+                                                    .map(|name| file.with_file_name(name));
+                                                let (name, data) =
+                                                    create_new_model_tab(reader, source);
                                                 models
                                                     .write()
                                                     .map(|mut models| models.new_model(&name))
@@ -255,7 +401,11 @@ fn main() {
                                     }
                                 }
                             }
-                        } else {
+                        } else if file.extension().and_then(|path| path.to_str()) == Some("vpd") {
+                            //ポーズは表示中のモデルに適用する
+                            if let Some(model_data_view) = model_data_views.get_mut(model_number) {
+                                model_data_view.2.import_vpd(&model_data_view.6, file);
+                            }
                         }
                     }
                     WindowEvent::HoveredFile(_) => {}
@@ -0,0 +1,155 @@
+//! ポーズ計算用の最小限の線形代数
+//!
+//! PMXUtilに合わせて配列の型エイリアスで扱う。行列は列優先(`m[列][行]`)
+
+pub type Vec3 = [f32; 3];
+///x,y,z,w
+pub type Quat = [f32; 4];
+pub type Mat4 = [[f32; 4]; 4];
+
+pub const QUAT_IDENTITY: Quat = [0.0, 0.0, 0.0, 1.0];
+
+pub fn add(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] + b[0], a[1] + b[1], a[2] + b[2]]
+}
+pub fn sub(a: Vec3, b: Vec3) -> Vec3 {
+    [a[0] - b[0], a[1] - b[1], a[2] - b[2]]
+}
+pub fn scale(a: Vec3, s: f32) -> Vec3 {
+    [a[0] * s, a[1] * s, a[2] * s]
+}
+pub fn dot(a: Vec3, b: Vec3) -> f32 {
+    a[0] * b[0] + a[1] * b[1] + a[2] * b[2]
+}
+pub fn cross(a: Vec3, b: Vec3) -> Vec3 {
+    [
+        a[1] * b[2] - a[2] * b[1],
+        a[2] * b[0] - a[0] * b[2],
+        a[0] * b[1] - a[1] * b[0],
+    ]
+}
+pub fn length(a: Vec3) -> f32 {
+    dot(a, a).sqrt()
+}
+///長さ0のベクトルはそのまま返す
+pub fn normalize(a: Vec3) -> Vec3 {
+    let len = length(a);
+    if len > f32::EPSILON {
+        scale(a, 1.0 / len)
+    } else {
+        a
+    }
+}
+
+pub fn quat_from_axis_angle(axis: Vec3, angle: f32) -> Quat {
+    let axis = normalize(axis);
+    let (sin, cos) = (angle * 0.5).sin_cos();
+    [axis[0] * sin, axis[1] * sin, axis[2] * sin, cos]
+}
+///`a * b` : bを適用してからaを適用する回転
+pub fn quat_mul(a: Quat, b: Quat) -> Quat {
+    [
+        a[3] * b[0] + a[0] * b[3] + a[1] * b[2] - a[2] * b[1],
+        a[3] * b[1] - a[0] * b[2] + a[1] * b[3] + a[2] * b[0],
+        a[3] * b[2] + a[0] * b[1] - a[1] * b[0] + a[2] * b[3],
+        a[3] * b[3] - a[0] * b[0] - a[1] * b[1] - a[2] * b[2],
+    ]
+}
+pub fn quat_conjugate(q: Quat) -> Quat {
+    [-q[0], -q[1], -q[2], q[3]]
+}
+pub fn quat_normalize(q: Quat) -> Quat {
+    let len = (q[0] * q[0] + q[1] * q[1] + q[2] * q[2] + q[3] * q[3]).sqrt();
+    if len > f32::EPSILON {
+        [q[0] / len, q[1] / len, q[2] / len, q[3] / len]
+    } else {
+        QUAT_IDENTITY
+    }
+}
+pub fn quat_rotate(q: Quat, v: Vec3) -> Vec3 {
+    let u = [q[0], q[1], q[2]];
+    let t = scale(cross(u, v), 2.0);
+    add(add(v, scale(t, q[3])), cross(u, t))
+}
+pub fn quat_slerp(a: Quat, b: Quat, t: f32) -> Quat {
+    let mut cos = a[0] * b[0] + a[1] * b[1] + a[2] * b[2] + a[3] * b[3];
+    let mut b = b;
+    if cos < 0.0 {
+        cos = -cos;
+        b = [-b[0], -b[1], -b[2], -b[3]];
+    }
+    let (wa, wb) = if cos > 0.9995 {
+        (1.0 - t, t)
+    } else {
+        let theta = cos.acos();
+        let sin = theta.sin();
+        (((1.0 - t) * theta).sin() / sin, (t * theta).sin() / sin)
+    };
+    quat_normalize([
+        a[0] * wa + b[0] * wb,
+        a[1] * wa + b[1] * wb,
+        a[2] * wa + b[2] * wb,
+        a[3] * wa + b[3] * wb,
+    ])
+}
+///X,Y,Zの順のオイラー角(ラジアン)に分解する。`quat_from_euler`の逆
+pub fn quat_to_euler(q: Quat) -> Vec3 {
+    let [x, y, z, w] = q;
+    let m02 = 2.0 * (x * z + w * y);
+    let m12 = 2.0 * (y * z - w * x);
+    let m22 = 1.0 - 2.0 * (x * x + y * y);
+    let m01 = 2.0 * (x * y - w * z);
+    let m00 = 1.0 - 2.0 * (y * y + z * z);
+    [
+        (-m12).atan2(m22),
+        m02.clamp(-1.0, 1.0).asin(),
+        (-m01).atan2(m00),
+    ]
+}
+///`Rx * Ry * Rz`の順で合成する
+pub fn quat_from_euler(euler: Vec3) -> Quat {
+    let x = quat_from_axis_angle([1.0, 0.0, 0.0], euler[0]);
+    let y = quat_from_axis_angle([0.0, 1.0, 0.0], euler[1]);
+    let z = quat_from_axis_angle([0.0, 0.0, 1.0], euler[2]);
+    quat_mul(quat_mul(x, y), z)
+}
+
+pub fn mat4_from_rotation_translation(q: Quat, t: Vec3) -> Mat4 {
+    let [x, y, z, w] = q;
+    [
+        [
+            1.0 - 2.0 * (y * y + z * z),
+            2.0 * (x * y + w * z),
+            2.0 * (x * z - w * y),
+            0.0,
+        ],
+        [
+            2.0 * (x * y - w * z),
+            1.0 - 2.0 * (x * x + z * z),
+            2.0 * (y * z + w * x),
+            0.0,
+        ],
+        [
+            2.0 * (x * z + w * y),
+            2.0 * (y * z - w * x),
+            1.0 - 2.0 * (x * x + y * y),
+            0.0,
+        ],
+        [t[0], t[1], t[2], 1.0],
+    ]
+}
+///回転だけを適用する
+pub fn mat4_transform_vector(m: &Mat4, v: Vec3) -> Vec3 {
+    [
+        m[0][0] * v[0] + m[1][0] * v[1] + m[2][0] * v[2],
+        m[0][1] * v[0] + m[1][1] * v[1] + m[2][1] * v[2],
+        m[0][2] * v[0] + m[1][2] * v[1] + m[2][2] * v[2],
+    ]
+}
+pub fn mat4_transform_point(m: &Mat4, p: Vec3) -> Vec3 {
+    [
+        m[0][0] * p[0] + m[1][0] * p[1] + m[2][0] * p[2] + m[3][0],
+        m[0][1] * p[0] + m[1][1] * p[1] + m[2][1] * p[2] + m[3][1],
+        m[0][2] * p[0] + m[1][2] * p[1] + m[2][2] * p[2] + m[3][2],
+    ]
+}
@@ -0,0 +1,332 @@
+//! X=0を挟んだ左右対称の頂点とボーンを扱う
+//!
+//! モデルの左(左腕など)が+X側
+
+use crate::math::{length, sub, Vec3};
+use std::collections::HashMap;
+use PMXUtil::types::{
+    Bone, ConnectionDisplayMode, RotateAndTranslateInherits, Vertex, VertexWeight,
+};
+
+///名前の左右を入れ替える。左右を表す部分がなければNone
+///
+/// 左/右の文字と、英語名などの末尾の`_L`や`.R`、`armL`のような接尾辞を見る
+pub fn mirrored_name(name: &str) -> Option<String> {
+    if name.contains('左') || name.contains('右') {
+        return Some(
+            name.chars()
+                .map(|c| match c {
+                    '左' => '右',
+                    '右' => '左',
+                    c => c,
+                })
+                .collect(),
+        );
+    }
+    let mut chars = name.chars().rev();
+    let last = chars.next()?;
+    let before = chars.next()?;
+    let swapped = match last {
+        'L' => 'R',
+        'R' => 'L',
+        'l' => 'r',
+        'r' => 'l',
+        _ => return None,
+    };
+    //小文字の末尾(`arml`)は普通の単語と区別できないので区切りがあるときだけ
+    let separated = matches!(before, '_' | '.' | ' ' | '-');
+    let camel_case = last.is_ascii_uppercase() && before.is_ascii_lowercase();
+    if !separated && !camel_case {
+        return None;
+    }
+    Some(format!("{}{}", &name[..name.len() - 1], swapped))
+}
+
+///名前の左右を入れ替えたボーン。なければ同じボーン
+pub fn mirrored_bone(bones: &[Bone], index: i32) -> i32 {
+    let bone = match bones.get(index as usize) {
+        Some(bone) if index >= 0 => bone,
+        _ => return index,
+    };
+    let by_name = mirrored_name(&bone.name)
+        .and_then(|name| bones.iter().position(|other| other.name == name));
+    let by_english_name = || {
+        mirrored_name(&bone.english_name).and_then(|name| {
+            bones
+                .iter()
+                .position(|other| !other.english_name.is_empty() && other.english_name == name)
+        })
+    };
+    by_name
+        .or_else(by_english_name)
+        .map_or(index, |mirrored| mirrored as i32)
+}
+
+fn mirror_x(position: Vec3) -> Vec3 {
+    [-position[0], position[1], position[2]]
+}
+
+///各頂点について、X反転した位置から`tolerance`以内で最も近い頂点。X=0上の頂点は自分自身になる
+pub fn symmetric_pairs(vertices: &[Vertex], tolerance: f32) -> Vec<Option<usize>> {
+    let cell = tolerance.max(1.0e-5);
+    let key = |position: Vec3| {
+        [
+            (position[0] / cell).floor() as i64,
+            (position[1] / cell).floor() as i64,
+            (position[2] / cell).floor() as i64,
+        ]
+    };
+    let mut grid: HashMap<[i64; 3], Vec<usize>> = HashMap::new();
+    for (index, vertex) in vertices.iter().enumerate() {
+        grid.entry(key(vertex.position)).or_default().push(index);
+    }
+    vertices
+        .iter()
+        .map(|vertex| {
+            let mirrored = mirror_x(vertex.position);
+            let [x, y, z] = key(mirrored);
+            let mut nearest: Option<(usize, f32)> = None;
+            for dx in -1..=1 {
+                for dy in -1..=1 {
+                    for dz in -1..=1 {
+                        let candidates = match grid.get(&[x + dx, y + dy, z + dz]) {
+                            Some(candidates) => candidates,
+                            None => continue,
+                        };
+                        for &other in candidates {
+                            let distance = length(sub(vertices[other].position, mirrored));
+                            if distance <= tolerance
+                                && nearest.is_none_or(|(_, nearest)| distance < nearest)
+                            {
+                                nearest = Some((other, distance));
+                            }
+                        }
+                    }
+                }
+            }
+            nearest.map(|(other, _)| other)
+        })
+        .collect()
+}
+
+///どちら側からコピーするか
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum MirrorSource {
+    ///左から右へ
+    PlusX,
+    ///右から左へ
+    MinusX,
+}
+impl MirrorSource {
+    pub const ALL: [MirrorSource; 2] = [MirrorSource::PlusX, MirrorSource::MinusX];
+    pub fn label(self) -> &'static str {
+        match self {
+            MirrorSource::PlusX => "+X(左)→-X(右)",
+            MirrorSource::MinusX => "-X(右)→+X(左)",
+        }
+    }
+    fn contains(self, x: f32) -> bool {
+        match self {
+            MirrorSource::PlusX => x > 0.0,
+            MirrorSource::MinusX => x < 0.0,
+        }
+    }
+}
+
+///ボーンを左右入れ替え、SDEFパラメータをX反転したウェイト
+pub fn mirror_weight(weight: &VertexWeight, bones: &[Bone]) -> VertexWeight {
+    let bone = |index: i32| mirrored_bone(bones, index);
+    match *weight {
+        VertexWeight::BDEF1(index) => VertexWeight::BDEF1(bone(index)),
+        VertexWeight::BDEF2 {
+            bone_index_1,
+            bone_index_2,
+            bone_weight_1,
+        } => VertexWeight::BDEF2 {
+            bone_index_1: bone(bone_index_1),
+            bone_index_2: bone(bone_index_2),
+            bone_weight_1,
+        },
+        VertexWeight::SDEF {
+            bone_index_1,
+            bone_index_2,
+            bone_weight_1,
+            sdef_c,
+            sdef_r0,
+            sdef_r1,
+        } => VertexWeight::SDEF {
+            bone_index_1: bone(bone_index_1),
+            bone_index_2: bone(bone_index_2),
+            bone_weight_1,
+            sdef_c: mirror_x(sdef_c),
+            sdef_r0: mirror_x(sdef_r0),
+            sdef_r1: mirror_x(sdef_r1),
+        },
+        VertexWeight::BDEF4 {
+            bone_index_1,
+            bone_index_2,
+            bone_index_3,
+            bone_index_4,
+            bone_weight_1,
+            bone_weight_2,
+            bone_weight_3,
+            bone_weight_4,
+        } => VertexWeight::BDEF4 {
+            bone_index_1: bone(bone_index_1),
+            bone_index_2: bone(bone_index_2),
+            bone_index_3: bone(bone_index_3),
+            bone_index_4: bone(bone_index_4),
+            bone_weight_1,
+            bone_weight_2,
+            bone_weight_3,
+            bone_weight_4,
+        },
+        VertexWeight::QDEF {
+            bone_index_1,
+            bone_index_2,
+            bone_index_3,
+            bone_index_4,
+            bone_weight_1,
+            bone_weight_2,
+            bone_weight_3,
+            bone_weight_4,
+        } => VertexWeight::QDEF {
+            bone_index_1: bone(bone_index_1),
+            bone_index_2: bone(bone_index_2),
+            bone_index_3: bone(bone_index_3),
+            bone_index_4: bone(bone_index_4),
+            bone_weight_1,
+            bone_weight_2,
+            bone_weight_3,
+            bone_weight_4,
+        },
+    }
+}
+
+///`sources`のうち`side`側にある頂点のウェイトを反対側の対の頂点へ写す。変えた頂点の数を返す
+///
+/// `geometry`なら位置と法線もX反転して写す
+pub fn mirror_vertices(
+    vertices: &mut [Vertex],
+    bones: &[Bone],
+    pairs: &[Option<usize>],
+    sources: &[usize],
+    side: MirrorSource,
+    geometry: bool,
+) -> usize {
+    let mut changed = 0;
+    for &source in sources {
+        let target = match pairs.get(source) {
+            Some(&Some(target)) if target != source && target < vertices.len() => target,
+            _ => continue,
+        };
+        if !side.contains(vertices[source].position[0]) {
+            continue;
+        }
+        let from = vertices[source].clone();
+        let mut mirrored = vertices[target].clone();
+        mirrored.weight_type = mirror_weight(&from.weight_type, bones);
+        if geometry {
+            mirrored.position = mirror_x(from.position);
+            mirrored.norm = mirror_x(from.norm);
+        }
+        if mirrored != vertices[target] {
+            vertices[target] = mirrored;
+            changed += 1;
+        }
+    }
+    changed
+}
+
+///`index`のボーンを左右反転した設定。名前に左右がなければNone
+///
+/// 参照するボーンは反対側があればそちらへ付け替える
+pub fn mirror_bone(bones: &[Bone], index: usize) -> Option<Bone> {
+    let bone = bones.get(index)?;
+    let name = mirrored_name(&bone.name)?;
+    let other = |index: i32| mirrored_bone(bones, index);
+    let mut mirrored = bone.clone();
+    mirrored.english_name =
+        mirrored_name(&bone.english_name).unwrap_or_else(|| bone.english_name.clone());
+    mirrored.name = name;
+    mirrored.position = mirror_x(bone.position);
+    mirrored.parent = other(bone.parent);
+    mirrored.connection_display_mode = match bone.connection_display_mode {
+        ConnectionDisplayMode::OtherBone(index) => ConnectionDisplayMode::OtherBone(other(index)),
+        ConnectionDisplayMode::Offset(offset) => ConnectionDisplayMode::Offset(mirror_x(offset)),
+    };
+    match &mut mirrored.inherits.rotate_and_translate {
+        RotateAndTranslateInherits::None => {}
+        RotateAndTranslateInherits::Both(parent, _)
+        | RotateAndTranslateInherits::Rotate(parent, _)
+        | RotateAndTranslateInherits::Translate(parent, _) => *parent = other(*parent),
+    }
+    mirrored.fixed_axis = bone.fixed_axis.map(mirror_x);
+    mirrored.local_axis = bone
+        .local_axis
+        .map(|(x_axis, z_axis)| (mirror_x(x_axis), mirror_x(z_axis)));
+    if let Some(ik_info) = &mut mirrored.ik_info {
+        ik_info.ik_target_bone_index = other(ik_info.ik_target_bone_index);
+        for link in ik_info.ik_links.iter_mut() {
+            link.ik_bone_index = other(link.ik_bone_index);
+            //X反転ではY軸とZ軸周りの回転の向きが逆になる
+            link.angle_limit = link
+                .angle_limit
+                .map(|(min, max)| ([min[0], -max[1], -max[2]], [max[0], -min[1], -min[2]]));
+        }
+    }
+    Some(mirrored)
+}
+
+#[test]
+fn test_mirror() {
+    use crate::vertex_edit::test_vertex;
+    assert_eq!(mirrored_name("左腕").as_deref(), Some("右腕"));
+    assert_eq!(mirrored_name("arm_L").as_deref(), Some("arm_R"));
+    assert_eq!(mirrored_name("LegR").as_deref(), Some("LegL"));
+    assert_eq!(mirrored_name("center"), None);
+    assert_eq!(mirrored_name("ALL"), None);
+
+    let bone = |name: &str, x: f32, parent: i32| Bone {
+        name: name.to_string(),
+        position: [x, 0.0, 0.0],
+        parent,
+        ..Bone::default()
+    };
+    let bones = vec![
+        bone("上半身", 0.0, -1),
+        bone("左腕", 1.0, 0),
+        bone("右腕", -1.0, 0),
+    ];
+    assert_eq!(mirrored_bone(&bones, 1), 2);
+    assert_eq!(mirrored_bone(&bones, 0), 0);
+    let mirrored = mirror_bone(&bones, 1).unwrap();
+    assert_eq!(mirrored.name, "右腕");
+    assert_eq!(mirrored.position, [-1.0, 0.0, 0.0]);
+    assert_eq!(mirrored.parent, 0);
+    assert!(mirror_bone(&bones, 0).is_none());
+
+    let vertex = |x: f32, weight_type: VertexWeight| test_vertex([x, 1.0, 0.0], weight_type);
+    let mut vertices = vec![
+        vertex(1.0, VertexWeight::BDEF1(1)),
+        vertex(-1.001, VertexWeight::BDEF1(0)),
+        vertex(0.0, VertexWeight::BDEF1(0)),
+    ];
+    let pairs = symmetric_pairs(&vertices, 0.01);
+    assert_eq!(pairs, vec![Some(1), Some(0), Some(2)]);
+    //X=0上の頂点と反対側の頂点はそのまま
+    assert_eq!(
+        mirror_vertices(
+            &mut vertices,
+            &bones,
+            &pairs,
+            &[0, 1, 2],
+            MirrorSource::PlusX,
+            true
+        ),
+        1
+    );
+    assert_eq!(vertices[1].weight_type, VertexWeight::BDEF1(2));
+    assert_eq!(vertices[1].position, [-1.0, 1.0, 0.0]);
+    assert_eq!(vertices[0].weight_type, VertexWeight::BDEF1(1));
+}
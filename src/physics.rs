@@ -0,0 +1,540 @@
+//! PMXの剛体とジョイントの物理演算
+//!
+//! rapier3dで剛体を形状どおりに衝突させ、摩擦と反発も扱う。
+//! ジョイントは移動/回転制限付きの6DOFバネとして扱う
+
+use crate::math::{
+    add, quat_conjugate, quat_from_axis_angle, quat_mul, quat_rotate, sub, Quat, Vec3,
+};
+use crate::pose::PoseEvaluator;
+use rapier3d::prelude::*;
+use std::num::NonZeroUsize;
+use PMXUtil::types::{Bone, Joint, JointType, Rigid, RigidCalcMethod, RigidForm};
+
+///物理演算の設定
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct PhysicsSettings {
+    ///1ステップの秒数
+    pub fixed_timestep: f32,
+    ///1フレームで進める最大ステップ数
+    pub max_substeps: u32,
+    ///リセット後に現在のポーズのまま空回しするフレーム数
+    pub warm_up_frames: u32,
+    pub gravity: Vec3,
+    ///拘束の反復回数
+    pub iterations: u32,
+}
+
+impl Default for PhysicsSettings {
+    fn default() -> Self {
+        Self {
+            fixed_timestep: 1.0 / 60.0,
+            max_substeps: 5,
+            warm_up_frames: 60,
+            gravity: [0.0, -98.0, 0.0],
+            iterations: 8,
+        }
+    }
+}
+
+struct Body {
+    handle: RigidBodyHandle,
+    bone_index: i32,
+    calc_method: RigidCalcMethod,
+    ///ボーン座標系での剛体の姿勢
+    offset_rotation: Quat,
+    offset_translation: Vec3,
+    rest_rotation: Quat,
+    rest_position: Vec3,
+}
+
+impl Body {
+    fn is_kinematic(&self) -> bool {
+        self.calc_method == RigidCalcMethod::Static
+    }
+}
+
+///剛体とジョイントの集合
+pub struct PhysicsWorld {
+    bodies: Vec<Body>,
+    rigid_bodies: RigidBodySet,
+    colliders: ColliderSet,
+    impulse_joints: ImpulseJointSet,
+    multibody_joints: MultibodyJointSet,
+    islands: IslandManager,
+    broad_phase: DefaultBroadPhase,
+    narrow_phase: NarrowPhase,
+    ccd_solver: CCDSolver,
+    pipeline: PhysicsPipeline,
+    pub settings: PhysicsSettings,
+    accumulator: f32,
+    needs_reset: bool,
+}
+
+///PMXの剛体の回転はY,X,Zの順のオイラー角
+fn rigid_rotation(rotation: Vec3) -> Quat {
+    quat_mul(
+        quat_mul(
+            quat_from_axis_angle([0.0, 1.0, 0.0], rotation[1]),
+            quat_from_axis_angle([1.0, 0.0, 0.0], rotation[0]),
+        ),
+        quat_from_axis_angle([0.0, 0.0, 1.0], rotation[2]),
+    )
+}
+
+fn isometry(rotation: Quat, position: Vec3) -> Isometry<Real> {
+    Isometry::from_parts(
+        Translation::new(position[0], position[1], position[2]),
+        Rotation::from_quaternion(nalgebra::Quaternion::new(
+            rotation[3],
+            rotation[0],
+            rotation[1],
+            rotation[2],
+        )),
+    )
+}
+
+///MMDの減衰(1秒あたりに失う速度の割合)をrapierの減衰係数にする
+fn damping(resist: f32) -> f32 {
+    -(1.0 - resist.clamp(0.0, 1.0)).max(1.0e-3).ln()
+}
+
+fn collider(rigid: &Rigid) -> Collider {
+    let size = rigid.size;
+    let shape = match rigid.form {
+        RigidForm::Sphere => ColliderBuilder::ball(size[0].max(1.0e-3)),
+        RigidForm::Box => ColliderBuilder::cuboid(
+            size[0].max(1.0e-3),
+            size[1].max(1.0e-3),
+            size[2].max(1.0e-3),
+        ),
+        //高さは半球を除いた円柱部分の長さ
+        RigidForm::Capsule => {
+            ColliderBuilder::capsule_y(size[1].max(0.0) * 0.5, size[0].max(1.0e-3))
+        }
+    };
+    //非衝突グループフラグは立っているビットのグループと衝突する
+    let groups = InteractionGroups::new(
+        Group::from_bits_truncate(1 << (rigid.group & 15)),
+        Group::from_bits_truncate(rigid.un_collision_group_flag as u32),
+    );
+    shape
+        .mass(rigid.mass.max(1.0e-3))
+        .friction(rigid.friction.max(0.0))
+        .restitution(rigid.repulsion.max(0.0))
+        .collision_groups(groups)
+        .build()
+}
+
+///`lower`から`upper`の制限を軸に与える。下限が上限を超えている軸は制限なし
+fn limit_axis(
+    builder: GenericJointBuilder,
+    locked: &mut JointAxesMask,
+    (axis, mask): (JointAxis, JointAxesMask),
+    (lower, upper): (f32, f32),
+    spring: f32,
+) -> GenericJointBuilder {
+    if lower > upper {
+        return builder;
+    }
+    if upper - lower < 1.0e-6 {
+        *locked |= mask;
+        return builder;
+    }
+    let builder = builder.limits(axis, [lower, upper]);
+    if spring > 0.0 {
+        builder
+            .motor_model(axis, MotorModel::ForceBased)
+            .motor_position(axis, 0.0, spring, 0.0)
+    } else {
+        builder
+    }
+}
+
+fn joint_data(
+    joint: &JointType,
+    frame_a: Isometry<Real>,
+    frame_b: Isometry<Real>,
+) -> Option<GenericJoint> {
+    let (move_limit, rotation_limit, spring_move, spring_rotation) = match *joint {
+        JointType::Spring6DOF {
+            move_limit_down,
+            move_limit_up,
+            rotation_limit_down,
+            rotation_limit_up,
+            spring_const_move,
+            spring_const_rotation,
+            ..
+        } => (
+            (move_limit_down, move_limit_up),
+            (rotation_limit_down, rotation_limit_up),
+            spring_const_move,
+            spring_const_rotation,
+        ),
+        JointType::SixDof {
+            move_limit_down,
+            move_limit_up,
+            rotation_limit_down,
+            rotation_limit_up,
+            ..
+        } => (
+            (move_limit_down, move_limit_up),
+            (rotation_limit_down, rotation_limit_up),
+            [0.0; 3],
+            [0.0; 3],
+        ),
+        //PMX2.1の拡張ジョイントは未対応
+        _ => return None,
+    };
+    let linear = [
+        (JointAxis::LinX, JointAxesMask::LIN_X),
+        (JointAxis::LinY, JointAxesMask::LIN_Y),
+        (JointAxis::LinZ, JointAxesMask::LIN_Z),
+    ];
+    let angular = [
+        (JointAxis::AngX, JointAxesMask::ANG_X),
+        (JointAxis::AngY, JointAxesMask::ANG_Y),
+        (JointAxis::AngZ, JointAxesMask::ANG_Z),
+    ];
+    let mut locked = JointAxesMask::empty();
+    let mut builder = GenericJointBuilder::new(JointAxesMask::empty())
+        .local_frame1(frame_a)
+        .local_frame2(frame_b)
+        .contacts_enabled(false);
+    for axis in 0..3 {
+        builder = limit_axis(
+            builder,
+            &mut locked,
+            linear[axis],
+            (move_limit.0[axis], move_limit.1[axis]),
+            spring_move[axis],
+        );
+        builder = limit_axis(
+            builder,
+            &mut locked,
+            angular[axis],
+            (rotation_limit.0[axis], rotation_limit.1[axis]),
+            spring_rotation[axis],
+        );
+    }
+    let mut joint = builder.build();
+    joint.locked_axes = locked;
+    Some(joint)
+}
+
+impl PhysicsWorld {
+    pub fn new(rigids: &[Rigid], joints: &[Joint], bones: &[Bone]) -> Self {
+        let mut rigid_bodies = RigidBodySet::new();
+        let mut colliders = ColliderSet::new();
+        let bodies = rigids
+            .iter()
+            .map(|rigid| {
+                let rest_rotation = rigid_rotation(rigid.rotation);
+                let bone_position = bones
+                    .get(rigid.bone_index as usize)
+                    .filter(|_| rigid.bone_index >= 0)
+                    .map_or(rigid.position, |bone| bone.position);
+                let builder = match rigid.calc_method {
+                    RigidCalcMethod::Static => RigidBodyBuilder::kinematic_position_based(),
+                    _ => RigidBodyBuilder::dynamic()
+                        .linear_damping(damping(rigid.move_resist))
+                        .angular_damping(damping(rigid.rotation_resist)),
+                };
+                let handle = rigid_bodies.insert(
+                    builder
+                        .position(isometry(rest_rotation, rigid.position))
+                        .can_sleep(false)
+                        .build(),
+                );
+                colliders.insert_with_parent(collider(rigid), handle, &mut rigid_bodies);
+                Body {
+                    handle,
+                    bone_index: rigid.bone_index,
+                    calc_method: rigid.calc_method.clone(),
+                    offset_rotation: rest_rotation,
+                    offset_translation: sub(rigid.position, bone_position),
+                    rest_rotation,
+                    rest_position: rigid.position,
+                }
+            })
+            .collect::<Vec<_>>();
+        let mut impulse_joints = ImpulseJointSet::new();
+        for joint in joints {
+            let (a_rigid_index, b_rigid_index, position, rotation) = match joint.joint_type {
+                JointType::Spring6DOF {
+                    a_rigid_index,
+                    b_rigid_index,
+                    position,
+                    rotation,
+                    ..
+                }
+                | JointType::SixDof {
+                    a_rigid_index,
+                    b_rigid_index,
+                    position,
+                    rotation,
+                    ..
+                } => (a_rigid_index, b_rigid_index, position, rotation),
+                _ => continue,
+            };
+            if a_rigid_index < 0 || b_rigid_index < 0 || a_rigid_index == b_rigid_index {
+                continue;
+            }
+            let (a, b) = match (
+                bodies.get(a_rigid_index as usize),
+                bodies.get(b_rigid_index as usize),
+            ) {
+                (Some(a), Some(b)) => (a, b),
+                _ => continue,
+            };
+            //各剛体の座標系でのジョイントの姿勢
+            let joint_frame = isometry(rigid_rotation(rotation), position);
+            let frame = |body: &Body| {
+                isometry(body.rest_rotation, body.rest_position).inverse() * joint_frame
+            };
+            if let Some(data) = joint_data(&joint.joint_type, frame(a), frame(b)) {
+                impulse_joints.insert(a.handle, b.handle, data, true);
+            }
+        }
+        Self {
+            bodies,
+            rigid_bodies,
+            colliders,
+            impulse_joints,
+            multibody_joints: MultibodyJointSet::new(),
+            islands: IslandManager::new(),
+            broad_phase: DefaultBroadPhase::new(),
+            narrow_phase: NarrowPhase::new(),
+            ccd_solver: CCDSolver::new(),
+            pipeline: PhysicsPipeline::new(),
+            settings: PhysicsSettings::default(),
+            accumulator: 0.0,
+            needs_reset: true,
+        }
+    }
+    ///次の更新で剛体をボーンの位置に戻し、ウォームアップからやり直す
+    pub fn reset(&mut self) {
+        self.needs_reset = true;
+    }
+    ///物理前のボーンで剛体を動かし、経過時間分シミュレーションして結果をボーンへ書き戻す
+    pub fn update(&mut self, evaluator: &mut PoseEvaluator, elapsed: f32) {
+        let dt = self.settings.fixed_timestep.max(1.0e-4);
+        if self.needs_reset {
+            self.needs_reset = false;
+            self.accumulator = 0.0;
+            for index in 0..self.bodies.len() {
+                let (rotation, position) = self.bone_driven_transform(evaluator, index);
+                let body = &mut self.rigid_bodies[self.bodies[index].handle];
+                body.set_position(isometry(rotation, position), true);
+                body.set_linvel(Vector::zeros(), true);
+                body.set_angvel(Vector::zeros(), true);
+            }
+            for _ in 0..self.settings.warm_up_frames {
+                self.step(evaluator, dt);
+            }
+        }
+        self.accumulator += elapsed;
+        let mut steps = 0;
+        while self.accumulator >= dt && steps < self.settings.max_substeps {
+            self.step(evaluator, dt);
+            self.accumulator -= dt;
+            steps += 1;
+        }
+        if steps == self.settings.max_substeps {
+            //処理落ちしたぶんは捨てる
+            self.accumulator = 0.0;
+        }
+        self.write_back(evaluator);
+    }
+    fn bone_driven_transform(&self, evaluator: &PoseEvaluator, index: usize) -> (Quat, Vec3) {
+        let body = &self.bodies[index];
+        match evaluator.states.get(body.bone_index as usize) {
+            Some(state) if body.bone_index >= 0 => (
+                quat_mul(state.global_rotation, body.offset_rotation),
+                add(
+                    state.global_position,
+                    quat_rotate(state.global_rotation, body.offset_translation),
+                ),
+            ),
+            _ => (body.rest_rotation, body.rest_position),
+        }
+    }
+    fn step(&mut self, evaluator: &PoseEvaluator, dt: f32) {
+        for index in 0..self.bodies.len() {
+            let (rotation, position) = self.bone_driven_transform(evaluator, index);
+            let body = &mut self.rigid_bodies[self.bodies[index].handle];
+            match self.bodies[index].calc_method {
+                RigidCalcMethod::Static => {
+                    body.set_next_kinematic_position(isometry(rotation, position));
+                }
+                RigidCalcMethod::DynamicWithBonePosition => {
+                    body.set_translation(vector![position[0], position[1], position[2]], true);
+                }
+                RigidCalcMethod::Dynamic => {}
+            }
+        }
+        let gravity = self.settings.gravity;
+        let parameters = IntegrationParameters {
+            dt,
+            num_solver_iterations: NonZeroUsize::new(self.settings.iterations.max(1) as usize)
+                .unwrap(),
+            ..IntegrationParameters::default()
+        };
+        self.pipeline.step(
+            &vector![gravity[0], gravity[1], gravity[2]],
+            &parameters,
+            &mut self.islands,
+            &mut self.broad_phase,
+            &mut self.narrow_phase,
+            &mut self.rigid_bodies,
+            &mut self.colliders,
+            &mut self.impulse_joints,
+            &mut self.multibody_joints,
+            &mut self.ccd_solver,
+            None,
+            &(),
+            &(),
+        );
+    }
+    ///剛体の姿勢を物理演算するボーンへ書き戻す。親のボーンから順に反映する
+    fn write_back(&self, evaluator: &mut PoseEvaluator) {
+        let mut driven: Vec<Option<usize>> = vec![None; evaluator.states.len()];
+        for (index, body) in self.bodies.iter().enumerate() {
+            if !body.is_kinematic() && body.bone_index >= 0 {
+                if let Some(slot) = driven.get_mut(body.bone_index as usize) {
+                    slot.get_or_insert(index);
+                }
+            }
+        }
+        for bone_index in evaluator.order().to_vec() {
+            let body = match driven[bone_index] {
+                Some(body) => &self.bodies[body],
+                None => continue,
+            };
+            let transform = self.rigid_bodies[body.handle].position();
+            let coords = transform.rotation.coords;
+            let translation = transform.translation.vector;
+            let rotation = quat_mul(
+                [coords.x, coords.y, coords.z, coords.w],
+                quat_conjugate(body.offset_rotation),
+            );
+            let position = match body.calc_method {
+                RigidCalcMethod::DynamicWithBonePosition => {
+                    evaluator.states[bone_index].global_position
+                }
+                _ => sub(
+                    [translation.x, translation.y, translation.z],
+                    quat_rotate(rotation, body.offset_translation),
+                ),
+            };
+            evaluator.override_global(bone_index, rotation, position);
+        }
+    }
+}
+
+#[test]
+fn test_pendulum_falls() {
+    use crate::global_model_state::BoneTree;
+    use crate::math::length;
+    use crate::pose::Pose;
+    //0:支点(ボーン追従) 1:おもり(物理)。支点のジョイントは回転だけ自由
+    let bone = |position: [f32; 3], parent: i32| Bone {
+        position,
+        parent,
+        ..Bone::default()
+    };
+    let bones = vec![bone([0.0; 3], -1), bone([4.0, 0.0, 0.0], 0)];
+    let rigid = |bone_index: i32, position: Vec3, calc_method: RigidCalcMethod| Rigid {
+        name: String::new(),
+        name_en: String::new(),
+        bone_index,
+        group: 0,
+        un_collision_group_flag: 0,
+        form: RigidForm::Sphere,
+        size: [0.5; 3],
+        position,
+        rotation: [0.0; 3],
+        mass: 1.0,
+        move_resist: 0.5,
+        rotation_resist: 0.5,
+        repulsion: 0.0,
+        friction: 0.0,
+        calc_method,
+    };
+    let rigids = vec![
+        rigid(0, [0.0; 3], RigidCalcMethod::Static),
+        rigid(1, [4.0, 0.0, 0.0], RigidCalcMethod::Dynamic),
+    ];
+    let joints = vec![Joint {
+        name: String::new(),
+        name_en: String::new(),
+        joint_type: JointType::Spring6DOF {
+            a_rigid_index: 0,
+            b_rigid_index: 1,
+            position: [0.0; 3],
+            rotation: [0.0; 3],
+            move_limit_down: [0.0; 3],
+            move_limit_up: [0.0; 3],
+            rotation_limit_down: [1.0; 3],
+            rotation_limit_up: [-1.0; 3],
+            spring_const_move: [0.0; 3],
+            spring_const_rotation: [0.0; 3],
+        },
+    }];
+    let mut physics = PhysicsWorld::new(&rigids, &joints, &bones);
+    physics.settings.warm_up_frames = 0;
+    let tree = BoneTree::from_bones(&bones).0;
+    let pose = Pose::new(bones.len(), 0);
+    let mut evaluator = PoseEvaluator::new(&bones, &tree);
+    for _ in 0..300 {
+        evaluator.evaluate_with_physics(&pose, &mut physics, 1.0 / 60.0);
+    }
+    //おもりは支点から離れずに真下へ垂れ下がる
+    let position = evaluator.states[1].global_position;
+    assert!((length(position) - 4.0).abs() < 0.1);
+    assert!(position[1] < -3.5);
+}
+
+#[test]
+fn test_box_rests_on_box() {
+    use crate::global_model_state::BoneTree;
+    use crate::pose::Pose;
+    //箱は包む球ではなく箱の形で床に乗る
+    let bone = |position: [f32; 3]| Bone {
+        position,
+        parent: -1,
+        ..Bone::default()
+    };
+    let bones = vec![bone([0.0, -1.0, 0.0]), bone([0.0, 3.0, 0.0])];
+    let rigid = |bone_index: i32, size: Vec3, calc_method: RigidCalcMethod| Rigid {
+        name: String::new(),
+        name_en: String::new(),
+        bone_index,
+        group: 0,
+        un_collision_group_flag: 0xFFFF,
+        form: RigidForm::Box,
+        size,
+        position: bones[bone_index as usize].position,
+        rotation: [0.0; 3],
+        mass: 1.0,
+        move_resist: 0.5,
+        rotation_resist: 0.5,
+        repulsion: 0.0,
+        friction: 0.5,
+        calc_method,
+    };
+    let rigids = vec![
+        rigid(0, [10.0, 1.0, 10.0], RigidCalcMethod::Static),
+        rigid(1, [1.0; 3], RigidCalcMethod::Dynamic),
+    ];
+    let mut physics = PhysicsWorld::new(&rigids, &[], &bones);
+    physics.settings.warm_up_frames = 0;
+    let tree = BoneTree::from_bones(&bones).0;
+    let pose = Pose::new(bones.len(), 0);
+    let mut evaluator = PoseEvaluator::new(&bones, &tree);
+    for _ in 0..300 {
+        evaluator.evaluate_with_physics(&pose, &mut physics, 1.0 / 60.0);
+    }
+    let position = evaluator.states[1].global_position;
+    assert!((position[1] - 1.0).abs() < 0.1, "{:?}", position);
+}
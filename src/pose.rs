@@ -0,0 +1,514 @@
+use crate::global_model_state::BoneTree;
+use crate::math::{
+    add, mat4_from_rotation_translation, mat4_transform_point, quat_mul, quat_rotate, quat_slerp,
+    scale, sub, Mat4, Quat, Vec3, QUAT_IDENTITY,
+};
+use crate::physics::PhysicsWorld;
+use crate::vpd::{Vpd, VpdBone, VpdMorph};
+use PMXUtil::types::{Bone, Morph, RotateAndTranslateInherits, Vertex, VertexWeight};
+
+///ボーン1本分のポーズ(初期姿勢からの移動量と回転)
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BonePose {
+    pub translation: [f32; 3],
+    ///quaternion x,y,z,w
+    pub rotation: [f32; 4],
+}
+
+impl Default for BonePose {
+    fn default() -> Self {
+        Self {
+            translation: [0.0; 3],
+            rotation: [0.0, 0.0, 0.0, 1.0],
+        }
+    }
+}
+
+impl BonePose {
+    pub fn is_identity(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+///モデルの現在のポーズ
+///
+/// `bones`と`ik_enabled`はボーンリスト、`morphs`はモーフリストと同じ並び
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Pose {
+    pub bones: Vec<BonePose>,
+    pub morphs: Vec<f32>,
+    ///IKボーンごとのIK有効/無効。IKボーン以外では使わない
+    pub ik_enabled: Vec<bool>,
+}
+
+///VPD適用時にモデル側に見つからなかった名前
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct VpdApplyReport {
+    pub unmatched_bones: Vec<String>,
+    pub unmatched_morphs: Vec<String>,
+}
+
+impl Pose {
+    pub fn new(bone_count: usize, morph_count: usize) -> Self {
+        Self {
+            bones: vec![BonePose::default(); bone_count],
+            morphs: vec![0.0; morph_count],
+            ik_enabled: vec![true; bone_count],
+        }
+    }
+    pub fn reset(&mut self) {
+        self.bones
+            .iter_mut()
+            .for_each(|pose| *pose = BonePose::default());
+        self.morphs.iter_mut().for_each(|weight| *weight = 0.0);
+    }
+    ///名前でボーンとモーフを突き合わせてポーズを上書きする
+    ///
+    /// VPDに含まれないボーンは元のポーズのまま
+    pub fn apply_vpd(&mut self, vpd: &Vpd, bones: &[Bone], morphs: &[Morph]) -> VpdApplyReport {
+        let mut report = VpdApplyReport::default();
+        for vpd_bone in &vpd.bones {
+            match bones.iter().position(|bone| bone.name == vpd_bone.name) {
+                Some(index) => {
+                    self.bones[index] = BonePose {
+                        translation: vpd_bone.translation,
+                        rotation: vpd_bone.rotation,
+                    }
+                }
+                None => report.unmatched_bones.push(vpd_bone.name.clone()),
+            }
+        }
+        for vpd_morph in &vpd.morphs {
+            match morphs.iter().position(|morph| morph.name == vpd_morph.name) {
+                Some(index) => self.morphs[index] = vpd_morph.weight,
+                None => report.unmatched_morphs.push(vpd_morph.name.clone()),
+            }
+        }
+        report
+    }
+    ///初期姿勢から動いているボーンと0でないモーフだけを書き出す
+    pub fn to_vpd(&self, model_name: &str, bones: &[Bone], morphs: &[Morph]) -> Vpd {
+        Vpd {
+            model_name: format!("{}.osm", model_name),
+            bones: bones
+                .iter()
+                .zip(self.bones.iter())
+                .filter(|(_, pose)| !pose.is_identity())
+                .map(|(bone, pose)| VpdBone {
+                    name: bone.name.clone(),
+                    translation: pose.translation,
+                    rotation: pose.rotation,
+                })
+                .collect(),
+            morphs: morphs
+                .iter()
+                .zip(self.morphs.iter())
+                .filter(|(_, weight)| **weight != 0.0)
+                .map(|(morph, weight)| VpdMorph {
+                    name: morph.name.clone(),
+                    weight: *weight,
+                })
+                .collect(),
+        }
+    }
+}
+
+///評価中のボーン1本分の姿勢
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct BoneState {
+    ///ポーズで与えられた回転
+    pub rotation: Quat,
+    pub translation: Vec3,
+    ///IKで追加される回転
+    pub ik_rotation: Quat,
+    ///付与親から受け取る回転と移動
+    pub grant_rotation: Quat,
+    pub grant_translation: Vec3,
+    pub global_rotation: Quat,
+    pub global_position: Vec3,
+}
+
+impl Default for BoneState {
+    fn default() -> Self {
+        Self {
+            rotation: QUAT_IDENTITY,
+            translation: [0.0; 3],
+            ik_rotation: QUAT_IDENTITY,
+            grant_rotation: QUAT_IDENTITY,
+            grant_translation: [0.0; 3],
+            global_rotation: QUAT_IDENTITY,
+            global_position: [0.0; 3],
+        }
+    }
+}
+
+impl BoneState {
+    pub fn local_rotation(&self) -> Quat {
+        quat_mul(
+            quat_mul(self.rotation, self.ik_rotation),
+            self.grant_rotation,
+        )
+    }
+    pub fn local_translation(&self) -> Vec3 {
+        add(self.translation, self.grant_translation)
+    }
+    ///ボーンの座標系からワールド座標系への変換
+    pub fn global_matrix(&self) -> Mat4 {
+        mat4_from_rotation_translation(self.global_rotation, self.global_position)
+    }
+}
+
+///ボーンリストとポーズからボーンの姿勢を求める
+pub struct PoseEvaluator<'a> {
+    pub(crate) bones: &'a [Bone],
+    pub(crate) children: Vec<Vec<usize>>,
+    ///親から順にたどる計算順
+    order: Vec<usize>,
+    ///変形階層順(物理後, 変形階層, インデックス)
+    deform_order: Vec<usize>,
+    pub states: Vec<BoneState>,
+}
+
+impl<'a> PoseEvaluator<'a> {
+    ///計算順はボーン木の前順。木に含まれないボーンは最後にインデックス順で計算する
+    pub fn new(bones: &'a [Bone], tree: &BoneTree) -> Self {
+        let mut children = vec![vec![]; bones.len()];
+        for (index, bone) in bones.iter().enumerate() {
+            if let Some(siblings) = children.get_mut(bone.parent as usize) {
+                if bone.parent >= 0 {
+                    siblings.push(index);
+                }
+            }
+        }
+        let mut in_tree = vec![false; bones.len()];
+        let mut order: Vec<usize> = tree
+            .preorder()
+            .into_iter()
+            .filter(|&index| index < bones.len())
+            .filter(|&index| !std::mem::replace(&mut in_tree[index], true))
+            .collect();
+        order.extend((0..bones.len()).filter(|&index| !in_tree[index]));
+        Self {
+            bones,
+            children,
+            order,
+            deform_order: deform_order(bones),
+            states: vec![BoneState::default(); bones.len()],
+        }
+    }
+    pub fn evaluate(&mut self, pose: &Pose) -> &[BoneState] {
+        self.evaluate_forward(pose);
+        self.evaluate_phase(pose, false);
+        self.evaluate_phase(pose, true);
+        &self.states
+    }
+    ///物理前の変形、物理演算、物理後の変形の順に評価する
+    pub fn evaluate_with_physics(
+        &mut self,
+        pose: &Pose,
+        physics: &mut PhysicsWorld,
+        elapsed: f32,
+    ) -> &[BoneState] {
+        self.evaluate_forward(pose);
+        self.evaluate_phase(pose, false);
+        physics.update(self, elapsed);
+        self.evaluate_phase(pose, true);
+        &self.states
+    }
+    ///ポーズの値だけで親から順にグローバル姿勢を求める
+    fn evaluate_forward(&mut self, pose: &Pose) {
+        for (state, bone_pose) in self.states.iter_mut().zip(pose.bones.iter()) {
+            *state = BoneState {
+                rotation: bone_pose.rotation,
+                translation: bone_pose.translation,
+                ..BoneState::default()
+            };
+        }
+        for position in 0..self.order.len() {
+            self.update_global(self.order[position]);
+        }
+    }
+    ///物理前(`after_physics == false`)または物理後のボーンに付与とIKを適用する
+    ///
+    /// 変形階層順に適用し、後のボーンは先に解いた結果を参照する
+    pub fn evaluate_phase(&mut self, pose: &Pose, after_physics: bool) {
+        for position in 0..self.deform_order.len() {
+            let index = self.deform_order[position];
+            if self.bones[index].physics_after_deform != after_physics {
+                continue;
+            }
+            if self.update_grant(index) {
+                self.update_subtree(index);
+            }
+            if self.bones[index].ik_info.is_some()
+                && pose.ik_enabled.get(index).copied().unwrap_or(true)
+            {
+                crate::ik::solve_ccd(self, index);
+            }
+        }
+    }
+    pub fn global_matrices(&self) -> Vec<Mat4> {
+        self.states.iter().map(BoneState::global_matrix).collect()
+    }
+    ///付与親の回転/移動に付与率を掛けて受け取る。付与があればtrue
+    fn update_grant(&mut self, index: usize) -> bool {
+        let bone = &self.bones[index];
+        let (grant_parent, ratio, rotate, translate) = match bone.inherits.rotate_and_translate {
+            RotateAndTranslateInherits::None => return false,
+            RotateAndTranslateInherits::Both(parent, ratio) => (parent, ratio, true, true),
+            RotateAndTranslateInherits::Rotate(parent, ratio) => (parent, ratio, true, false),
+            RotateAndTranslateInherits::Translate(parent, ratio) => (parent, ratio, false, true),
+        };
+        let grant_parent = match self.bones.get(grant_parent as usize) {
+            Some(parent_bone) if grant_parent >= 0 && grant_parent as usize != index => {
+                (grant_parent as usize, parent_bone)
+            }
+            _ => return false,
+        };
+        let parent_state = self.states[grant_parent.0];
+        //付与親も付与を受けている場合は付与分だけを連鎖させる(ローカル付与を除く)
+        let chained = !bone.inherits.inherit_local
+            && grant_parent.1.inherits.rotate_and_translate != RotateAndTranslateInherits::None;
+        let state = &mut self.states[index];
+        if rotate {
+            let source = if chained {
+                parent_state.grant_rotation
+            } else {
+                parent_state.rotation
+            };
+            let source = quat_mul(source, parent_state.ik_rotation);
+            state.grant_rotation = quat_slerp(QUAT_IDENTITY, source, ratio);
+        }
+        if translate {
+            let source = if chained {
+                parent_state.grant_translation
+            } else {
+                parent_state.translation
+            };
+            state.grant_translation = scale(source, ratio);
+        }
+        true
+    }
+    ///親の姿勢からボーン1本のグローバル姿勢を求める
+    pub(crate) fn update_global(&mut self, index: usize) {
+        let bone = &self.bones[index];
+        let state = self.states[index];
+        let offset = add(bone.position, state.local_translation());
+        let (global_rotation, global_position) = match self.bones.get(bone.parent as usize) {
+            Some(parent) if bone.parent >= 0 => {
+                let parent_state = &self.states[bone.parent as usize];
+                (
+                    quat_mul(parent_state.global_rotation, state.local_rotation()),
+                    add(
+                        parent_state.global_position,
+                        quat_rotate(parent_state.global_rotation, sub(offset, parent.position)),
+                    ),
+                )
+            }
+            _ => (state.local_rotation(), offset),
+        };
+        let state = &mut self.states[index];
+        state.global_rotation = global_rotation;
+        state.global_position = global_position;
+    }
+    pub(crate) fn order(&self) -> &[usize] {
+        &self.order
+    }
+    ///ボーンのグローバル姿勢を直接与え、子孫をそれに追従させる
+    pub(crate) fn override_global(&mut self, index: usize, rotation: Quat, position: Vec3) {
+        let state = &mut self.states[index];
+        state.global_rotation = rotation;
+        state.global_position = position;
+        for position in 0..self.children[index].len() {
+            let child = self.children[index][position];
+            self.update_subtree(child);
+        }
+    }
+    ///ボーンとその子孫のグローバル姿勢を更新する
+    pub(crate) fn update_subtree(&mut self, index: usize) {
+        let mut stack = vec![index];
+        let mut visited = 0;
+        while let Some(index) = stack.pop() {
+            visited += 1;
+            if visited > self.bones.len() {
+                break;
+            }
+            self.update_global(index);
+            stack.extend(self.children[index].iter());
+        }
+    }
+}
+
+///ボーンを(物理後, 変形階層, インデックス)の順に並べる
+pub fn deform_order(bones: &[Bone]) -> Vec<usize> {
+    let mut order: Vec<usize> = (0..bones.len()).collect();
+    order.sort_by_key(|&index| {
+        let bone = &bones[index];
+        (bone.physics_after_deform, bone.deform_depth, index)
+    });
+    order
+}
+
+///GPUなしでポーズを評価し、各ボーンのグローバル行列を返す
+pub fn evaluate_global_matrices(bones: &[Bone], tree: &BoneTree, pose: &Pose) -> Vec<Mat4> {
+    let mut evaluator = PoseEvaluator::new(bones, tree);
+    evaluator.evaluate(pose);
+    evaluator.global_matrices()
+}
+
+///頂点のウェイトを(ボーン, ウェイト)の組にする。BDEF2とSDEFの2本目は1から引いた値
+pub fn weight_influences(weight: &VertexWeight) -> [(i32, f32); 4] {
+    match *weight {
+        VertexWeight::BDEF1(index) => [(index, 1.0), (-1, 0.0), (-1, 0.0), (-1, 0.0)],
+        VertexWeight::BDEF2 {
+            bone_index_1,
+            bone_index_2,
+            bone_weight_1,
+        }
+        | VertexWeight::SDEF {
+            bone_index_1,
+            bone_index_2,
+            bone_weight_1,
+            ..
+        } => [
+            (bone_index_1, bone_weight_1),
+            (bone_index_2, 1.0 - bone_weight_1),
+            (-1, 0.0),
+            (-1, 0.0),
+        ],
+        VertexWeight::BDEF4 {
+            bone_index_1,
+            bone_index_2,
+            bone_index_3,
+            bone_index_4,
+            bone_weight_1,
+            bone_weight_2,
+            bone_weight_3,
+            bone_weight_4,
+        }
+        | VertexWeight::QDEF {
+            bone_index_1,
+            bone_index_2,
+            bone_index_3,
+            bone_index_4,
+            bone_weight_1,
+            bone_weight_2,
+            bone_weight_3,
+            bone_weight_4,
+        } => [
+            (bone_index_1, bone_weight_1),
+            (bone_index_2, bone_weight_2),
+            (bone_index_3, bone_weight_3),
+            (bone_index_4, bone_weight_4),
+        ],
+    }
+}
+
+///ボーンのグローバル行列で頂点を変形した位置を返す
+///
+/// 表示と選択用なのでSDEFとQDEFも線形ブレンドで近似する
+pub fn skin_vertices(vertices: &[Vertex], bones: &[Bone], matrices: &[Mat4]) -> Vec<Vec3> {
+    vertices
+        .iter()
+        .map(|vertex| {
+            let mut position = [0.0; 3];
+            let mut total = 0.0;
+            for &(index, weight) in weight_influences(&vertex.weight_type).iter() {
+                if index < 0 || weight <= 0.0 {
+                    continue;
+                }
+                if let (Some(bone), Some(matrix)) =
+                    (bones.get(index as usize), matrices.get(index as usize))
+                {
+                    let local = sub(vertex.position, bone.position);
+                    position = add(position, scale(mat4_transform_point(matrix, local), weight));
+                    total += weight;
+                }
+            }
+            if total > f32::EPSILON {
+                scale(position, 1.0 / total)
+            } else {
+                vertex.position
+            }
+        })
+        .collect()
+}
+
+#[test]
+fn test_grant_rotation() {
+    use crate::math::{quat_from_axis_angle, quat_to_euler};
+    use PMXUtil::types::BoneInherits;
+    let mut bones = vec![Bone::default(), Bone::default(), Bone::default()];
+    bones[0].parent = -1;
+    bones[1].parent = -1;
+    bones[2].parent = -1;
+    //1は0の回転を半分、2は1の付与をそのまま反転して受け取る
+    bones[1].inherits = BoneInherits {
+        inherit_local: false,
+        rotate_and_translate: RotateAndTranslateInherits::Rotate(0, 0.5),
+    };
+    bones[2].inherits = BoneInherits {
+        inherit_local: false,
+        rotate_and_translate: RotateAndTranslateInherits::Rotate(1, -1.0),
+    };
+    let mut pose = Pose::new(bones.len(), 0);
+    pose.bones[0].rotation = quat_from_axis_angle([0.0, 1.0, 0.0], 1.0);
+    let mut evaluator = PoseEvaluator::new(&bones, &BoneTree::from_bones(&bones).0);
+    let states = evaluator.evaluate(&pose);
+    assert!((quat_to_euler(states[1].global_rotation)[1] - 0.5).abs() < 1.0e-4);
+    assert!((quat_to_euler(states[2].global_rotation)[1] + 0.5).abs() < 1.0e-4);
+}
+
+#[test]
+fn test_deform_order() {
+    let mut bones = vec![Bone::default(); 4];
+    bones[0].deform_depth = 1;
+    bones[1].physics_after_deform = true;
+    bones[2].deform_depth = -1;
+    assert_eq!(deform_order(&bones), vec![2, 3, 0, 1]);
+}
+
+#[test]
+fn test_global_matrices() {
+    use crate::math::{mat4_transform_point, quat_from_axis_angle};
+    let bone = |position: [f32; 3], parent: i32| Bone {
+        position,
+        parent,
+        ..Bone::default()
+    };
+    let bones = vec![bone([0.0, 0.0, 0.0], -1), bone([0.0, 1.0, 0.0], 0)];
+    let mut pose = Pose::new(bones.len(), 0);
+    pose.bones[0].translation = [1.0, 0.0, 0.0];
+    pose.bones[0].rotation = quat_from_axis_angle([0.0, 0.0, 1.0], std::f32::consts::FRAC_PI_2);
+    let matrices = evaluate_global_matrices(&bones, &BoneTree::from_bones(&bones).0, &pose);
+    //子ボーンは親の回転でX軸負の方向へ倒れる
+    let tip = mat4_transform_point(&matrices[1], [0.0; 3]);
+    assert!((tip[0] - 0.0).abs() < 1.0e-5 && (tip[1] - 0.0).abs() < 1.0e-5);
+    let root = mat4_transform_point(&matrices[0], [0.0; 3]);
+    assert!((root[0] - 1.0).abs() < 1.0e-5);
+}
+
+#[test]
+fn test_skin_vertices() {
+    use crate::vertex_edit::test_vertex;
+    let bone = |position: [f32; 3]| Bone {
+        position,
+        parent: -1,
+        ..Bone::default()
+    };
+    let bones = vec![bone([0.0; 3]), bone([0.0, 1.0, 0.0])];
+    let mut pose = Pose::new(bones.len(), 0);
+    pose.bones[1].translation = [2.0, 0.0, 0.0];
+    let matrices = evaluate_global_matrices(&bones, &BoneTree::from_bones(&bones).0, &pose);
+    let vertex = test_vertex(
+        [0.0, 1.0, 0.0],
+        VertexWeight::BDEF2 {
+            bone_index_1: 0,
+            bone_index_2: 1,
+            bone_weight_1: 0.25,
+        },
+    );
+    //移動したボーンへのウェイト分だけ動く
+    let skinned = skin_vertices(&[vertex], &bones, &matrices);
+    assert!((skinned[0][0] - 1.5).abs() < 1.0e-5 && (skinned[0][1] - 1.0).abs() < 1.0e-5);
+}
@@ -0,0 +1,192 @@
+//! 保存する文字列の確認
+//!
+//! PMXはUTF-16LEでもUTF-8でもすべての文字を表せるので文字コードの変換では何も失われない。
+//! ただしMMDが表示できない文字や、Shift-JISで名前を持つVMDに入らない名前がある
+
+use crate::document::PmxDocument;
+
+///VMDのボーン名・モーフ名の長さ(Shift-JISのバイト数)
+pub const VMD_NAME_BYTES: usize = 15;
+
+///文字列の問題
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TextProblem {
+    ///改行とタブ以外の制御文字や、BMP外の文字(絵文字など)はMMDで表示できない
+    Unrenderable(char),
+    ///Shift-JISにない文字はVMDやVPDでは`?`になり、テクスチャのパスにあるとMMDで開けない
+    NotShiftJis(char),
+    ///Shift-JISでのバイト数。VMDでは15バイトで切れる
+    TooLongForVmd(usize),
+}
+impl std::fmt::Display for TextProblem {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TextProblem::Unrenderable(c) => write!(f, "MMDで表示できない文字 U+{:04X}", *c as u32),
+            TextProblem::NotShiftJis(c) => write!(f, "Shift-JISにない文字 '{}'", c),
+            TextProblem::TooLongForVmd(bytes) => write!(
+                f,
+                "Shift-JISで{}バイト(VMDは{}バイトまで)",
+                bytes, VMD_NAME_BYTES
+            ),
+        }
+    }
+}
+
+///問題のある文字列と、その場所
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct TextIssue {
+    pub location: String,
+    pub text: String,
+    pub problem: TextProblem,
+}
+
+///確かめる文字列の種類
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum TextKind {
+    Text,
+    ///VMDに書くボーン名・モーフ名
+    VmdName,
+    ///テクスチャのパス。MMDはShift-JISにない文字を含むファイルを開けない
+    Path,
+}
+
+pub fn check_text(text: &str, kind: TextKind) -> Vec<TextProblem> {
+    let mut problems: Vec<TextProblem> = text
+        .chars()
+        .filter(|&c| (c.is_control() && !matches!(c, '\n' | '\r' | '\t')) || c as u32 > 0xFFFF)
+        .map(TextProblem::Unrenderable)
+        .collect();
+    if kind == TextKind::Text {
+        return problems;
+    }
+    //Shift-JISにない文字は`?`の1バイトとして数える
+    let mut bytes = 0;
+    let mut buffer = [0; 4];
+    for c in text.chars() {
+        let (encoded, _, unmappable) = encoding_rs::SHIFT_JIS.encode(c.encode_utf8(&mut buffer));
+        if unmappable {
+            problems.push(TextProblem::NotShiftJis(c));
+            bytes += 1;
+        } else {
+            bytes += encoded.len();
+        }
+    }
+    if kind == TextKind::VmdName && bytes > VMD_NAME_BYTES {
+        problems.push(TextProblem::TooLongForVmd(bytes));
+    }
+    problems
+}
+
+///モデルのすべての文字列を確かめる。ボーン名とモーフ名はVMDの名前としても、
+///テクスチャはShift-JISのパスとしても確かめる
+pub fn check_document(document: &PmxDocument) -> Vec<TextIssue> {
+    let mut issues = vec![];
+    let mut check = |location: String, text: &str, kind: TextKind| {
+        for problem in check_text(text, kind) {
+            issues.push(TextIssue {
+                location: location.clone(),
+                text: text.to_owned(),
+                problem,
+            });
+        }
+    };
+    let info = &document.model_info;
+    check("モデル名".to_owned(), &info.name, TextKind::Text);
+    check("モデル名(英)".to_owned(), &info.name_en, TextKind::Text);
+    check("コメント".to_owned(), &info.comment, TextKind::Text);
+    check("コメント(英)".to_owned(), &info.comment_en, TextKind::Text);
+    for (index, texture) in document.textures.iter().enumerate() {
+        check(format!("テクスチャ{}", index), texture, TextKind::Path);
+    }
+    for (index, material) in document.materials.iter().enumerate() {
+        check(format!("材質{}", index), &material.name, TextKind::Text);
+        check(
+            format!("材質{}(英)", index),
+            &material.english_name,
+            TextKind::Text,
+        );
+        check(
+            format!("材質{}のメモ", index),
+            &material.memo,
+            TextKind::Text,
+        );
+    }
+    for (index, bone) in document.bones.iter().enumerate() {
+        check(format!("ボーン{}", index), &bone.name, TextKind::VmdName);
+        check(
+            format!("ボーン{}(英)", index),
+            &bone.english_name,
+            TextKind::Text,
+        );
+    }
+    for (index, morph) in document.morphs.iter().enumerate() {
+        check(format!("モーフ{}", index), &morph.name, TextKind::VmdName);
+        check(
+            format!("モーフ{}(英)", index),
+            &morph.english_name,
+            TextKind::Text,
+        );
+    }
+    for (index, frame) in document.frames.iter().enumerate() {
+        check(format!("表示枠{}", index), &frame.name, TextKind::Text);
+        check(
+            format!("表示枠{}(英)", index),
+            &frame.name_en,
+            TextKind::Text,
+        );
+    }
+    for (index, rigid) in document.rigids.iter().enumerate() {
+        check(format!("剛体{}", index), &rigid.name, TextKind::Text);
+        check(format!("剛体{}(英)", index), &rigid.name_en, TextKind::Text);
+    }
+    for (index, joint) in document.joints.iter().enumerate() {
+        check(format!("ジョイント{}", index), &joint.name, TextKind::Text);
+        check(
+            format!("ジョイント{}(英)", index),
+            &joint.name_en,
+            TextKind::Text,
+        );
+    }
+    for (index, soft_body) in document.soft_bodies.iter().enumerate() {
+        check(
+            format!("ソフトボディ{}", index),
+            &soft_body.name,
+            TextKind::Text,
+        );
+        check(
+            format!("ソフトボディ{}(英)", index),
+            &soft_body.name_en,
+            TextKind::Text,
+        );
+    }
+    issues
+}
+
+#[test]
+fn test_check_text() {
+    assert!(check_text("左腕", TextKind::VmdName).is_empty());
+    assert!(check_text("コメント\r\n2行目", TextKind::Text).is_empty());
+    assert_eq!(
+        check_text("笑顔😀", TextKind::Text),
+        vec![TextProblem::Unrenderable('😀')]
+    );
+    //全角9文字は18バイト
+    assert_eq!(
+        check_text("左腕捩れ補助ボーン", TextKind::VmdName),
+        vec![TextProblem::TooLongForVmd(18)]
+    );
+    assert_eq!(check_text("左腕捩れ補助", TextKind::VmdName), vec![]);
+    assert_eq!(
+        check_text("한글", TextKind::VmdName),
+        vec![
+            TextProblem::NotShiftJis('한'),
+            TextProblem::NotShiftJis('글')
+        ]
+    );
+    assert!(check_text("한글", TextKind::Text).is_empty());
+    assert_eq!(
+        check_text("tex/한.png", TextKind::Path),
+        vec![TextProblem::NotShiftJis('한')]
+    );
+    assert!(check_text("tex/とても長いテクスチャの名前.png", TextKind::Path).is_empty());
+}
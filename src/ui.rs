@@ -1,8 +1,26 @@
+use crate::additional_uv::{discarded, resize_additional_uv, UvDiscard};
+use crate::bone_remap::{parent_first_order, BoneRemap};
+use crate::document::{default_file_name, DocumentChanges, PmxDocument};
 use crate::global_model_state::BoneTree;
+use crate::history::{BoneReferences, BoneState, Change, Step, UvState};
+use crate::ik::validate_chain;
+use crate::index_size::IndexSizes;
+use crate::math::{add, quat_conjugate, quat_mul, quat_rotate, scale, sub, QUAT_IDENTITY};
+use crate::mirror::{mirror_bone, mirror_vertices, symmetric_pairs, MirrorSource};
+use crate::pose::{BonePose, Pose, PoseEvaluator, VpdApplyReport};
+use crate::text_check::{check_document, TextIssue};
+use crate::vertex_edit::{apply_edit, convert_weight, VertexEdit, WeightKind};
+use crate::vpd::Vpd;
+use crate::weight_check::{check_vertex, check_weights, fix_weights, WeightReport};
 use egui::containers::panel::TopBottomSide;
 
-use PMXUtil::types::{Bone, BoneFlags, Header, ModelInfo, Vertex, VertexWeight};
 use egui::Vec2;
+use std::collections::BTreeSet;
+use std::path::{Path, PathBuf};
+use PMXUtil::types::{
+    Bone, BoneFlags, BoneIKInfo, ConnectionDisplayMode, IKLink, ModelInfo, Rigid, RigidCalcMethod,
+    RotateAndTranslateInherits, Vertex, VertexWeight,
+};
 
 #[derive(Copy, Clone, Eq, PartialEq)]
 pub(crate) enum TabKind {
@@ -46,36 +64,400 @@ impl Tabs {
     }
 }
 
+///ツリー上でドラッグ中のボーンと、離した位置のボーン(新しい親)
+#[derive(Default)]
+struct TreeDrag {
+    dragging: Option<i32>,
+    dropped: Option<(i32, i32)>,
+}
+impl TreeDrag {
+    ///ラベルをドラッグの始点・落とし先として扱う
+    fn handle(&mut self, ui: &egui::Ui, response: &egui::Response, id: i32) {
+        if response.drag_started() {
+            self.dragging = Some(id);
+        }
+        let dragging = match self.dragging {
+            Some(dragging) => dragging,
+            None => return,
+        };
+        let pointer = ui.input().pointer.hover_pos();
+        let over = pointer.is_some_and(|pointer| response.rect.contains(pointer));
+        if over && dragging != id {
+            ui.painter().rect_stroke(
+                response.rect,
+                2.0,
+                egui::Stroke::new(1.0, egui::Color32::LIGHT_BLUE),
+            );
+            if ui.input().pointer.any_released() {
+                self.dropped = Some((dragging, id));
+            }
+        }
+    }
+}
+
+///ツリー表示の絞り込み条件。有効な条件をすべて満たすボーンを表示する
+#[derive(Default)]
+struct TreeFilter {
+    search: String,
+    ik: bool,
+    physics: bool,
+    invisible: bool,
+    unused: bool,
+}
+impl TreeFilter {
+    fn is_active(&self) -> bool {
+        !self.search.is_empty() || self.ik || self.physics || self.invisible || self.unused
+    }
+}
+///ツリーの開閉状態など、ボーンの中身以外の表示状態
+#[derive(Default)]
+struct TreeViewState {
+    drag: TreeDrag,
+    collapsed: Vec<bool>,
+    filter: TreeFilter,
+    ///次の描画で選択中のボーンまでスクロールする
+    scroll_to_selected: bool,
+    ///絞り込み中に表示するボーン。絞り込んでいなければNone
+    shown: Option<Vec<bool>>,
+}
+
+///ツリーを1ノード分描画し、開いていれば子を続けて描画する
+///
+/// 絞り込み中は該当するボーンとその祖先だけを開いた状態で描画する
 fn display_in_collapsing_header(
     tree: &BoneTree,
+    node: i32,
     ui: &mut egui::Ui,
     select: &mut i32,
+    state: &mut TreeViewState,
     data_source: &[Bone],
     indent_level: usize,
 ) {
-    let name = if tree.id == -1 {
+    let name = if node == -1 {
         "-1:Root".to_string()
     } else {
-        format!(
-            "{}{}:{}",
-            "\t".repeat(indent_level),
-            tree.id,
-            &data_source[tree.id as usize].name
-        )
+        format!("{}:{}", node, &data_source[node as usize].name)
     };
+    let filtering = state.shown.is_some();
+    let children: Vec<usize> = tree
+        .children(node)
+        .filter(|&child| state.shown.as_ref().is_none_or(|shown| shown[child]))
+        .collect();
+    let expanded =
+        node < 0 || filtering || !state.collapsed.get(node as usize).copied().unwrap_or(false);
 
-    egui::Frame::none().show(ui, |ui| {
-        ui.vertical(|ui| {
-            let label = egui::SelectableLabel::new(tree.id == *select, name);
-            if ui.add(label).clicked() {
-                *select = tree.id;
-                println!("{} selected", tree.id);
+    ui.horizontal(|ui| {
+        ui.add_space(indent_level as f32 * 12.0);
+        if node >= 0 && !children.is_empty() && !filtering {
+            let icon = if expanded { "▼" } else { "▶" };
+            if ui.small_button(icon).clicked() {
+                if let Some(collapsed) = state.collapsed.get_mut(node as usize) {
+                    *collapsed = expanded;
+                }
             }
-            for sub_tree in tree.child.values() {
-                display_in_collapsing_header(sub_tree, ui, select, data_source, indent_level + 1);
+        } else {
+            ui.add_space(18.0);
+        }
+        let label = egui::SelectableLabel::new(node == *select, name);
+        let response = ui.add(label).interact(egui::Sense::drag());
+        if response.clicked() && node >= 0 {
+            *select = node;
+        }
+        if node == *select && std::mem::take(&mut state.scroll_to_selected) {
+            response.scroll_to_me(Some(egui::Align::Center));
+        }
+        state.drag.handle(ui, &response, node);
+    });
+    if expanded {
+        for child in children {
+            display_in_collapsing_header(
+                tree,
+                child as i32,
+                ui,
+                select,
+                state,
+                data_source,
+                indent_level + 1,
+            );
+        }
+    }
+}
+fn filter_bones(
+    bones: &[Bone],
+    tree: &BoneTree,
+    rigids: &[Rigid],
+    weighted_bones: &[bool],
+    filter: &TreeFilter,
+) -> Vec<bool> {
+    let search = filter.search.to_lowercase();
+    //IKボーンとそのリンク
+    let mut ik_bones = vec![false; bones.len()];
+    for (index, bone) in bones.iter().enumerate() {
+        if let Some(ik_info) = &bone.ik_info {
+            ik_bones[index] = true;
+            for link in &ik_info.ik_links {
+                if let Some(flag) = ik_bones.get_mut(link.ik_bone_index as usize) {
+                    *flag = true;
+                }
             }
+        }
+    }
+    //ボーン追従以外の剛体が付いたボーン
+    let mut physics_bones = vec![false; bones.len()];
+    for rigid in rigids {
+        if rigid.calc_method == RigidCalcMethod::Static {
+            continue;
+        }
+        if let Some(flag) = physics_bones.get_mut(rigid.bone_index as usize) {
+            *flag = true;
+        }
+    }
+    let mut shown: Vec<bool> = bones
+        .iter()
+        .enumerate()
+        .map(|(index, bone)| {
+            (search.is_empty()
+                || bone.name.to_lowercase().contains(&search)
+                || bone.english_name.to_lowercase().contains(&search))
+                && (!filter.ik || ik_bones[index])
+                && (!filter.physics || physics_bones[index])
+                && (!filter.invisible || !bone.display_bone_in_viewer)
+                && (!filter.unused || !weighted_bones.get(index).copied().unwrap_or(false))
+        })
+        .collect();
+    //子から親へ向かう順に見て、該当する子孫があれば祖先も表示する
+    for &index in tree.preorder().iter().rev() {
+        let parent = tree.parent(index);
+        if shown[index] && parent >= 0 {
+            shown[parent as usize] = true;
+        }
+    }
+    shown
+}
+///ボーンごとに、いずれかの頂点のウェイトで使われているか
+fn weighted_bones(document: &PmxDocument) -> Vec<bool> {
+    let mut weighted = vec![false; document.bones.len()];
+    for vertex in &document.vertices {
+        let parameters: WeightParameters = vertex.weight_type.into();
+        for (&index, &weight) in parameters
+            .bone_indices
+            .iter()
+            .zip(parameters.weights.iter())
+        {
+            if weight <= 0.0 {
+                continue;
+            }
+            if let Some(flag) = weighted.get_mut(index as usize) {
+                *flag = true;
+            }
+        }
+    }
+    weighted
+}
+fn bone_name(bones: &[Bone], index: i32, lang: Lang) -> &str {
+    match bones.get(index as usize) {
+        Some(bone) if index >= 0 => match lang {
+            Lang::English => &bone.english_name,
+            Lang::Japanese => &bone.name,
+        },
+        _ => "-",
+    }
+}
+fn drag_vec3(ui: &mut egui::Ui, value: &mut [f32; 3]) {
+    for value in value.iter_mut() {
+        ui.add(egui::DragValue::new(value).speed(0.01));
+    }
+}
+///ボーンフラグのチェックボックスと、有効なフラグに付随する値の編集欄
+fn display_bone_flags(ui: &mut egui::Ui, bone: &mut Bone, bones: &[Bone], lang: Lang) {
+    let mut flags = PMXBoneFlags::from(&*bone);
+    let before = flags;
+    egui::Grid::new("bone flags").show(ui, |ui| {
+        ui.checkbox(&mut flags.rotatable, "回転");
+        ui.checkbox(&mut flags.translatable, "移動");
+        ui.checkbox(&mut flags.ik, "IK");
+        ui.checkbox(&mut flags.visible, "表示");
+        ui.checkbox(&mut flags.operable, "操作");
+        ui.end_row();
+        ui.checkbox(&mut flags.inherit_rotation, "回転付与");
+        ui.checkbox(&mut flags.inherit_translation, "移動付与");
+        ui.checkbox(&mut flags.inherit_local, "ローカル付与");
+        ui.checkbox(&mut flags.fixed_axis, "軸制限");
+        ui.checkbox(&mut flags.local_axis, "ローカル軸");
+        ui.end_row();
+        ui.checkbox(&mut flags.connect_to_other_bone, "表示先(ボーン)");
+        ui.checkbox(&mut flags.deform_after_physics, "物理後");
+        ui.checkbox(&mut flags.external_parent, "外部親");
+        ui.end_row();
+    });
+    if flags != before {
+        flags.apply(bone);
+    }
+    ui.horizontal(|ui| {
+        ui.label("表示先");
+        match &mut bone.connection_display_mode {
+            ConnectionDisplayMode::OtherBone(index) => {
+                ui.add(egui::DragValue::new(index).clamp_range(-1..=bones.len() as i32 - 1));
+                ui.label(bone_name(bones, *index, lang));
+            }
+            ConnectionDisplayMode::Offset(offset) => drag_vec3(ui, offset),
+        }
+    });
+    match &mut bone.inherits.rotate_and_translate {
+        RotateAndTranslateInherits::None => {}
+        RotateAndTranslateInherits::Both(parent, ratio)
+        | RotateAndTranslateInherits::Rotate(parent, ratio)
+        | RotateAndTranslateInherits::Translate(parent, ratio) => {
+            ui.horizontal(|ui| {
+                ui.label("付与親");
+                ui.add(egui::DragValue::new(parent).clamp_range(-1..=bones.len() as i32 - 1));
+                ui.label(bone_name(bones, *parent, lang));
+                ui.label("付与率");
+                ui.add(egui::DragValue::new(ratio).speed(0.01));
+            });
+        }
+    }
+    if let Some(axis) = &mut bone.fixed_axis {
+        ui.horizontal(|ui| {
+            ui.label("制限軸");
+            drag_vec3(ui, axis);
+        });
+    }
+    if let Some((x_axis, z_axis)) = &mut bone.local_axis {
+        ui.horizontal(|ui| {
+            ui.label("ローカルX軸");
+            drag_vec3(ui, x_axis);
+        });
+        ui.horizontal(|ui| {
+            ui.label("ローカルZ軸");
+            drag_vec3(ui, z_axis);
+        });
+    }
+    if let Some(key) = &mut bone.external_parent {
+        ui.horizontal(|ui| {
+            ui.label("外部親Key");
+            ui.add(egui::DragValue::new(key));
         });
+    }
+}
+fn drag_degrees(ui: &mut egui::Ui, value: &mut [f32; 3]) {
+    for value in value.iter_mut() {
+        let mut degrees = value.to_degrees();
+        if ui
+            .add(egui::DragValue::new(&mut degrees).suffix("°"))
+            .changed()
+        {
+            *value = degrees.to_radians();
+        }
+    }
+}
+///IKボーンのターゲット、ループ回数、単位角とリンクの編集欄
+fn display_ik(ui: &mut egui::Ui, bone: &mut Bone, bones: &[Bone], lang: Lang) {
+    let ik_info = match &mut bone.ik_info {
+        Some(ik_info) => ik_info,
+        None => return,
+    };
+    ui.separator();
+    ui.horizontal(|ui| {
+        ui.label("IKターゲット");
+        egui::ComboBox::from_id_source("ik target")
+            .selected_text(format!(
+                "{}:{}",
+                ik_info.ik_target_bone_index,
+                bone_name(bones, ik_info.ik_target_bone_index, lang)
+            ))
+            .show_ui(ui, |ui| {
+                for index in 0..bones.len() as i32 {
+                    ui.selectable_value(
+                        &mut ik_info.ik_target_bone_index,
+                        index,
+                        format!("{}:{}", index, bone_name(bones, index, lang)),
+                    );
+                }
+            });
+        ui.label("ループ");
+        ui.add(egui::DragValue::new(&mut ik_info.ik_iter_count).clamp_range(0..=255));
+        ui.label("単位角");
+        let mut degrees = ik_info.ik_limit_angle.to_degrees();
+        if ui
+            .add(egui::DragValue::new(&mut degrees).suffix("°"))
+            .changed()
+        {
+            ik_info.ik_limit_angle = degrees.to_radians();
+        }
     });
+    //ループ中は並べ替えず、押されたボタンを最後に反映する
+    enum LinkEdit {
+        Up(usize),
+        Down(usize),
+        Remove(usize),
+    }
+    let mut edit = None;
+    let link_count = ik_info.ik_links.len();
+    for (link, ik_link) in ik_info.ik_links.iter_mut().enumerate() {
+        ui.horizontal(|ui| {
+            ui.label(format!("リンク{}", link));
+            ui.add(
+                egui::DragValue::new(&mut ik_link.ik_bone_index)
+                    .clamp_range(-1..=bones.len() as i32 - 1),
+            );
+            ui.label(bone_name(bones, ik_link.ik_bone_index, lang));
+            let mut limited = ik_link.angle_limit.is_some();
+            if ui.checkbox(&mut limited, "角度制限").changed() {
+                ik_link.angle_limit = if limited {
+                    Some(([0.0; 3], [0.0; 3]))
+                } else {
+                    None
+                };
+            }
+            if ui.add_enabled(link > 0, egui::Button::new("↑")).clicked() {
+                edit = Some(LinkEdit::Up(link));
+            }
+            if ui
+                .add_enabled(link + 1 < link_count, egui::Button::new("↓"))
+                .clicked()
+            {
+                edit = Some(LinkEdit::Down(link));
+            }
+            if ui.button("削除").clicked() {
+                edit = Some(LinkEdit::Remove(link));
+            }
+        });
+        if let Some((lower, upper)) = &mut ik_link.angle_limit {
+            ui.horizontal(|ui| {
+                ui.label("下限");
+                drag_degrees(ui, lower);
+                ui.label("上限");
+                drag_degrees(ui, upper);
+            });
+        }
+    }
+    match edit {
+        Some(LinkEdit::Up(link)) => ik_info.ik_links.swap(link - 1, link),
+        Some(LinkEdit::Down(link)) => ik_info.ik_links.swap(link, link + 1),
+        Some(LinkEdit::Remove(link)) => {
+            ik_info.ik_links.remove(link);
+        }
+        None => {}
+    }
+    if ui.button("リンク追加").clicked() {
+        //直前のリンク(なければターゲット)の親を候補にする
+        let last = ik_info
+            .ik_links
+            .last()
+            .map_or(ik_info.ik_target_bone_index, |link| link.ik_bone_index);
+        let parent = bones
+            .get(last as usize)
+            .filter(|_| last >= 0)
+            .map_or(-1, |bone| bone.parent);
+        ik_info.ik_links.push(IKLink {
+            ik_bone_index: parent,
+            angle_limit: None,
+        });
+    }
+    for error in validate_chain(bones, ik_info) {
+        ui.colored_label(egui::Color32::RED, error.to_string());
+    }
 }
 #[derive(Debug, Copy, Clone, Eq, PartialEq)]
 pub enum Lang {
@@ -84,43 +466,588 @@ pub enum Lang {
 }
 
 pub struct EguiBoneView {
-    pub(crate) bones: Vec<Bone>,
     pub(crate) current_displaying_bone: i32,
-    pub(crate) bone_tree: BoneTree,
     pub(crate) lang: Lang,
+    pub(crate) pose: Pose,
+    vpd_path: String,
+    vpd_status: String,
+    vpd_report: VpdApplyReport,
+    tree_view: TreeViewState,
+    ///親を付け替えるときにポーズ後のワールド姿勢を保つ
+    keep_world_position: bool,
+    bone_status: String,
+    ///取り消し履歴へまだ渡していない編集
+    steps: Vec<Step>,
+    ///`record_edit`の間に並びを変えたとき、変える前の参照と頂点
+    remapped: Option<(BoneReferences, Vec<Vertex>)>,
 }
 
 impl EguiBoneView {
-    pub fn new(bones: &[Bone]) -> Self {
+    pub fn new(document: &PmxDocument) -> Self {
         Self {
-            bones: bones.to_vec(),
             current_displaying_bone: 0,
-            bone_tree: BoneTree::from_iter(bones.iter()),
             lang: Lang::Japanese,
+            pose: Pose::new(document.bones.len(), document.morphs.len()),
+            vpd_path: String::new(),
+            vpd_status: String::new(),
+            vpd_report: VpdApplyReport::default(),
+            tree_view: TreeViewState {
+                collapsed: vec![false; document.bones.len()],
+                ..TreeViewState::default()
+            },
+            keep_world_position: false,
+            bone_status: String::new(),
+            steps: vec![],
+            remapped: None,
+        }
+    }
+    ///他のビューから選ばれたボーンを選択し、ツリーを開いてその位置までスクロールする
+    pub fn select_bone(&mut self, document: &PmxDocument, index: i32) {
+        if index < 0 || index as usize >= document.bones.len() {
+            return;
+        }
+        self.current_displaying_bone = index;
+        let mut current = document.bone_tree.parent(index as usize);
+        while current >= 0 {
+            self.tree_view.collapsed[current as usize] = false;
+            current = document.bone_tree.parent(current as usize);
+        }
+        self.tree_view.scroll_to_selected = true;
+    }
+    fn bone_references(document: &PmxDocument) -> BoneReferences {
+        BoneReferences {
+            morphs: document.morphs.clone(),
+            frames: document.frames.clone(),
+            rigids: document.rigids.clone(),
+        }
+    }
+    ///`edit`で変わったボーンの構造を1つの編集として記録する。付け替えた頂点のウェイトも含める
+    ///
+    /// `merge_key`を渡すと、ドラッグなどで続けて行った同じ編集を1つにまとめる
+    fn record_edit(
+        &mut self,
+        document: &mut PmxDocument,
+        merge_key: Option<String>,
+        label: &str,
+        edit: impl FnOnce(&mut Self, &mut PmxDocument),
+    ) {
+        let bones = document.bones.clone();
+        let pose = self.pose.clone();
+        self.remapped = None;
+        edit(self, document);
+        //参照と頂点は並びを変えたときだけ`apply_remap`が控えている
+        let (references, vertices) = match self.remapped.take() {
+            Some((references, vertices)) => (Some(references), Some(vertices)),
+            None => (None, None),
+        };
+        let before = BoneState {
+            bones,
+            pose,
+            references,
+        };
+        let after = BoneState {
+            bones: document.bones.clone(),
+            pose: self.pose.clone(),
+            references: before
+                .references
+                .as_ref()
+                .map(|_| Self::bone_references(document)),
+        };
+        if before == after {
+            return;
+        }
+        let mut step = Step::new(
+            label,
+            Change::Bones {
+                before: Box::new(before),
+                after: Box::new(after),
+            },
+        );
+        if let Some(change) =
+            vertices.and_then(|vertices| Change::vertices(&vertices, &document.vertices))
+        {
+            step = step.with(change);
+        }
+        self.steps.push(match merge_key {
+            Some(key) => step.merging(key),
+            None => step,
+        });
+    }
+    ///取り消し履歴へ積む編集を取り出す
+    pub fn query_steps(&mut self) -> Vec<Step> {
+        std::mem::take(&mut self.steps)
+    }
+    ///取り消しややり直しでボーンの構造を戻す
+    pub fn restore(&mut self, document: &mut PmxDocument, state: &BoneState) {
+        document.bones = state.bones.clone();
+        match &state.references {
+            Some(references) => {
+                document.morphs = references.morphs.clone();
+                document.frames = references.frames.clone();
+                document.rigids = references.rigids.clone();
+                document.notify(DocumentChanges::BONE_STRUCTURE);
+            }
+            None => document.notify(DocumentChanges::BONES),
+        }
+        self.pose = state.pose.clone();
+        self.tree_view.collapsed.resize(document.bones.len(), false);
+        self.current_displaying_bone = self
+            .current_displaying_bone
+            .min(document.bones.len() as i32 - 1)
+            .max(0);
+        document.rebuild_bone_tree();
+    }
+    ///モーフが増減したら、並びが変わったのでポーズのモーフの値は0に戻す
+    pub fn document_changed(&mut self, document: &PmxDocument, changes: DocumentChanges) {
+        if changes.morphs {
+            self.pose.morphs = vec![0.0; document.morphs.len()];
+        }
+    }
+    pub fn restore_bone(&mut self, document: &mut PmxDocument, index: usize, bone: &Bone) {
+        if let Some(current) = document.bones.get_mut(index) {
+            let reparented = current.parent != bone.parent;
+            *current = bone.clone();
+            document.notify(DocumentChanges::BONES);
+            if reparented {
+                document.rebuild_bone_tree();
+            }
+        }
+    }
+    fn display_tree_filter(&mut self, ui: &mut egui::Ui) {
+        let filter = &mut self.tree_view.filter;
+        ui.horizontal(|ui| {
+            ui.label("検索");
+            ui.text_edit_singleline(&mut filter.search);
+        });
+        ui.horizontal(|ui| {
+            ui.checkbox(&mut filter.ik, "IK");
+            ui.checkbox(&mut filter.physics, "物理");
+            ui.checkbox(&mut filter.invisible, "非表示");
+            ui.checkbox(&mut filter.unused, "ウェイトなし");
+        });
+    }
+    ///絞り込みに該当するボーンと、その祖先
+    fn filtered_bones(&self, document: &PmxDocument) -> Vec<bool> {
+        //未使用の絞り込みのときだけ頂点のウェイトを調べる
+        let weighted = if self.tree_view.filter.unused {
+            weighted_bones(document)
+        } else {
+            vec![]
+        };
+        filter_bones(
+            &document.bones,
+            &document.bone_tree,
+            &document.rigids,
+            &weighted,
+            &self.tree_view.filter,
+        )
+    }
+    ///ボーンの親を付け替える
+    ///
+    /// 子孫を親にはできない。親が子より後ろになる場合はボーンを並べ替える
+    pub fn reparent(&mut self, document: &mut PmxDocument, index: usize, new_parent: i32) {
+        if index >= document.bones.len() || new_parent >= document.bones.len() as i32 {
+            return;
+        }
+        if new_parent >= 0 && document.bone_tree.is_descendant(new_parent as usize, index) {
+            self.bone_status = format!(
+                "{}を子孫の{}の子にはできません",
+                document.bones[index].name, document.bones[new_parent as usize].name
+            );
+            return;
+        }
+        if self.keep_world_position {
+            let states = PoseEvaluator::new(&document.bones, &document.bone_tree)
+                .evaluate(&self.pose)
+                .to_vec();
+            let (parent_rotation, parent_position, parent_rest) =
+                match states.get(new_parent as usize) {
+                    Some(parent) if new_parent >= 0 => (
+                        parent.global_rotation,
+                        parent.global_position,
+                        document.bones[new_parent as usize].position,
+                    ),
+                    _ => (QUAT_IDENTITY, [0.0; 3], [0.0; 3]),
+                };
+            //新しい親から見たローカルの回転と移動を求め、付与とIKの分は除いてポーズにする
+            let state = &states[index];
+            let inverse = quat_conjugate(parent_rotation);
+            let local = quat_mul(inverse, state.global_rotation);
+            let bone_pose = &mut self.pose.bones[index];
+            bone_pose.rotation = quat_mul(
+                quat_mul(local, quat_conjugate(state.grant_rotation)),
+                quat_conjugate(state.ik_rotation),
+            );
+            bone_pose.translation = sub(
+                sub(
+                    quat_rotate(inverse, sub(state.global_position, parent_position)),
+                    sub(document.bones[index].position, parent_rest),
+                ),
+                state.grant_translation,
+            );
+        }
+        document.bones[index].parent = new_parent;
+        document.notify(DocumentChanges::BONES);
+        self.bone_status = format!(
+            "{}の親を{}にしました",
+            document.bones[index].name,
+            bone_name(&document.bones, new_parent, Lang::Japanese)
+        );
+        //親が子より後ろになったときだけ親が先になるように並べ替える
+        if new_parent > index as i32 {
+            let remap = BoneRemap::from_order(&document.bones, parent_first_order(&document.bones));
+            if !remap.is_identity() {
+                self.apply_remap(document, remap);
+            }
+            document.rebuild_bone_tree();
+        } else if !document.tree_report.is_empty() {
+            //不正な親子関係の報告を更新する
+            document.rebuild_bone_tree();
+        } else {
+            document.bone_tree.reparent(index, new_parent);
+        }
+    }
+    ///ボーンの並びを変え、ボーンを参照しているデータをすべて付け替える
+    fn apply_remap(&mut self, document: &mut PmxDocument, remap: BoneRemap) {
+        if self.remapped.is_none() {
+            self.remapped = Some((Self::bone_references(document), document.vertices.clone()));
+        }
+        remap.apply_bones(&mut document.bones);
+        remap.apply_vertices(&mut document.vertices);
+        remap.apply_morphs(&mut document.morphs);
+        remap.apply_frames(&mut document.frames);
+        remap.apply_rigids(&mut document.rigids);
+        remap.apply_pose(&mut self.pose);
+        document.notify(DocumentChanges::BONE_STRUCTURE);
+        self.tree_view.collapsed = remap.permute(&self.tree_view.collapsed);
+        self.current_displaying_bone = remap.index(self.current_displaying_bone).max(0);
+    }
+    ///ボーンを末尾に追加して選択する
+    fn push_bone(&mut self, document: &mut PmxDocument, bone: Bone) {
+        document.bone_tree.push(bone.parent);
+        document.bones.push(bone);
+        document.notify(DocumentChanges::BONES);
+        self.pose.bones.push(BonePose::default());
+        self.pose.ik_enabled.push(true);
+        self.tree_view.collapsed.push(false);
+        self.current_displaying_bone = document.bones.len() as i32 - 1;
+    }
+    ///選択中のボーンの子として新しいボーンを追加する
+    pub fn add_bone(&mut self, document: &mut PmxDocument) {
+        let current = self.current_displaying_bone;
+        let position = document
+            .bones
+            .get(current as usize)
+            .map_or([0.0; 3], |bone| bone.position);
+        self.push_bone(
+            document,
+            Bone {
+                name: "新規ボーン".to_owned(),
+                english_name: "new bone".to_owned(),
+                position,
+                parent: current,
+                rotatable_in_viewer: true,
+                display_bone_in_viewer: true,
+                controllable_in_viewer: true,
+                ..Bone::default()
+            },
+        );
+        self.bone_status = "ボーンを追加しました".to_owned();
+    }
+    ///選択中のボーンを同じ親の下に複製する
+    pub fn duplicate_bone(&mut self, document: &mut PmxDocument) {
+        let mut bone = match document.bones.get(self.current_displaying_bone as usize) {
+            Some(bone) => bone.clone(),
+            None => return,
+        };
+        bone.name += "+";
+        bone.english_name += "+";
+        self.bone_status = format!("{}を複製しました", bone.name);
+        self.push_bone(document, bone);
+    }
+    ///選択中のボーンを削除する。子やウェイトなどの参照は親へ付け替える
+    ///
+    /// 移し先のないウェイトは外して残りのボーンで正規化し直す。
+    /// どのボーンも残らない頂点があれば削除しない
+    pub fn delete_bone(&mut self, document: &mut PmxDocument) {
+        let current = self.current_displaying_bone as usize;
+        if document.bones.len() <= 1 || current >= document.bones.len() {
+            self.bone_status = "最後のボーンは削除できません".to_owned();
+            return;
+        }
+        let order = (0..document.bones.len())
+            .filter(|&index| index != current)
+            .collect();
+        let remap = BoneRemap::from_order(&document.bones, order);
+        let orphaned = remap.orphaned_vertices(&document.vertices);
+        if orphaned > 0 {
+            self.bone_status = format!(
+                "{}個の頂点のウェイトの移し先がないので{}は削除できません",
+                orphaned, document.bones[current].name
+            );
+            return;
+        }
+        self.bone_status = format!("{}を削除しました", document.bones[current].name);
+        self.apply_remap(document, remap);
+        document.rebuild_bone_tree();
+    }
+    ///選択中のボーンを左右反転して反対側のボーンを作る。既にあれば位置と設定を上書きする
+    pub fn mirror_bone(&mut self, document: &mut PmxDocument) {
+        let current = self.current_displaying_bone as usize;
+        let mirrored = match mirror_bone(&document.bones, current) {
+            Some(mirrored) => mirrored,
+            None => {
+                self.bone_status = "名前に左右が含まれていません".to_owned();
+                return;
+            }
+        };
+        let existing = document
+            .bones
+            .iter()
+            .position(|bone| bone.name == mirrored.name);
+        match existing {
+            Some(existing) if existing == current => {}
+            Some(existing) => {
+                let parent = mirrored.parent;
+                let status = format!("{}を更新しました", mirrored.name);
+                document.bones[existing] = Bone {
+                    parent: document.bones[existing].parent,
+                    ..mirrored
+                };
+                document.notify(DocumentChanges::BONES);
+                if parent != document.bones[existing].parent {
+                    self.reparent(document, existing, parent);
+                }
+                self.bone_status = status;
+            }
+            None => {
+                self.bone_status = format!("{}を作成しました", mirrored.name);
+                self.push_bone(document, mirrored);
+            }
+        }
+    }
+    ///選択中のボーンを1つ前(`up`)か後ろと入れ替える。親が子より後ろになる場合は入れ替えない
+    pub fn move_bone(&mut self, document: &mut PmxDocument, up: bool) {
+        let current = self.current_displaying_bone as usize;
+        let other = if up {
+            current.checked_sub(1)
+        } else {
+            Some(current + 1).filter(|&other| other < document.bones.len())
+        };
+        let other = match other {
+            Some(other) => other,
+            None => return,
+        };
+        let (front, back) = (current.min(other), current.max(other));
+        if document.bones[back].parent == front as i32 {
+            self.bone_status = "親より前には移動できません".to_owned();
+            return;
+        }
+        let mut order: Vec<usize> = (0..document.bones.len()).collect();
+        order.swap(front, back);
+        self.apply_remap(document, BoneRemap::from_order(&document.bones, order));
+        document.rebuild_bone_tree();
+    }
+    ///VPDを読み込んで現在のポーズに適用する
+    pub fn import_vpd(&mut self, document: &PmxDocument, path: &Path) {
+        self.vpd_path = path.to_string_lossy().into_owned();
+        match Vpd::open(path) {
+            Ok(vpd) => {
+                self.vpd_report = self.pose.apply_vpd(&vpd, &document.bones, &document.morphs);
+                self.vpd_status = format!(
+                    "loaded {} bones / {} morphs",
+                    vpd.bones.len() - self.vpd_report.unmatched_bones.len(),
+                    vpd.morphs.len() - self.vpd_report.unmatched_morphs.len()
+                );
+            }
+            Err(err) => {
+                self.vpd_report = VpdApplyReport::default();
+                self.vpd_status = format!("failed to load VPD: {}", err);
+            }
+        }
+    }
+    ///現在のポーズをVPDとして保存する
+    pub fn export_vpd(&mut self, document: &PmxDocument, path: &Path) {
+        let vpd = self
+            .pose
+            .to_vpd(&document.model_info.name, &document.bones, &document.morphs);
+        self.vpd_status = match vpd.save(path) {
+            Ok(()) => format!(
+                "saved {} bones / {} morphs",
+                vpd.bones.len(),
+                vpd.morphs.len()
+            ),
+            Err(err) => format!("failed to save VPD: {}", err),
+        };
+    }
+    fn display_pose(&mut self, ui: &mut egui::Ui, document: &PmxDocument) {
+        ui.horizontal(|ui| {
+            ui.label("ポーズ");
+            let current = self.current_displaying_bone as usize;
+            let is_ik = document
+                .bones
+                .get(current)
+                .and_then(|bone| bone.ik_info.as_ref())
+                .is_some();
+            if let (true, Some(enabled)) = (is_ik, self.pose.ik_enabled.get_mut(current)) {
+                ui.checkbox(enabled, "IK有効");
+            }
+        });
+        if let Some(bone_pose) = self
+            .pose
+            .bones
+            .get_mut(self.current_displaying_bone as usize)
+        {
+            ui.horizontal(|ui| {
+                ui.label("移動");
+                ui.add(egui::DragValue::new(&mut bone_pose.translation[0]).speed(0.01));
+                ui.add(egui::DragValue::new(&mut bone_pose.translation[1]).speed(0.01));
+                ui.add(egui::DragValue::new(&mut bone_pose.translation[2]).speed(0.01));
+            });
+            ui.horizontal(|ui| {
+                ui.label("回転(quaternion)");
+                let mut changed = false;
+                for value in bone_pose.rotation.iter_mut() {
+                    changed |= ui
+                        .add(
+                            egui::DragValue::new(value)
+                                .speed(0.01)
+                                .clamp_range(-1.0..=1.0),
+                        )
+                        .changed();
+                }
+                if changed {
+                    let length = bone_pose
+                        .rotation
+                        .iter()
+                        .map(|value| value * value)
+                        .sum::<f32>()
+                        .sqrt();
+                    if length > f32::EPSILON {
+                        bone_pose
+                            .rotation
+                            .iter_mut()
+                            .for_each(|value| *value /= length);
+                    }
+                }
+            });
+        }
+        ui.horizontal(|ui| {
+            ui.label("VPD");
+            ui.text_edit_singleline(&mut self.vpd_path);
+            if ui.button("読込").clicked() {
+                let path = self.vpd_path.clone();
+                self.import_vpd(document, Path::new(&path));
+            }
+            if ui.button("保存").clicked() {
+                let path = self.vpd_path.clone();
+                self.export_vpd(document, Path::new(&path));
+            }
+            if ui.button("ポーズ初期化").clicked() {
+                self.pose.reset();
+            }
+        });
+        ui.label(&self.vpd_status);
+        let report = &self.vpd_report;
+        let unmatched = report.unmatched_bones.len() + report.unmatched_morphs.len();
+        if unmatched > 0 {
+            ui.collapsing(format!("見つからない名前 ({})", unmatched), |ui| {
+                for name in &report.unmatched_bones {
+                    ui.label(format!("ボーン: {}", name));
+                }
+                for name in &report.unmatched_morphs {
+                    ui.label(format!("モーフ: {}", name));
+                }
+            });
         }
     }
-    pub fn display(&mut self, ui: &mut egui::Ui) {
+    pub fn display(&mut self, ui: &mut egui::Ui, document: &mut PmxDocument) {
         //lets create tree view
         egui::containers::SidePanel::left("Bone tree view")
             .min_width(270.0)
             .show_inside(ui, |ui| {
+                ui.horizontal(|ui| {
+                    if ui.button("追加").clicked() {
+                        self.record_edit(document, None, "ボーン追加", Self::add_bone);
+                    }
+                    if ui.button("複製").clicked() {
+                        self.record_edit(document, None, "ボーン複製", Self::duplicate_bone);
+                    }
+                    if ui.button("削除").clicked() {
+                        self.record_edit(document, None, "ボーン削除", Self::delete_bone);
+                    }
+                    if ui.button("ミラー").clicked() {
+                        self.record_edit(document, None, "ボーンミラー", Self::mirror_bone);
+                    }
+                    if ui.button("↑").clicked() {
+                        self.record_edit(document, None, "ボーン移動", |view, document| {
+                            view.move_bone(document, true)
+                        });
+                    }
+                    if ui.button("↓").clicked() {
+                        self.record_edit(document, None, "ボーン移動", |view, document| {
+                            view.move_bone(document, false)
+                        });
+                    }
+                });
+                ui.checkbox(
+                    &mut self.keep_world_position,
+                    "付け替え時にワールド姿勢を保つ",
+                );
+                ui.label(&self.bone_status);
+                let report = &document.tree_report;
+                if !report.is_empty() {
+                    ui.collapsing(
+                        format!(
+                            "親子関係の問題 (親なし{} 循環{})",
+                            report.orphans.len(),
+                            report.cycles.len()
+                        ),
+                        |ui| {
+                            for &index in &report.orphans {
+                                let name = bone_name(&document.bones, index, self.lang);
+                                ui.label(format!("{}:{} 親が見つかりません", index, name));
+                            }
+                            for &index in &report.cycles {
+                                let name = bone_name(&document.bones, index, self.lang);
+                                ui.label(format!("{}:{} 親が循環しています", index, name));
+                            }
+                        },
+                    );
+                }
+                self.display_tree_filter(ui);
+                self.tree_view.shown = if self.tree_view.filter.is_active() {
+                    Some(self.filtered_bones(document))
+                } else {
+                    None
+                };
                 egui::ScrollArea::vertical().show(ui, |ui| {
                     display_in_collapsing_header(
-                        &self.bone_tree,
+                        &document.bone_tree,
+                        -1,
                         ui,
                         &mut self.current_displaying_bone,
-                        &self.bones,
+                        &mut self.tree_view,
+                        &document.bones,
                         0,
                     )
                 });
             });
+        if ui.input().pointer.any_released() {
+            self.tree_view.drag.dragging = None;
+        }
+        if let Some((bone, new_parent)) = self.tree_view.drag.dropped.take() {
+            self.record_edit(document, None, "親の付け替え", |view, document| {
+                view.reparent(document, bone as usize, new_parent)
+            });
+        }
 
-        let mut cloned_bone = self
+        let mut cloned_bone = document
             .bones
             .get(self.current_displaying_bone as usize)
             .unwrap()
             .clone();
-        let mut rebuilt_tree = false;
+        let mut new_parent = None;
         egui::containers::CentralPanel::default().show_inside(ui, |ui| {
             ui.vertical(|ui| {
                 ui.horizontal(|ui| {
@@ -135,7 +1062,6 @@ impl EguiBoneView {
                     ui.label("変形階層");
                     let deform = egui::DragValue::new(&mut cloned_bone.deform_depth);
                     ui.add(deform);
-                    ui.checkbox(&mut cloned_bone.physics_after_deform, "物理後");
                 });
                 ui.horizontal(|ui| {
                     ui.label("位置");
@@ -146,126 +1072,438 @@ impl EguiBoneView {
                 ui.horizontal(|ui| {
                     ui.label("親ボーン");
 
-                    rebuilt_tree = ui
-                        .add(egui::DragValue::new(&mut cloned_bone.parent))
-                        .changed();
-                    let parent_name = if cloned_bone.parent < 0 {
-                        "-"
-                    } else {
-                        let parent = self.bones.get(cloned_bone.parent as usize).unwrap();
-                        match self.lang {
-                            Lang::English => &parent.english_name,
-                            Lang::Japanese => &parent.name,
-                        }
-                    };
-                    ui.label(parent_name);
+                    let mut parent = cloned_bone.parent;
+                    if ui
+                        .add(
+                            egui::DragValue::new(&mut parent)
+                                .clamp_range(-1..=document.bones.len() as i32 - 1),
+                        )
+                        .changed()
+                    {
+                        new_parent = Some(parent);
+                    }
+                    ui.label(bone_name(&document.bones, cloned_bone.parent, self.lang));
                 });
+                display_bone_flags(ui, &mut cloned_bone, &document.bones, self.lang);
+                display_ik(ui, &mut cloned_bone, &document.bones, self.lang);
+                ui.separator();
+                self.display_pose(ui, document);
             })
         });
         //ボーン情報更新
-        *self
-            .bones
-            .get_mut(self.current_displaying_bone as usize)
-            .unwrap() = cloned_bone;
+        let index = self.current_displaying_bone as usize;
+        if document.bones[index] != cloned_bone {
+            let before = std::mem::replace(&mut document.bones[index], cloned_bone.clone());
+            document.notify(DocumentChanges::BONES);
+            let label = format!("{}の編集", cloned_bone.name);
+            let change = Change::Bone {
+                index,
+                before: Box::new(before),
+                after: Box::new(cloned_bone),
+            };
+            self.steps
+                .push(Step::new(label, change).merging(format!("bone {}", index)));
+        }
 
         //親ボーンを変更したのでツリー組み立てなおし
-        if rebuilt_tree {
-            self.bone_tree = BoneTree::from_iter(self.bones.iter());
+        if let Some(new_parent) = new_parent {
+            let merge_key = Some(format!("parent {}", index));
+            self.record_edit(
+                document,
+                merge_key,
+                "親の付け替え",
+                |view, document| view.reparent(document, index, new_parent),
+            );
         }
     }
 }
 /// the rust friendly PMX Bone flag representation
-#[derive(Clone, Copy)]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
 struct PMXBoneFlags {
-    is_target_is_other_bone: bool,
-    deform_after_physics: bool, //0x1000
-    allow_rotate: bool,
-    allow_translate: bool,
-    flag1: bool,
+    connect_to_other_bone: bool, //0x0001
+    rotatable: bool,             //0x0002
+    translatable: bool,          //0x0004
+    visible: bool,               //0x0008
+    operable: bool,              //0x0010
+    ik: bool,                    //0x0020
+    inherit_local: bool,         //0x0080
+    inherit_rotation: bool,      //0x0100
+    inherit_translation: bool,   //0x0200
+    fixed_axis: bool,            //0x0400
+    local_axis: bool,            //0x0800
+    deform_after_physics: bool,  //0x1000
+    external_parent: bool,       //0x2000
 }
 impl PMXBoneFlags {
-    fn none() -> Self {
-        Self {
-            is_target_is_other_bone: false,
-            deform_after_physics: false,
-            allow_rotate: false,
-            allow_translate: false,
-            flag1: false,
+    ///フラグに合わせてボーンの付随データを作成/削除する。残るデータは元の値を引き継ぐ
+    fn apply(self, bone: &mut Bone) {
+        bone.connection_display_mode =
+            match (self.connect_to_other_bone, bone.connection_display_mode) {
+                (true, ConnectionDisplayMode::Offset(_)) => ConnectionDisplayMode::OtherBone(-1),
+                (false, ConnectionDisplayMode::OtherBone(_)) => {
+                    ConnectionDisplayMode::Offset([0.0; 3])
+                }
+                (_, mode) => mode,
+            };
+        bone.rotatable_in_viewer = self.rotatable;
+        bone.translatable_in_viewer = self.translatable;
+        bone.display_bone_in_viewer = self.visible;
+        bone.controllable_in_viewer = self.operable;
+        if !self.ik {
+            bone.ik_info = None;
+        } else if bone.ik_info.is_none() {
+            bone.ik_info = Some(BoneIKInfo {
+                ik_target_bone_index: -1,
+                ik_iter_count: 40,
+                ik_limit_angle: 2.0,
+                ik_links: vec![],
+            });
         }
+        bone.inherits.inherit_local = self.inherit_local;
+        let (parent, ratio) = match bone.inherits.rotate_and_translate {
+            RotateAndTranslateInherits::None => (-1, 1.0),
+            RotateAndTranslateInherits::Both(parent, ratio)
+            | RotateAndTranslateInherits::Rotate(parent, ratio)
+            | RotateAndTranslateInherits::Translate(parent, ratio) => (parent, ratio),
+        };
+        bone.inherits.rotate_and_translate = match (self.inherit_rotation, self.inherit_translation)
+        {
+            (true, true) => RotateAndTranslateInherits::Both(parent, ratio),
+            (true, false) => RotateAndTranslateInherits::Rotate(parent, ratio),
+            (false, true) => RotateAndTranslateInherits::Translate(parent, ratio),
+            (false, false) => RotateAndTranslateInherits::None,
+        };
+        bone.fixed_axis = if self.fixed_axis {
+            Some(bone.fixed_axis.unwrap_or([1.0, 0.0, 0.0]))
+        } else {
+            None
+        };
+        bone.local_axis = if self.local_axis {
+            Some(
+                bone.local_axis
+                    .unwrap_or(([1.0, 0.0, 0.0], [0.0, 0.0, 1.0])),
+            )
+        } else {
+            None
+        };
+        bone.physics_after_deform = self.deform_after_physics;
+        bone.external_parent = if self.external_parent {
+            Some(bone.external_parent.unwrap_or(0))
+        } else {
+            None
+        };
     }
 }
 impl From<BoneFlags> for PMXBoneFlags {
     fn from(flags: BoneFlags) -> Self {
         Self {
-            is_target_is_other_bone: flags.contains(BoneFlags::CONNECT_TO_OTHER_BONE),
+            connect_to_other_bone: flags.contains(BoneFlags::CONNECT_TO_OTHER_BONE),
+            rotatable: flags.contains(BoneFlags::ROTATABLE),
+            translatable: flags.contains(BoneFlags::TRANSLATABLE),
+            visible: flags.contains(BoneFlags::IS_VISIBLE),
+            operable: flags.contains(BoneFlags::ENABLED),
+            ik: flags.contains(BoneFlags::IK),
+            inherit_local: flags.contains(BoneFlags::INHERIT_LOCAL),
+            inherit_rotation: flags.contains(BoneFlags::INHERIT_ROTATION),
+            inherit_translation: flags.contains(BoneFlags::INHERIT_TRANSLATION),
+            fixed_axis: flags.contains(BoneFlags::FIXED_AXIS),
+            local_axis: flags.contains(BoneFlags::LOCAL_COORDINATE),
             deform_after_physics: flags.contains(BoneFlags::PHYSICS_AFTER_DEFORM),
-            allow_rotate: flags.contains(BoneFlags::ROTATABLE),
-            allow_translate: flags.contains(BoneFlags::TRANSLATABLE),
-            flag1: false,
+            external_parent: flags.contains(BoneFlags::EXTERNAL_PARENT_DEFORM),
         }
     }
 }
-
+impl From<PMXBoneFlags> for BoneFlags {
+    fn from(flags: PMXBoneFlags) -> Self {
+        let mut bits = BoneFlags::empty();
+        bits.set(
+            BoneFlags::CONNECT_TO_OTHER_BONE,
+            flags.connect_to_other_bone,
+        );
+        bits.set(BoneFlags::ROTATABLE, flags.rotatable);
+        bits.set(BoneFlags::TRANSLATABLE, flags.translatable);
+        bits.set(BoneFlags::IS_VISIBLE, flags.visible);
+        bits.set(BoneFlags::ENABLED, flags.operable);
+        bits.set(BoneFlags::IK, flags.ik);
+        bits.set(BoneFlags::INHERIT_LOCAL, flags.inherit_local);
+        bits.set(BoneFlags::INHERIT_ROTATION, flags.inherit_rotation);
+        bits.set(BoneFlags::INHERIT_TRANSLATION, flags.inherit_translation);
+        bits.set(BoneFlags::FIXED_AXIS, flags.fixed_axis);
+        bits.set(BoneFlags::LOCAL_COORDINATE, flags.local_axis);
+        bits.set(BoneFlags::PHYSICS_AFTER_DEFORM, flags.deform_after_physics);
+        bits.set(BoneFlags::EXTERNAL_PARENT_DEFORM, flags.external_parent);
+        bits
+    }
+}
+impl From<&Bone> for PMXBoneFlags {
+    fn from(bone: &Bone) -> Self {
+        bone.calculate_bone_flag().into()
+    }
+}
+impl From<u16> for PMXBoneFlags {
+    fn from(bits: u16) -> Self {
+        BoneFlags::from_bits_truncate(bits).into()
+    }
+}
 impl From<PMXBoneFlags> for u16 {
-    fn from(_: PMXBoneFlags) -> Self {
-        0
+    fn from(flags: PMXBoneFlags) -> Self {
+        BoneFlags::from(flags).bits()
     }
 }
 
 pub struct PMXInfoView {
-    pub(crate) header: Header,
-    pub(crate) model_info: ModelInfo,
     pub(crate) encode: Encode,
     pub(crate) lang: Lang,
-    additonal_uvs_changed: bool,
+    ///取り消し履歴へまだ渡していない編集
+    steps: Vec<Step>,
+    ///データが失われるので確認待ちの追加UVの数
+    pending_uv: Option<(u8, UvDiscard)>,
+    index_sizes: IndexSizes,
+    ///編集でインデックスサイズが変わった種類。閉じるまで表示する
+    size_warnings: Vec<String>,
+    save_path: String,
+    ///保存先が既にあるか問題のある文字列があるので、書き込む前の確認待ち
+    confirm_save: bool,
+    save_status: String,
+    ///最後に確認したときの問題のある文字列
+    text_issues: Vec<TextIssue>,
 }
 impl PMXInfoView {
-    pub fn new(header: Header, model_info: ModelInfo) -> Self {
-        let encode = header.encode.into();
+    ///保存先の既定値は読み込んだファイル。なければモデル名から決める
+    pub fn new(document: &PmxDocument, source: Option<PathBuf>) -> Self {
         Self {
-            header,
-            model_info,
-            encode,
+            encode: document.header.encode.into(),
             lang: Lang::Japanese,
-            additonal_uvs_changed: false,
+            steps: vec![],
+            pending_uv: None,
+            index_sizes: document.index_sizes(),
+            size_warnings: vec![],
+            save_path: source.map_or_else(
+                || default_file_name(&document.model_info.name),
+                |source| source.to_string_lossy().into_owned(),
+            ),
+            confirm_save: false,
+            save_status: String::new(),
+            text_issues: vec![],
+        }
+    }
+    ///要素数が変わっていればインデックスサイズを計算し直す
+    pub fn document_changed(&mut self, document: &PmxDocument, changes: DocumentChanges) {
+        if changes.is_empty() {
+            return;
         }
+        let sizes = document.index_sizes();
+        self.size_warnings.extend(sizes.warnings(&self.index_sizes));
+        self.index_sizes = sizes;
     }
-    pub(crate) fn display(&mut self, ui: &mut egui::Ui) {
+    ///インデックスサイズが変わった警告。どのタブを開いていても出す
+    pub fn display_size_warnings(&mut self, ctx: &egui::Context) {
+        if self.size_warnings.is_empty() {
+            return;
+        }
+        let warnings = &mut self.size_warnings;
+        egui::Window::new("インデックスサイズの変更").show(ctx, |ui| {
+            for warning in warnings.iter() {
+                ui.label(warning);
+            }
+            if ui.button("閉じる").clicked() {
+                warnings.clear();
+            }
+        });
+    }
+    ///保存時にヘッダーへ書くインデックスサイズ。要素数から自動で選ぶので表示のみ
+    fn display_index_sizes(&self, ui: &mut egui::Ui) {
+        ui.collapsing("インデックスサイズ", |ui| {
+            egui::Grid::new("index sizes").show(ui, |ui| {
+                for (kind, count, size) in self.index_sizes.iter() {
+                    ui.label(kind.label());
+                    ui.label(count.to_string());
+                    ui.label(format!("{}バイト", size.bytes()));
+                    ui.end_row();
+                }
+            });
+        });
+    }
+    ///書き込む前に確かめた`text_issues`の数を結果に添える
+    fn save(&mut self, document: &PmxDocument) {
+        self.save_status = match document.save(&self.save_path, self.encode.into()) {
+            Ok(()) if self.text_issues.is_empty() => format!("saved {}", self.save_path),
+            Ok(()) => format!(
+                "saved {} (問題のある文字列: {})",
+                self.save_path,
+                self.text_issues.len()
+            ),
+            Err(err) => format!("failed to save PMX: {:?}", err),
+        };
+    }
+    ///文字コードは保存するときに選び、すべての文字列をその文字コードで書き直す
+    fn display_save(&mut self, ui: &mut egui::Ui, document: &PmxDocument) {
+        ui.horizontal(|ui| {
+            ui.label("PMX");
+            if ui.text_edit_singleline(&mut self.save_path).changed() {
+                self.confirm_save = false;
+            }
+            egui::ComboBox::from_id_source("save encoding")
+                .selected_text(self.encode.to_string())
+                .show_ui(ui, |ui| {
+                    for encode in [Encode::UTF16LE, Encode::UTF8] {
+                        ui.selectable_value(&mut self.encode, encode, encode.to_string());
+                    }
+                });
+            if ui.button("文字列を確認").clicked() {
+                self.text_issues = check_document(document);
+                self.save_status = format!("問題のある文字列: {}", self.text_issues.len());
+            }
+            if ui.button("保存").clicked() {
+                //書き込む前に確かめ、問題があれば確認してから保存する
+                self.text_issues = check_document(document);
+                if Path::new(&self.save_path).exists() || !self.text_issues.is_empty() {
+                    self.confirm_save = true;
+                } else {
+                    self.save(document);
+                }
+            }
+        });
+        if self.confirm_save {
+            if Path::new(&self.save_path).exists() {
+                ui.label(format!("{}は既にあります。上書きします", self.save_path));
+            }
+            if !self.text_issues.is_empty() {
+                ui.label(format!(
+                    "問題のある文字列が{}個あります。下の一覧を確認してください",
+                    self.text_issues.len()
+                ));
+            }
+            ui.horizontal(|ui| {
+                if ui.button("保存する").clicked() {
+                    self.confirm_save = false;
+                    self.save(document);
+                }
+                if ui.button("キャンセル").clicked() {
+                    self.confirm_save = false;
+                }
+            });
+        }
+        ui.label(&self.save_status);
+        if !self.text_issues.is_empty() {
+            ui.collapsing(
+                format!("問題のある文字列 ({})", self.text_issues.len()),
+                |ui| {
+                    egui::ScrollArea::vertical()
+                        .max_height(200.0)
+                        .show(ui, |ui| {
+                            for issue in &self.text_issues {
+                                ui.label(format!(
+                                    "{}「{}」: {}",
+                                    issue.location, issue.text, issue.problem
+                                ));
+                            }
+                        });
+                },
+            );
+        }
+    }
+    fn uv_state(document: &PmxDocument) -> UvState {
+        UvState {
+            additional_uv: document.header.additional_uv,
+            morphs: document.morphs.clone(),
+            frames: document.frames.clone(),
+        }
+    }
+    ///取り消し履歴へ積む編集を取り出す
+    pub fn query_steps(&mut self) -> Vec<Step> {
+        std::mem::take(&mut self.steps)
+    }
+    ///取り消しややり直しでモデル情報を戻す
+    pub fn restore(&mut self, document: &mut PmxDocument, model_info: &ModelInfo) {
+        if document.model_info != *model_info {
+            document.model_info = model_info.clone();
+            document.notify(DocumentChanges::MODEL_INFO);
+        }
+    }
+    pub fn restore_uv(&mut self, document: &mut PmxDocument, state: &UvState) {
+        document.header.additional_uv = state.additional_uv;
+        document.morphs = state.morphs.clone();
+        document.frames = state.frames.clone();
+        self.pending_uv = None;
+        document.notify(DocumentChanges::ADDITIONAL_UV);
+    }
+    ///追加UVの数を変え、頂点とUVモーフを組み替える
+    fn set_additional_uv(&mut self, document: &mut PmxDocument, count: u8) {
+        let before = Self::uv_state(document);
+        let vertices = document.vertices.clone();
+        resize_additional_uv(
+            &mut document.vertices,
+            &mut document.morphs,
+            &mut document.frames,
+            before.additional_uv,
+            count,
+        );
+        document.header.additional_uv = count;
+        document.notify(DocumentChanges::ADDITIONAL_UV);
+        let mut step = Step::new(
+            "追加UV数の変更",
+            Change::AdditionalUv {
+                before: Box::new(before),
+                after: Box::new(Self::uv_state(document)),
+            },
+        );
+        if let Some(change) = Change::vertices(&vertices, &document.vertices) {
+            step = step.with(change);
+        }
+        self.steps.push(step);
+    }
+    ///失われるデータの確認
+    fn display_uv_confirmation(&mut self, ui: &mut egui::Ui, document: &mut PmxDocument) {
+        let (count, discard) = match self.pending_uv.clone() {
+            Some(pending) => pending,
+            None => return,
+        };
+        ui.label(format!(
+            "追加UVを{}個にすると、{}頂点の追加UV{}以降の値とUVモーフ{}個が失われます",
+            count,
+            discard.vertices,
+            count + 1,
+            discard.morphs.len()
+        ));
+        if !discard.morphs.is_empty() {
+            ui.label(egui::RichText::new(discard.morphs.join(", ")).weak());
+        }
+        ui.horizontal(|ui| {
+            if ui.button("変更する").clicked() {
+                self.pending_uv = None;
+                self.set_additional_uv(document, count);
+            }
+            if ui.button("キャンセル").clicked() {
+                self.pending_uv = None;
+            }
+        });
+    }
+    pub(crate) fn display(&mut self, ui: &mut egui::Ui, document: &mut PmxDocument) {
+        let before = document.model_info.clone();
+        let mut additional_uv = document.header.additional_uv;
         egui::Frame::none().show(ui, |ui| {
             ui.vertical(|ui| {
                 ui.label("System");
                 egui::Frame::none().show(ui, |ui| {
                     ui.horizontal(|ui| {
-                        ui.label(format!("PMX Version : {:?}", self.header.version));
-                        ui.label("character encoding :");
-                        egui::ComboBox::from_label("")
-                            .selected_text(self.encode.to_string())
-                            .show_ui(ui, |ui| {
-                                ui.selectable_value(
-                                    &mut self.encode,
-                                    Encode::UTF16LE,
-                                    Encode::UTF16LE.to_string(),
-                                );
-                                ui.selectable_value(
-                                    &mut self.encode,
-                                    Encode::UTF8,
-                                    Encode::UTF8.to_string(),
-                                );
-                            });
+                        ui.label(format!("PMX Version : {:?}", document.header.version));
+                        ui.label(format!(
+                            "character encoding : {}",
+                            Encode::from(document.header.encode).to_string()
+                        ));
                         ui.label("additional uvs");
-                        let saved_addtional_uvs = self.header.additional_uv;
                         egui::ComboBox::from_label("uvs")
-                            .selected_text(self.header.additional_uv.to_string())
+                            .selected_text(additional_uv.to_string())
                             .show_ui(ui, |ui| {
-                                ui.selectable_value(&mut self.header.additional_uv, 0, "0");
-                                ui.selectable_value(&mut self.header.additional_uv, 1, "1");
-                                ui.selectable_value(&mut self.header.additional_uv, 2, "2");
-                                ui.selectable_value(&mut self.header.additional_uv, 3, "3");
-                                ui.selectable_value(&mut self.header.additional_uv, 4, "4");
+                                for count in 0..=4 {
+                                    ui.selectable_value(
+                                        &mut additional_uv,
+                                        count,
+                                        count.to_string(),
+                                    );
+                                }
                             });
-                        if saved_addtional_uvs != self.header.additional_uv {
-                            self.additonal_uvs_changed = true;
-                        }
                     });
                 });
 
@@ -274,8 +1512,8 @@ impl PMXInfoView {
                         ui.horizontal(|ui| {
                             ui.label("model name");
                             let name = match self.lang {
-                                Lang::English => &mut self.model_info.name_en,
-                                Lang::Japanese => &mut self.model_info.name,
+                                Lang::English => &mut document.model_info.name_en,
+                                Lang::Japanese => &mut document.model_info.name,
                             };
                             ui.text_edit_singleline(name);
                             ui.selectable_value(&mut self.lang, Lang::Japanese, "日");
@@ -283,21 +1521,34 @@ impl PMXInfoView {
                         });
                         ui.label("comment");
                         let comment = match self.lang {
-                            Lang::English => &mut self.model_info.comment_en,
-                            Lang::Japanese => &mut self.model_info.comment,
+                            Lang::English => &mut document.model_info.comment_en,
+                            Lang::Japanese => &mut document.model_info.comment,
                         };
                         ui.text_edit_multiline(comment);
                     })
                 });
             });
         });
-    }
-    pub fn query_updated_header(&mut self) -> Option<Header> {
-        if self.additonal_uvs_changed {
-            self.additonal_uvs_changed = false;
-            Some(self.header.clone())
-        } else {
-            None
+        if additional_uv != document.header.additional_uv {
+            let discard = discarded(&document.vertices, &document.morphs, additional_uv);
+            if discard.is_empty() {
+                self.pending_uv = None;
+                self.set_additional_uv(document, additional_uv);
+            } else {
+                self.pending_uv = Some((additional_uv, discard));
+            }
+        }
+        self.display_uv_confirmation(ui, document);
+        self.display_index_sizes(ui);
+        self.display_save(ui, document);
+        if before != document.model_info {
+            document.notify(DocumentChanges::MODEL_INFO);
+            let change = Change::Info {
+                before: Box::new(before),
+                after: Box::new(document.model_info.clone()),
+            };
+            self.steps
+                .push(Step::new("モデル情報の編集", change).merging("info"));
         }
     }
 }
@@ -314,6 +1565,14 @@ impl From<PMXUtil::types::Encode> for Encode {
         }
     }
 }
+impl From<Encode> for PMXUtil::types::Encode {
+    fn from(encode: Encode) -> Self {
+        match encode {
+            Encode::UTF8 => Self::UTF8,
+            Encode::UTF16LE => Self::Utf16Le,
+        }
+    }
+}
 impl ToString for Encode {
     fn to_string(&self) -> String {
         match self {
@@ -322,72 +1581,272 @@ impl ToString for Encode {
         }
     }
 }
+///頂点の選択をどう変えるか
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum SelectionMode {
+    Replace,
+    Add,
+    Remove,
+}
+impl SelectionMode {
+    ///Shiftで追加、Ctrlで除外
+    pub fn from_modifiers(modifiers: egui::Modifiers) -> Self {
+        if modifiers.shift {
+            SelectionMode::Add
+        } else if modifiers.ctrl {
+            SelectionMode::Remove
+        } else {
+            SelectionMode::Replace
+        }
+    }
+}
 pub struct PMXVertexView {
-    vertices: Vec<Vertex>,
+    ///編集対象の頂点
     selected: usize,
+    ///選択中の頂点。`selected`も含む
+    pub(crate) selection: BTreeSet<usize>,
+    ///次の描画で一覧を`selected`までスクロールする
+    scroll_to_selected: bool,
     display_sdef_parameter: bool,
     update_vertices: bool,
-    header: Header,
-    bones: Vec<Bone>,
     selected_uv: u8,
     lang: Lang,
+    ///ウェイトのボーン名から選ばれた、ボーンビューで表示するボーン
+    requested_bone: Option<i32>,
+    bulk_edit: BulkEditState,
+    ///最後に行ったウェイトチェックの結果
+    weight_report: Option<WeightReport>,
+    mirror: MirrorState,
+    ///取り消し履歴へまだ渡していない編集
+    steps: Vec<Step>,
+}
+///左右対称コピーの入力値
+struct MirrorState {
+    source: MirrorSource,
+    ///対になる頂点とみなす距離
+    tolerance: f32,
+    ///位置と法線も写す
+    geometry: bool,
+    status: String,
+}
+impl Default for MirrorState {
+    fn default() -> Self {
+        Self {
+            source: MirrorSource::PlusX,
+            tolerance: 0.001,
+            geometry: false,
+            status: String::new(),
+        }
+    }
+}
+///一括編集の入力値
+struct BulkEditState {
+    weight_kind: WeightKind,
+    bone: i32,
+    weight: f32,
+    replace_from: i32,
+    replace_to: i32,
+    offset: [f32; 3],
+    scale: [f32; 3],
+    edge_mag: f32,
+    ///コピーしたUVと追加UV
+    uv_clipboard: Option<([f32; 2], [[f32; 4]; 4])>,
+    status: String,
+}
+impl Default for BulkEditState {
+    fn default() -> Self {
+        Self {
+            weight_kind: WeightKind::BDEF2,
+            bone: 0,
+            weight: 1.0,
+            replace_from: 0,
+            replace_to: 0,
+            offset: [0.0; 3],
+            scale: [1.0; 3],
+            edge_mag: 1.0,
+            uv_clipboard: None,
+            status: String::new(),
+        }
+    }
 }
 impl PMXVertexView {
-    pub fn new(vertices: Vec<Vertex>, header: Header, bones: &[Bone]) -> Self {
+    pub fn new() -> Self {
         Self {
-            vertices,
             selected: 0,
+            selection: [0].iter().copied().collect(),
+            scroll_to_selected: false,
             display_sdef_parameter: false,
             update_vertices: true,
-            header,
-            bones: Vec::from(bones),
             selected_uv: 0,
             lang: Lang::Japanese,
+            requested_bone: None,
+            bulk_edit: BulkEditState::default(),
+            weight_report: None,
+            mirror: MirrorState::default(),
+            steps: vec![],
         }
     }
-    pub fn update_header(&mut self, header: Header) {
-        self.header = header;
+    ///`edit`で変わった頂点を1つの編集として記録する
+    pub fn edit_vertices<R>(
+        &mut self,
+        document: &mut PmxDocument,
+        label: &str,
+        merge_key: Option<&str>,
+        edit: impl FnOnce(&mut Self, &mut PmxDocument) -> R,
+    ) -> R {
+        let before = document.vertices.clone();
+        let result = edit(self, document);
+        if let Some(change) = Change::vertices(&before, &document.vertices) {
+            document.notify(DocumentChanges::VERTICES);
+            let step = Step::new(label, change);
+            self.steps.push(match merge_key {
+                Some(key) => step.merging(key),
+                None => step,
+            });
+        }
+        result
     }
-    pub fn update_bone(&mut self, bones: &[Bone]) {
-        self.bones = bones.to_vec();
+    ///取り消し履歴へ積む編集を取り出す
+    pub fn query_steps(&mut self) -> Vec<Step> {
+        std::mem::take(&mut self.steps)
     }
-    pub fn display(&mut self, ui: &mut egui::Ui) {
-        let text_style = egui::TextStyle::Small;
-        let row_height = ui.text_style_height(&text_style);
-        egui::SidePanel::left("Vertices")
-            .show_inside(ui, |ui| {
-                egui::ScrollArea::vertical()
-                    .max_height(ui.available_height() - 32.0)
-                    .stick_to_right()
-                    .show_rows(ui, row_height, self.vertices.len(), |ui, row_range| {
-                        for (index, vertices) in self
-                            .vertices
-                            .iter()
-                            .enumerate()
-                            .skip(row_range.start)
-                            .take(row_range.end)
-                        {
-                            if ui
-                                .add_sized(Vec2::new(200.0,20.0),egui::SelectableLabel::new(
-                                    self.selected == index,
-                                    egui::WidgetText::RichText(
-                                        egui::RichText::new(format!(
-                                            "{:06}: [{:.7},{:.7},{:.7}]",
-                                            index, vertices.position[0],vertices.position[1],vertices.position[2]
-                                        ))
-                                        .text_style(egui::TextStyle::Monospace),
-                                    ),
-                                ))
-                                .clicked()
-                            {
-                                self.selected = index;
-                            }
-                        }
-                    });
+    ///取り消しややり直しで頂点を戻す
+    pub fn restore_vertices(
+        &mut self,
+        document: &mut PmxDocument,
+        indices: &[usize],
+        vertices: &[Vertex],
+    ) {
+        for (&index, vertex) in indices.iter().zip(vertices) {
+            if let Some(current) = document.vertices.get_mut(index) {
+                *current = vertex.clone();
+            }
+        }
+        document.notify(DocumentChanges::VERTICES);
+        self.weight_report = None;
+    }
+    ///ビューポートなどで選んだ頂点を選択に反映し、一覧をその位置までスクロールする
+    pub fn select_vertices(
+        &mut self,
+        vertex_count: usize,
+        vertices: &[usize],
+        mode: SelectionMode,
+    ) {
+        match mode {
+            SelectionMode::Replace => {
+                self.selection = vertices.iter().copied().collect();
+            }
+            SelectionMode::Add => self.selection.extend(vertices.iter().copied()),
+            SelectionMode::Remove => {
+                for vertex in vertices {
+                    self.selection.remove(vertex);
+                }
+            }
+        }
+        self.selection.retain(|&vertex| vertex < vertex_count);
+        if let Some(&first) = vertices
+            .iter()
+            .find(|vertex| self.selection.contains(vertex))
+        {
+            self.selected = first;
+            self.scroll_to_selected = true;
+        } else if !self.selection.contains(&self.selected) {
+            if let Some(&first) = self.selection.iter().next() {
+                self.selected = first;
+            }
+        }
+    }
+    ///ボーン名がクリックされていれば、そのボーンのインデックスを取り出す
+    pub fn query_requested_bone(&mut self) -> Option<i32> {
+        self.requested_bone.take()
+    }
+    ///一覧でクリックした頂点を選ぶ。Ctrlで切り替え、Shiftで範囲選択
+    fn click_vertex(&mut self, index: usize, modifiers: egui::Modifiers) {
+        if modifiers.ctrl {
+            if !self.selection.remove(&index) {
+                self.selection.insert(index);
+            }
+        } else if modifiers.shift {
+            let (from, to) = (self.selected.min(index), self.selected.max(index));
+            self.selection.extend(from..=to);
+        } else {
+            self.selection.clear();
+            self.selection.insert(index);
+        }
+        self.selected = index;
+    }
+    pub fn display(&mut self, ui: &mut egui::Ui, document: &mut PmxDocument) {
+        let row_height = 20.0;
+        egui::SidePanel::left("Vertices").show_inside(ui, |ui| {
+            ui.label(format!("選択中の頂点: {}", self.selection.len()));
+            let mut scroll_area = egui::ScrollArea::vertical()
+                .max_height(ui.available_height() - 32.0)
+                .stick_to_right();
+            if std::mem::take(&mut self.scroll_to_selected) {
+                let row_height = row_height + ui.spacing().item_spacing.y;
+                scroll_area = scroll_area.vertical_scroll_offset(self.selected as f32 * row_height);
+            }
+            let mut clicked = None;
+            scroll_area.show_rows(ui, row_height, document.vertices.len(), |ui, row_range| {
+                for (index, vertices) in document
+                    .vertices
+                    .iter()
+                    .enumerate()
+                    .skip(row_range.start)
+                    .take(row_range.len())
+                {
+                    let label = egui::SelectableLabel::new(
+                        self.selection.contains(&index),
+                        egui::WidgetText::RichText(
+                            egui::RichText::new(format!(
+                                "{:06}: [{:.7},{:.7},{:.7}]",
+                                index,
+                                vertices.position[0],
+                                vertices.position[1],
+                                vertices.position[2]
+                            ))
+                            .text_style(egui::TextStyle::Monospace),
+                        ),
+                    );
+                    if ui.add_sized(Vec2::new(200.0, row_height), label).clicked() {
+                        clicked = Some(index);
+                    }
+                }
             });
-        let mut cloned_vertex = self.vertices[self.selected].clone();
+            if let Some(index) = clicked {
+                self.click_vertex(index, ui.input().modifiers);
+            }
+        });
+        egui::TopBottomPanel::bottom("Vertex bulk edit").show_inside(ui, |ui| {
+            let title = format!("一括編集 ({}頂点)", self.selection.len());
+            ui.collapsing(title, |ui| {
+                if let Some(edit) = self.display_bulk_edit(ui, document) {
+                    let result =
+                        self.edit_vertices(document, "一括編集", None, |view, document| {
+                            apply_edit(
+                                &mut document.vertices,
+                                &view.selection,
+                                &document.bones,
+                                &edit,
+                            )
+                        });
+                    self.bulk_edit.status = match result {
+                        Ok(()) => format!("{}頂点に適用しました", self.selection.len()),
+                        Err(error) => error.to_string(),
+                    };
+                }
+            });
+            ui.collapsing("ウェイトチェック", |ui| {
+                self.display_weight_check(ui, document)
+            });
+            ui.collapsing("左右対称", |ui| self.display_mirror(ui, document));
+        });
+        let mut cloned_vertex = document.vertices[self.selected].clone();
+        let original_vertex = cloned_vertex.clone();
+        let bone_count = document.bones.len();
         let mut weight_kind: WeightKind = cloned_vertex.weight_type.into();
         let mut weight_parameters: WeightParameters = cloned_vertex.weight_type.into();
+        let mut requested_bone = None;
         egui::CentralPanel::default().show_inside(ui, |ui| {
             ui.vertical(|ui| {
                 ui.horizontal(|ui| {
@@ -427,33 +1886,40 @@ impl PMXVertexView {
                         ui.add(egui::DragValue::new(&mut cloned_vertex.uv[1]));
                         ui.add(egui::Label::new("※追加UVの有効数設定はInfoから設定"));
                     });
-                    ui.horizontal(|ui| {
-                        egui::ComboBox::from_id_source("AdditionalUV select").show_ui(ui, |ui| {
-                            for i in 0..self.header.additional_uv {
-                                ui.selectable_value(
-                                    &mut self.selected_uv,
-                                    i,
-                                    format!("Addtional UV {}", i),
-                                );
-                            }
+                    //追加UVの数を減らしたときは範囲内へ戻す
+                    let additional_uv = document.header.additional_uv;
+                    self.selected_uv = self.selected_uv.min(additional_uv.saturating_sub(1));
+                    if additional_uv > 0 {
+                        ui.horizontal(|ui| {
+                            egui::ComboBox::from_id_source("AdditionalUV select")
+                                .selected_text(format!("Addtional UV {}", self.selected_uv))
+                                .show_ui(ui, |ui| {
+                                    for i in 0..additional_uv {
+                                        ui.selectable_value(
+                                            &mut self.selected_uv,
+                                            i,
+                                            format!("Addtional UV {}", i),
+                                        );
+                                    }
+                                });
+                            ui.label("x");
+                            ui.add(egui::DragValue::new(
+                                &mut cloned_vertex.add_uv[self.selected_uv as usize][0],
+                            ));
+                            ui.label("y");
+                            ui.add(egui::DragValue::new(
+                                &mut cloned_vertex.add_uv[self.selected_uv as usize][1],
+                            ));
+                            ui.label("z");
+                            ui.add(egui::DragValue::new(
+                                &mut cloned_vertex.add_uv[self.selected_uv as usize][2],
+                            ));
+                            ui.label("w");
+                            ui.add(egui::DragValue::new(
+                                &mut cloned_vertex.add_uv[self.selected_uv as usize][3],
+                            ));
                         });
-                        ui.label("x");
-                        ui.add(egui::DragValue::new(
-                            &mut cloned_vertex.add_uv[self.selected_uv as usize][0],
-                        ));
-                        ui.label("y");
-                        ui.add(egui::DragValue::new(
-                            &mut cloned_vertex.add_uv[self.selected_uv as usize][1],
-                        ));
-                        ui.label("z");
-                        ui.add(egui::DragValue::new(
-                            &mut cloned_vertex.add_uv[self.selected_uv as usize][2],
-                        ));
-                        ui.label("w");
-                        ui.add(egui::DragValue::new(
-                            &mut cloned_vertex.add_uv[self.selected_uv as usize][3],
-                        ));
-                    });
+                    }
                 });
                 //bone weight
                 ui.vertical(|ui| {
@@ -494,7 +1960,7 @@ impl PMXVertexView {
                             ui.add(egui::DragValue::new(&mut weight_parameters.weights[3]));
                         });
                         let fetch_bone_name = |index: i32| -> &str {
-                            match self.bones.get(index as usize) {
+                            match document.bones.get(index as usize) {
                                 None => "-",
                                 Some(bone) => match self.lang {
                                     Lang::English => &bone.english_name,
@@ -503,52 +1969,326 @@ impl PMXVertexView {
                             }
                         };
                         ui.vertical(|ui| {
-                            ui.label(fetch_bone_name(weight_parameters.bone_indices[0]));
-                            ui.label(fetch_bone_name(weight_parameters.bone_indices[1]));
-                            ui.label(fetch_bone_name(weight_parameters.bone_indices[2]));
-                            ui.label(fetch_bone_name(weight_parameters.bone_indices[3]));
+                            for &index in &weight_parameters.bone_indices {
+                                if ui
+                                    .add(
+                                        egui::Label::new(fetch_bone_name(index))
+                                            .sense(egui::Sense::click()),
+                                    )
+                                    .clicked()
+                                    && index >= 0
+                                {
+                                    requested_bone = Some(index);
+                                }
+                            }
                         })
                     });
+                    for issue in check_vertex(&original_vertex, bone_count) {
+                        ui.colored_label(egui::Color32::RED, issue.to_string());
+                    }
                 })
             });
         });
+        if requested_bone.is_some() {
+            self.requested_bone = requested_bone;
+        }
+        //変形方式の変更はウェイトを保って変換し、それ以外は入力した値をそのまま書き戻す
+        if weight_kind != WeightKind::from(original_vertex.weight_type) {
+            convert_weight(&mut cloned_vertex, weight_kind, &document.bones);
+        } else if weight_parameters != WeightParameters::from(original_vertex.weight_type) {
+            weight_parameters.apply(&mut cloned_vertex.weight_type);
+        }
+        if cloned_vertex != original_vertex {
+            document.vertices[self.selected] = cloned_vertex.clone();
+            document.notify(DocumentChanges::VERTICES);
+            let change = Change::Vertices {
+                indices: vec![self.selected],
+                before: vec![original_vertex],
+                after: vec![cloned_vertex],
+            };
+            let label = format!("頂点{}の編集", self.selected);
+            self.steps
+                .push(Step::new(label, change).merging(format!("vertex {}", self.selected)));
+        }
     }
-}
-#[derive(Debug, Copy, Clone, Eq, PartialEq)]
-enum WeightKind {
-    BDEF1,
-    BDEF2,
-    BDEF4,
-    Sdef,
-    Qdef,
-}
-impl ToString for WeightKind {
-    fn to_string(&self) -> String {
-        match self {
-            WeightKind::BDEF1 => "BDEF1",
-            WeightKind::BDEF2 => "BDEF2",
-            WeightKind::BDEF4 => "BDEF4",
-            WeightKind::Sdef => "SDEF",
-            WeightKind::Qdef => "QDEF",
+    ///問題のある頂点を種類ごとに数え、選択や修正をする
+    fn display_weight_check(&mut self, ui: &mut egui::Ui, document: &mut PmxDocument) {
+        let bone_count = document.bones.len();
+        if ui.button("チェック").clicked() {
+            self.weight_report = Some(check_weights(&document.vertices, bone_count));
+        }
+        let report = match &self.weight_report {
+            Some(report) => report,
+            None => return,
+        };
+        if report.is_empty() {
+            ui.label("問題は見つかりませんでした");
+            return;
+        }
+        let mut select = None;
+        let mut fix = None;
+        egui::Grid::new("weight check").show(ui, |ui| {
+            for (issue, vertices) in &report.vertices {
+                if vertices.is_empty() {
+                    continue;
+                }
+                ui.colored_label(egui::Color32::RED, issue.to_string());
+                ui.label(format!("{}頂点", vertices.len()));
+                if ui.button("選択").clicked() {
+                    select = Some(vertices.clone());
+                }
+                if ui.button("修正").clicked() {
+                    fix = Some((*issue, vertices.clone()));
+                }
+                ui.end_row();
+            }
+        });
+        if let Some(vertices) = select {
+            self.select_vertices(document.vertices.len(), &vertices, SelectionMode::Replace);
+        }
+        if let Some((issue, vertices)) = fix {
+            self.edit_vertices(document, "ウェイト修正", None, |_, document| {
+                fix_weights(&mut document.vertices, bone_count, issue, &vertices)
+            });
+            self.weight_report = Some(check_weights(&document.vertices, bone_count));
         }
-        .to_string()
     }
-}
-impl From<VertexWeight> for WeightKind {
-    fn from(weight: VertexWeight) -> Self {
-        match weight {
-            VertexWeight::BDEF1(_) => WeightKind::BDEF1,
-            VertexWeight::BDEF2 { .. } => WeightKind::BDEF2,
-            VertexWeight::BDEF4 { .. } => WeightKind::BDEF4,
-            VertexWeight::SDEF { .. } => WeightKind::Sdef,
-            VertexWeight::QDEF { .. } => WeightKind::Qdef,
+    ///X=0を挟んだ対の頂点へウェイトを写す。複数選択していれば選択中の頂点だけを写す
+    fn display_mirror(&mut self, ui: &mut egui::Ui, document: &mut PmxDocument) {
+        let mirror = &mut self.mirror;
+        let mut run = false;
+        ui.horizontal(|ui| {
+            egui::ComboBox::from_id_source("mirror source")
+                .selected_text(mirror.source.label())
+                .show_ui(ui, |ui| {
+                    for source in MirrorSource::ALL {
+                        ui.selectable_value(&mut mirror.source, source, source.label());
+                    }
+                });
+            ui.label("許容距離");
+            ui.add(
+                egui::DragValue::new(&mut mirror.tolerance)
+                    .speed(0.0001)
+                    .clamp_range(0.0..=1.0),
+            );
+            ui.checkbox(&mut mirror.geometry, "位置と法線も写す");
+            run = ui.button("ウェイトを写す").clicked();
+        });
+        if run {
+            let pairs = symmetric_pairs(&document.vertices, self.mirror.tolerance);
+            let sources: Vec<usize> = if self.selection.len() > 1 {
+                self.selection.iter().copied().collect()
+            } else {
+                (0..document.vertices.len()).collect()
+            };
+            let unpaired = sources
+                .iter()
+                .filter(|&&index| pairs[index].is_none())
+                .count();
+            let (source, geometry) = (self.mirror.source, self.mirror.geometry);
+            let changed =
+                self.edit_vertices(document, "左右対称コピー", None, |_, document| {
+                    mirror_vertices(
+                        &mut document.vertices,
+                        &document.bones,
+                        &pairs,
+                        &sources,
+                        source,
+                        geometry,
+                    )
+                });
+            self.mirror.status = format!(
+                "{}頂点を更新しました (対が見つからない頂点{})",
+                changed, unpaired
+            );
         }
+        ui.label(&self.mirror.status);
+    }
+    ///選択中の頂点にまとめて適用する操作を入力する
+    fn display_bulk_edit(
+        &mut self,
+        ui: &mut egui::Ui,
+        document: &PmxDocument,
+    ) -> Option<VertexEdit> {
+        let count = self.selection.len().max(1) as f32;
+        let center = self
+            .selection
+            .iter()
+            .filter_map(|&index| document.vertices.get(index))
+            .fold([0.0; 3], |sum, vertex| {
+                add(sum, scale(vertex.position, 1.0 / count))
+            });
+        let active = document
+            .vertices
+            .get(self.selected)
+            .map(|vertex| (vertex.uv, vertex.add_uv));
+        let bones = &document.bones;
+        let lang = self.lang;
+        let bulk = &mut self.bulk_edit;
+        let max_bone = bones.len().saturating_sub(1) as i32;
+        let mut edit = None;
+        egui::Grid::new("vertex bulk edit").show(ui, |ui| {
+            ui.label("変形方式");
+            egui::ComboBox::from_id_source("bulk weight kind")
+                .selected_text(bulk.weight_kind.to_string())
+                .show_ui(ui, |ui| {
+                    for kind in WeightKind::ALL {
+                        ui.selectable_value(&mut bulk.weight_kind, kind, kind.to_string());
+                    }
+                });
+            if ui.button("適用").clicked() {
+                edit = Some(VertexEdit::SetWeightKind(bulk.weight_kind));
+            }
+            ui.end_row();
+
+            ui.label("ウェイト0の枠");
+            ui.label("除いて小さい方式にする");
+            if ui.button("詰める").clicked() {
+                edit = Some(VertexEdit::CollapseWeights);
+            }
+            ui.end_row();
+
+            ui.label("ボーン割り当て");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut bulk.bone).clamp_range(0..=max_bone));
+                ui.label(bone_name(bones, bulk.bone, lang));
+                ui.label("ウェイト");
+                ui.add(
+                    egui::DragValue::new(&mut bulk.weight)
+                        .speed(0.01)
+                        .clamp_range(0.0..=1.0),
+                );
+            });
+            if ui.button("割り当て").clicked() {
+                edit = Some(VertexEdit::AssignBone {
+                    bone: bulk.bone,
+                    weight: bulk.weight,
+                });
+            }
+            ui.end_row();
+
+            ui.label("ボーン置換");
+            ui.horizontal(|ui| {
+                ui.add(egui::DragValue::new(&mut bulk.replace_from).clamp_range(0..=max_bone));
+                ui.label(bone_name(bones, bulk.replace_from, lang));
+                ui.label("→");
+                ui.add(egui::DragValue::new(&mut bulk.replace_to).clamp_range(0..=max_bone));
+                ui.label(bone_name(bones, bulk.replace_to, lang));
+            });
+            if ui.button("置換").clicked() {
+                edit = Some(VertexEdit::ReplaceBone {
+                    from: bulk.replace_from,
+                    to: bulk.replace_to,
+                });
+            }
+            ui.end_row();
+
+            ui.label("ウェイト");
+            ui.label("合計を1にする");
+            if ui.button("正規化").clicked() {
+                edit = Some(VertexEdit::NormalizeWeights);
+            }
+            ui.end_row();
+
+            ui.label("移動");
+            ui.horizontal(|ui| drag_vec3(ui, &mut bulk.offset));
+            if ui.button("移動").clicked() {
+                edit = Some(VertexEdit::Translate(bulk.offset));
+            }
+            ui.end_row();
+
+            ui.label("拡大(選択の中心)");
+            ui.horizontal(|ui| drag_vec3(ui, &mut bulk.scale));
+            if ui.button("拡大").clicked() {
+                edit = Some(VertexEdit::Scale {
+                    center,
+                    factor: bulk.scale,
+                });
+            }
+            ui.end_row();
+
+            ui.label("エッジ倍率");
+            ui.add(egui::DragValue::new(&mut bulk.edge_mag).speed(0.01));
+            if ui.button("設定").clicked() {
+                edit = Some(VertexEdit::SetEdgeMagnifier(bulk.edge_mag));
+            }
+            ui.end_row();
+
+            ui.label("UV");
+            if ui.button("編集中の頂点からコピー").clicked() {
+                bulk.uv_clipboard = active;
+            }
+            let paste = ui.add_enabled(bulk.uv_clipboard.is_some(), egui::Button::new("貼り付け"));
+            if let (true, Some((uv, add_uv))) = (paste.clicked(), bulk.uv_clipboard) {
+                edit = Some(VertexEdit::PasteUv { uv, add_uv });
+            }
+            ui.end_row();
+        });
+        if !bulk.status.is_empty() {
+            ui.label(&bulk.status);
+        }
+        edit
     }
 }
+#[derive(Debug, Copy, Clone, PartialEq)]
 struct WeightParameters {
     weights: [f32; 4],
     bone_indices: [i32; 4],
 }
+impl WeightParameters {
+    ///変形方式は変えずに、その方式が持つ枠へ書き込む
+    fn apply(&self, weight: &mut VertexWeight) {
+        let [index_1, index_2, index_3, index_4] = self.bone_indices;
+        let [weight_1, weight_2, weight_3, weight_4] = self.weights;
+        match weight {
+            VertexWeight::BDEF1(bone_index) => *bone_index = index_1,
+            VertexWeight::BDEF2 {
+                bone_index_1,
+                bone_index_2,
+                bone_weight_1,
+            }
+            | VertexWeight::SDEF {
+                bone_index_1,
+                bone_index_2,
+                bone_weight_1,
+                ..
+            } => {
+                *bone_index_1 = index_1;
+                *bone_index_2 = index_2;
+                *bone_weight_1 = weight_1;
+            }
+            VertexWeight::BDEF4 {
+                bone_index_1,
+                bone_index_2,
+                bone_index_3,
+                bone_index_4,
+                bone_weight_1,
+                bone_weight_2,
+                bone_weight_3,
+                bone_weight_4,
+            }
+            | VertexWeight::QDEF {
+                bone_index_1,
+                bone_index_2,
+                bone_index_3,
+                bone_index_4,
+                bone_weight_1,
+                bone_weight_2,
+                bone_weight_3,
+                bone_weight_4,
+            } => {
+                *bone_index_1 = index_1;
+                *bone_index_2 = index_2;
+                *bone_index_3 = index_3;
+                *bone_index_4 = index_4;
+                *bone_weight_1 = weight_1;
+                *bone_weight_2 = weight_2;
+                *bone_weight_3 = weight_3;
+                *bone_weight_4 = weight_4;
+            }
+        }
+    }
+}
 impl From<VertexWeight> for WeightParameters {
     fn from(weight: VertexWeight) -> Self {
         match weight {
@@ -604,3 +2344,57 @@ impl From<VertexWeight> for WeightParameters {
         }
     }
 }
+
+#[test]
+fn test_bone_flags_round_trip() {
+    for bit in 0..16 {
+        let bits = BoneFlags::from_bits_truncate(1 << bit).bits();
+        assert_eq!(u16::from(PMXBoneFlags::from(bits)), bits);
+    }
+    //フラグを立てると付随データが作られ、ボーンから同じフラグが読める
+    let flags = PMXBoneFlags::from(0x3fbf);
+    let mut bone = Bone::default();
+    flags.apply(&mut bone);
+    assert_eq!(PMXBoneFlags::from(&bone), flags);
+    assert!(bone.ik_info.is_some() && bone.fixed_axis.is_some() && bone.local_axis.is_some());
+    PMXBoneFlags::default().apply(&mut bone);
+    assert_eq!(u16::from(PMXBoneFlags::from(&bone)), 0);
+    assert_eq!(
+        bone.connection_display_mode,
+        ConnectionDisplayMode::Offset([0.0; 3])
+    );
+}
+
+#[test]
+fn test_filter_bones() {
+    let bone = |name: &str, parent: i32| Bone {
+        name: name.to_string(),
+        parent,
+        display_bone_in_viewer: true,
+        ..Bone::default()
+    };
+    let mut bones = vec![
+        bone("センター", -1),
+        bone("上半身", 0),
+        bone("頭", 1),
+        bone("左足", 0),
+    ];
+    bones[3].english_name = "leg_L".to_string();
+    bones[2].display_bone_in_viewer = false;
+    let (tree, _) = BoneTree::from_bones(&bones);
+    let mut filter = TreeFilter {
+        search: "LEG".to_string(),
+        ..TreeFilter::default()
+    };
+    //英語名でも大文字小文字を区別せずに探し、祖先も表示する
+    let shown = filter_bones(&bones, &tree, &[], &[], &filter);
+    assert_eq!(shown, vec![true, false, false, true]);
+    filter.search.clear();
+    filter.invisible = true;
+    let shown = filter_bones(&bones, &tree, &[], &[], &filter);
+    assert_eq!(shown, vec![true, true, true, false]);
+    //条件はすべて満たす必要がある
+    filter.unused = true;
+    let shown = filter_bones(&bones, &tree, &[], &[false, false, true, false], &filter);
+    assert_eq!(shown, vec![false; 4]);
+}
@@ -0,0 +1,484 @@
+//! 選択した頂点へまとめて適用する編集
+
+use crate::math::{add, dot, scale, sub, Vec3};
+use crate::pose::weight_influences;
+use std::collections::BTreeSet;
+use PMXUtil::types::{Bone, Vertex, VertexWeight};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WeightKind {
+    BDEF1,
+    BDEF2,
+    BDEF4,
+    Sdef,
+    Qdef,
+}
+impl ToString for WeightKind {
+    fn to_string(&self) -> String {
+        match self {
+            WeightKind::BDEF1 => "BDEF1",
+            WeightKind::BDEF2 => "BDEF2",
+            WeightKind::BDEF4 => "BDEF4",
+            WeightKind::Sdef => "SDEF",
+            WeightKind::Qdef => "QDEF",
+        }
+        .to_string()
+    }
+}
+impl From<VertexWeight> for WeightKind {
+    fn from(weight: VertexWeight) -> Self {
+        match weight {
+            VertexWeight::BDEF1(_) => WeightKind::BDEF1,
+            VertexWeight::BDEF2 { .. } => WeightKind::BDEF2,
+            VertexWeight::BDEF4 { .. } => WeightKind::BDEF4,
+            VertexWeight::SDEF { .. } => WeightKind::Sdef,
+            VertexWeight::QDEF { .. } => WeightKind::Qdef,
+        }
+    }
+}
+impl WeightKind {
+    pub const ALL: [WeightKind; 5] = [
+        WeightKind::BDEF1,
+        WeightKind::BDEF2,
+        WeightKind::BDEF4,
+        WeightKind::Sdef,
+        WeightKind::Qdef,
+    ];
+    ///持てるボーンの数
+    pub fn capacity(self) -> usize {
+        match self {
+            WeightKind::BDEF1 => 1,
+            WeightKind::BDEF2 | WeightKind::Sdef => 2,
+            WeightKind::BDEF4 | WeightKind::Qdef => 4,
+        }
+    }
+    ///`count`本のボーンを持てるように変形方式を広げる
+    fn promote(self, count: usize) -> Self {
+        if count <= self.capacity() {
+            self
+        } else if self == WeightKind::BDEF1 && count <= 2 {
+            WeightKind::BDEF2
+        } else {
+            WeightKind::BDEF4
+        }
+    }
+    ///`count`本のボーンを持てる最小のBDEF
+    fn smallest(count: usize) -> Self {
+        match count {
+            0 | 1 => WeightKind::BDEF1,
+            2 => WeightKind::BDEF2,
+            _ => WeightKind::BDEF4,
+        }
+    }
+}
+
+///頂点をまとめて編集する操作
+#[derive(Debug, Clone, PartialEq)]
+pub enum VertexEdit {
+    SetWeightKind(WeightKind),
+    ///ウェイト0の枠を除き、BDEFを収まる最小の方式にする
+    CollapseWeights,
+    ///ボーンにウェイトを割り当て、残りを他のボーンへ元の比で配分する
+    AssignBone {
+        bone: i32,
+        weight: f32,
+    },
+    ReplaceBone {
+        from: i32,
+        to: i32,
+    },
+    NormalizeWeights,
+    Translate(Vec3),
+    ///`center`を中心に軸ごとに拡大する
+    Scale {
+        center: Vec3,
+        factor: Vec3,
+    },
+    SetEdgeMagnifier(f32),
+    ///UVと追加UVを貼り付ける
+    PasteUv {
+        uv: [f32; 2],
+        add_uv: [[f32; 4]; 4],
+    },
+}
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum VertexEditError {
+    EmptySelection,
+    InvalidVertex(usize),
+    InvalidBone(i32),
+}
+
+impl std::fmt::Display for VertexEditError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VertexEditError::EmptySelection => write!(f, "頂点が選択されていません"),
+            VertexEditError::InvalidVertex(index) => write!(f, "頂点{}がありません", index),
+            VertexEditError::InvalidBone(index) => write!(f, "ボーン{}がありません", index),
+        }
+    }
+}
+
+///選択した頂点すべてに編集を適用する
+///
+/// 先にすべて検証し、エラーがあればどの頂点も変更しない
+pub fn apply_edit(
+    vertices: &mut [Vertex],
+    selection: &BTreeSet<usize>,
+    bones: &[Bone],
+    edit: &VertexEdit,
+) -> Result<(), VertexEditError> {
+    if selection.is_empty() {
+        return Err(VertexEditError::EmptySelection);
+    }
+    if let Some(&index) = selection.iter().find(|&&index| index >= vertices.len()) {
+        return Err(VertexEditError::InvalidVertex(index));
+    }
+    let invalid_bone = |bone: i32| bone < 0 || bone as usize >= bones.len();
+    match *edit {
+        VertexEdit::AssignBone { bone, .. } if invalid_bone(bone) => {
+            return Err(VertexEditError::InvalidBone(bone))
+        }
+        VertexEdit::ReplaceBone { to, .. } if invalid_bone(to) => {
+            return Err(VertexEditError::InvalidBone(to))
+        }
+        _ => {}
+    }
+    for &index in selection {
+        edit_vertex(&mut vertices[index], bones, edit);
+    }
+    Ok(())
+}
+
+fn edit_vertex(vertex: &mut Vertex, bones: &[Bone], edit: &VertexEdit) {
+    let kind = WeightKind::from(vertex.weight_type);
+    match *edit {
+        VertexEdit::SetWeightKind(kind) => convert_weight(vertex, kind, bones),
+        VertexEdit::CollapseWeights => collapse_weights(vertex),
+        VertexEdit::AssignBone { bone, weight } => assign_weight(vertex, bone, weight),
+        VertexEdit::ReplaceBone { from, to } => {
+            let replaced = influences(&vertex.weight_type)
+                .into_iter()
+                .map(|(index, weight)| (if index == from { to } else { index }, weight))
+                .collect();
+            set_weights(vertex, kind, replaced);
+        }
+        VertexEdit::NormalizeWeights => {
+            let current = influences(&vertex.weight_type);
+            set_weights(vertex, kind, current);
+        }
+        VertexEdit::Translate(offset) => vertex.position = add(vertex.position, offset),
+        VertexEdit::Scale { center, factor } => {
+            let relative = sub(vertex.position, center);
+            for axis in 0..3 {
+                vertex.position[axis] = center[axis] + relative[axis] * factor[axis];
+            }
+        }
+        VertexEdit::SetEdgeMagnifier(edge_mag) => vertex.edge_mag = edge_mag,
+        VertexEdit::PasteUv { uv, add_uv } => {
+            vertex.uv = uv;
+            vertex.add_uv = add_uv;
+        }
+    }
+}
+
+///ボーンのウェイトを`weight`にし、残りを他のボーンへ元の比で配分する
+///
+/// ボーンの数が変形方式に収まらなければBDEF2、BDEF4へ広げる
+pub fn assign_weight(vertex: &mut Vertex, bone: i32, weight: f32) {
+    let weight = weight.clamp(0.0, 1.0);
+    let others: Vec<(i32, f32)> = influences(&vertex.weight_type)
+        .into_iter()
+        .filter(|&(index, _)| index != bone)
+        .collect();
+    let rest: f32 = others.iter().map(|&(_, weight)| weight).sum();
+    let mut assigned = vec![(bone, weight)];
+    if rest > f32::EPSILON {
+        assigned.extend(
+            others
+                .into_iter()
+                .map(|(index, other)| (index, other / rest * (1.0 - weight))),
+        );
+    }
+    let count = assigned.iter().filter(|&&(_, weight)| weight > 0.0).count();
+    let kind = WeightKind::from(vertex.weight_type).promote(count);
+    set_weights(vertex, kind, assigned);
+}
+///ボーンのウェイト。影響していなければ0
+pub fn bone_weight(weight: &VertexWeight, bone: i32) -> f32 {
+    influences(weight)
+        .into_iter()
+        .find(|&(index, _)| index == bone)
+        .map_or(0.0, |(_, weight)| weight)
+}
+///変形方式を変える
+///
+/// 収まらないボーンはウェイトの軽いものから外して正規化し直す。
+/// SDEFにするときはC/R0/R1を2本のボーンの位置から求める。ボーンが1本ならBDEF1にする
+pub fn convert_weight(vertex: &mut Vertex, kind: WeightKind, bones: &[Bone]) {
+    let was_sdef = WeightKind::from(vertex.weight_type) == WeightKind::Sdef;
+    let current = influences(&vertex.weight_type);
+    set_weights(vertex, kind, current);
+    if kind != WeightKind::Sdef || was_sdef {
+        return;
+    }
+    if let VertexWeight::SDEF {
+        bone_index_1,
+        bone_index_2,
+        sdef_c,
+        sdef_r0,
+        sdef_r1,
+        ..
+    } = &mut vertex.weight_type
+    {
+        let position = |index: i32| bones.get(index as usize).map(|bone| bone.position);
+        if let (Some(from), Some(to)) = (position(*bone_index_1), position(*bone_index_2)) {
+            let c = sdef_center(vertex.position, from, to);
+            *sdef_c = c;
+            *sdef_r0 = c;
+            *sdef_r1 = c;
+        }
+    }
+}
+///SDEFの回転中心C。頂点から2本のボーンを結ぶ線分へ下ろした足
+///
+/// R0とR1はCと同じにすると、初期姿勢での変形はBDEF2と一致する
+pub fn sdef_center(position: Vec3, from: Vec3, to: Vec3) -> Vec3 {
+    let axis = sub(to, from);
+    let length_sq = dot(axis, axis);
+    if length_sq <= f32::EPSILON {
+        return from;
+    }
+    let t = (dot(sub(position, from), axis) / length_sq).clamp(0.0, 1.0);
+    add(from, scale(axis, t))
+}
+///ウェイト0の枠を除いて、BDEFなら収まる最小の方式にする。SDEFとQDEFは方式を保つ
+///
+/// ボーンが1本だけになったSDEFはBDEF1にする
+pub fn collapse_weights(vertex: &mut Vertex) {
+    let current = influences(&vertex.weight_type);
+    let kind = match WeightKind::from(vertex.weight_type) {
+        WeightKind::Sdef if current.len() == 2 => WeightKind::Sdef,
+        WeightKind::Qdef => WeightKind::Qdef,
+        _ => WeightKind::smallest(current.len()),
+    };
+    set_weights(vertex, kind, current);
+}
+///重複したボーンをまとめ、ウェイトが0以下のものを除いて重い順に並べる
+pub fn influences(weight: &VertexWeight) -> Vec<(i32, f32)> {
+    let mut merged: Vec<(i32, f32)> = vec![];
+    for &(index, weight) in weight_influences(weight).iter() {
+        if index < 0 || weight <= 0.0 {
+            continue;
+        }
+        match merged.iter_mut().find(|(other, _)| *other == index) {
+            Some(entry) => entry.1 += weight,
+            None => merged.push((index, weight)),
+        }
+    }
+    merged.sort_by(|a, b| b.1.total_cmp(&a.1));
+    merged
+}
+
+///重い順に変形方式で持てる数だけ残し、合計が1になるように配分し直す
+///
+/// 有効なウェイトがなければ変更しない。SDEFは2本のボーンが必要なので、1本ならBDEF1にする
+pub fn set_weights(vertex: &mut Vertex, kind: WeightKind, influences: Vec<(i32, f32)>) {
+    let mut influences = influences;
+    influences.retain(|&(index, weight)| index >= 0 && weight > 0.0);
+    influences.sort_by(|a, b| b.1.total_cmp(&a.1));
+    influences.truncate(kind.capacity());
+    let kind = if kind == WeightKind::Sdef && influences.len() < 2 {
+        WeightKind::BDEF1
+    } else {
+        kind
+    };
+    let total: f32 = influences.iter().map(|&(_, weight)| weight).sum();
+    if total <= f32::EPSILON {
+        return;
+    }
+    let mut bones = [-1; 4];
+    let mut weights = [0.0; 4];
+    for (slot, &(index, weight)) in influences.iter().enumerate() {
+        bones[slot] = index;
+        weights[slot] = weight / total;
+    }
+    //2本目がなければ1本目と同じボーンにしておく
+    if bones[1] < 0 {
+        bones[1] = bones[0];
+    }
+    vertex.weight_type = match kind {
+        WeightKind::BDEF1 => VertexWeight::BDEF1(bones[0]),
+        WeightKind::BDEF2 => VertexWeight::BDEF2 {
+            bone_index_1: bones[0],
+            bone_index_2: bones[1],
+            bone_weight_1: weights[0],
+        },
+        WeightKind::Sdef => {
+            let (sdef_c, sdef_r0, sdef_r1) = match vertex.weight_type {
+                VertexWeight::SDEF {
+                    sdef_c,
+                    sdef_r0,
+                    sdef_r1,
+                    ..
+                } => (sdef_c, sdef_r0, sdef_r1),
+                _ => (vertex.position, vertex.position, vertex.position),
+            };
+            VertexWeight::SDEF {
+                bone_index_1: bones[0],
+                bone_index_2: bones[1],
+                bone_weight_1: weights[0],
+                sdef_c,
+                sdef_r0,
+                sdef_r1,
+            }
+        }
+        WeightKind::BDEF4 => VertexWeight::BDEF4 {
+            bone_index_1: bones[0],
+            bone_index_2: bones[1],
+            bone_index_3: bones[2],
+            bone_index_4: bones[3],
+            bone_weight_1: weights[0],
+            bone_weight_2: weights[1],
+            bone_weight_3: weights[2],
+            bone_weight_4: weights[3],
+        },
+        WeightKind::Qdef => VertexWeight::QDEF {
+            bone_index_1: bones[0],
+            bone_index_2: bones[1],
+            bone_index_3: bones[2],
+            bone_index_4: bones[3],
+            bone_weight_1: weights[0],
+            bone_weight_2: weights[1],
+            bone_weight_3: weights[2],
+            bone_weight_4: weights[3],
+        },
+    };
+}
+
+///テスト用の頂点。法線は+Y、UVと追加UVは0
+#[cfg(test)]
+pub(crate) fn test_vertex(position: Vec3, weight_type: VertexWeight) -> Vertex {
+    Vertex {
+        position,
+        norm: [0.0, 1.0, 0.0],
+        uv: [0.0; 2],
+        add_uv: [[0.0; 4]; 4],
+        weight_type,
+        edge_mag: 1.0,
+    }
+}
+
+#[test]
+fn test_apply_edit() {
+    let vertex = |weight_type: VertexWeight| test_vertex([0.0; 3], weight_type);
+    let mut vertices = vec![
+        vertex(VertexWeight::BDEF1(0)),
+        vertex(VertexWeight::BDEF4 {
+            bone_index_1: 0,
+            bone_index_2: 1,
+            bone_index_3: 1,
+            bone_index_4: -1,
+            bone_weight_1: 1.0,
+            bone_weight_2: 0.5,
+            bone_weight_3: 0.5,
+            bone_weight_4: 0.0,
+        }),
+    ];
+    let all: BTreeSet<usize> = [0, 1].iter().copied().collect();
+    let bones = vec![Bone::default(); 3];
+    //存在しないボーンならどの頂点も変わらない
+    let before = vertices.clone();
+    let edit = VertexEdit::AssignBone {
+        bone: 5,
+        weight: 0.5,
+    };
+    assert_eq!(
+        apply_edit(&mut vertices, &all, &bones, &edit),
+        Err(VertexEditError::InvalidBone(5))
+    );
+    assert_eq!(vertices, before);
+    //BDEF1に2本目を割り当てるとBDEF2になる
+    let edit = VertexEdit::AssignBone {
+        bone: 2,
+        weight: 0.25,
+    };
+    apply_edit(&mut vertices, &[0].iter().copied().collect(), &bones, &edit).unwrap();
+    assert_eq!(
+        vertices[0].weight_type,
+        VertexWeight::BDEF2 {
+            bone_index_1: 0,
+            bone_index_2: 2,
+            bone_weight_1: 0.75,
+        }
+    );
+    //重複したボーンをまとめて合計を1にする
+    apply_edit(&mut vertices, &all, &bones, &VertexEdit::NormalizeWeights).unwrap();
+    assert_eq!(
+        influences(&vertices[1].weight_type),
+        vec![(0, 0.5), (1, 0.5)]
+    );
+    apply_edit(
+        &mut vertices,
+        &all,
+        &bones,
+        &VertexEdit::Translate([1.0, 0.0, 0.0]),
+    )
+    .unwrap();
+    assert!(vertices
+        .iter()
+        .all(|vertex| vertex.position == [1.0, 0.0, 0.0]));
+}
+
+#[test]
+fn test_convert_weight() {
+    let bone = |position: Vec3| Bone {
+        position,
+        ..Bone::default()
+    };
+    let bones = vec![bone([0.0; 3]), bone([0.0, 2.0, 0.0]), bone([1.0, 0.0, 0.0])];
+    let mut vertex = test_vertex(
+        [1.0, 1.0, 0.0],
+        VertexWeight::BDEF4 {
+            bone_index_1: 0,
+            bone_index_2: 1,
+            bone_index_3: 2,
+            bone_index_4: -1,
+            bone_weight_1: 0.4,
+            bone_weight_2: 0.4,
+            bone_weight_3: 0.2,
+            bone_weight_4: 0.0,
+        },
+    );
+    //上位2本を残して正規化する
+    convert_weight(&mut vertex, WeightKind::BDEF2, &bones);
+    assert_eq!(
+        vertex.weight_type,
+        VertexWeight::BDEF2 {
+            bone_index_1: 0,
+            bone_index_2: 1,
+            bone_weight_1: 0.5,
+        }
+    );
+    //Cはボーンを結ぶ線分上の最も近い点
+    convert_weight(&mut vertex, WeightKind::Sdef, &bones);
+    match vertex.weight_type {
+        VertexWeight::SDEF {
+            sdef_c, sdef_r0, ..
+        } => assert_eq!((sdef_c, sdef_r0), ([0.0, 1.0, 0.0], [0.0, 1.0, 0.0])),
+        _ => panic!("SDEFになっていません"),
+    }
+    convert_weight(&mut vertex, WeightKind::BDEF2, &bones);
+    assert_eq!(WeightKind::from(vertex.weight_type), WeightKind::BDEF2);
+    //ウェイト0の枠を詰めるとBDEF1になる
+    vertex.weight_type = VertexWeight::BDEF2 {
+        bone_index_1: 1,
+        bone_index_2: 0,
+        bone_weight_1: 1.0,
+    };
+    collapse_weights(&mut vertex);
+    assert_eq!(vertex.weight_type, VertexWeight::BDEF1(1));
+    //ボーン1本ではSDEFにならない
+    convert_weight(&mut vertex, WeightKind::Sdef, &bones);
+    assert_eq!(vertex.weight_type, VertexWeight::BDEF1(1));
+}
@@ -0,0 +1,1244 @@
+use crate::document::{DocumentChanges, PmxDocument};
+use crate::math::{
+    add, cross, dot, length, mat4_transform_point, mat4_transform_vector, normalize,
+    quat_conjugate, quat_from_axis_angle, quat_mul, quat_normalize, quat_rotate, scale, sub, Mat4,
+    Vec3,
+};
+use crate::mirror::mirrored_bone;
+use crate::physics::{PhysicsSettings, PhysicsWorld};
+use crate::pose::{evaluate_global_matrices, skin_vertices, PoseEvaluator};
+use crate::ui::{EguiBoneView, Lang, PMXVertexView, SelectionMode};
+use crate::vertex_edit::{bone_weight, influences, WeightKind};
+use crate::weight_paint::{paint, vertex_neighbors, Brush, BrushMode};
+use egui::{Color32, PointerButton, Pos2, Rect, Sense, Stroke};
+use PMXUtil::types::{Bone, ConnectionDisplayMode, Face, VertexWeight};
+
+const FOV_Y: f32 = 30.0 * std::f32::consts::PI / 180.0;
+const NEAR: f32 = 0.1;
+///クリックやつまみの当たり判定の距離(ピクセル)
+const PICK_RADIUS: f32 = 6.0;
+const ROTATE_COLOR: Color32 = Color32::from_rgb(80, 150, 255);
+const TRANSLATE_COLOR: Color32 = Color32::from_rgb(200, 110, 255);
+const IK_COLOR: Color32 = Color32::from_rgb(255, 160, 0);
+const INVISIBLE_COLOR: Color32 = Color32::from_gray(110);
+const SELECTED_COLOR: Color32 = Color32::from_rgb(255, 80, 80);
+const SELECTED_VERTEX_COLOR: Color32 = Color32::from_rgb(255, 170, 40);
+const AXIS_COLORS: [Color32; 3] = [
+    Color32::from_rgb(230, 60, 60),
+    Color32::from_rgb(60, 200, 60),
+    Color32::from_rgb(60, 110, 240),
+];
+
+///選択中のボーンを動かすマニピュレータ
+///
+/// 軸は親の座標系での向きとワールドでの向きの組で持つ
+struct Manipulator {
+    bone: usize,
+    origin: Vec3,
+    ///ワールドでのつまみの大きさ
+    size: f32,
+    translate_axes: Vec<(Vec3, Vec3)>,
+    rotate_axes: Vec<(Vec3, Vec3)>,
+}
+
+///左ドラッグとクリックで何を選ぶか
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum Tool {
+    Bone,
+    ///頂点を矩形で選ぶ
+    Rectangle,
+    ///頂点を投げ縄で選ぶ
+    Lasso,
+    ///選択中のボーンのウェイトを塗る
+    Paint,
+}
+
+///頂点をどの値で色分けするか
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+enum VertexColorMode {
+    Plain,
+    ///選択中のボーンのウェイト(青から赤)
+    BoneWeight,
+    WeightKind,
+    ///影響するボーンの数
+    InfluenceCount,
+}
+impl VertexColorMode {
+    const ALL: [VertexColorMode; 4] = [
+        VertexColorMode::Plain,
+        VertexColorMode::BoneWeight,
+        VertexColorMode::WeightKind,
+        VertexColorMode::InfluenceCount,
+    ];
+    fn label(self) -> &'static str {
+        match self {
+            VertexColorMode::Plain => "なし",
+            VertexColorMode::BoneWeight => "ボーンのウェイト",
+            VertexColorMode::WeightKind => "変形方式",
+            VertexColorMode::InfluenceCount => "影響ボーン数",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+enum Handle {
+    Translate(usize),
+    Rotate(usize),
+}
+
+///ボーンとポーズを確認するための3Dビュー
+pub struct Viewport {
+    yaw: f32,
+    pitch: f32,
+    distance: f32,
+    target: Vec3,
+    show_ik: bool,
+    show_invisible: bool,
+    ///ドラッグ中のマニピュレータのつまみ
+    grabbed: Option<Handle>,
+    tool: Tool,
+    show_vertices: bool,
+    vertex_color: VertexColorMode,
+    ///頂点の選択でドラッグ中の軌跡
+    selecting: Option<Vec<Pos2>>,
+    brush: Brush,
+    ///ブラシが当たっている位置
+    brush_hit: Option<Vec3>,
+    ///ぼかし用の頂点のつながり。頂点数が変わったら作り直す
+    neighbors: Vec<Vec<usize>>,
+    physics_enabled: bool,
+    playing: bool,
+    physics_settings: PhysicsSettings,
+    ///ボーンが変わったら破棄し、次に有効な描画でボーンの初期位置から作る
+    physics: Option<PhysicsWorld>,
+}
+
+impl Viewport {
+    pub fn new() -> Self {
+        Self {
+            yaw: 0.0,
+            pitch: 0.0,
+            distance: 40.0,
+            target: [0.0, 10.0, 0.0],
+            show_ik: true,
+            show_invisible: false,
+            grabbed: None,
+            tool: Tool::Bone,
+            show_vertices: false,
+            vertex_color: VertexColorMode::Plain,
+            selecting: None,
+            brush: Brush::default(),
+            brush_hit: None,
+            neighbors: vec![],
+            physics_enabled: false,
+            playing: true,
+            physics_settings: PhysicsSettings::default(),
+            physics: None,
+        }
+    }
+    ///ボーンや剛体が変わったら、次に有効な描画で物理演算を作り直す
+    pub fn document_changed(&mut self, changes: DocumentChanges) {
+        if changes.bones || changes.bone_references {
+            self.physics = None;
+        }
+    }
+    fn camera_rotation(&self) -> [f32; 4] {
+        quat_mul(
+            quat_from_axis_angle([0.0, 1.0, 0.0], self.yaw),
+            quat_from_axis_angle([1.0, 0.0, 0.0], self.pitch),
+        )
+    }
+    ///ワールド座標を画面上の位置に変換する。カメラの後ろならNone
+    fn project(&self, rect: Rect, position: Vec3) -> Option<Pos2> {
+        let inverse = quat_conjugate(self.camera_rotation());
+        let relative = quat_rotate(inverse, sub(position, self.target));
+        let depth = relative[2] + self.distance;
+        if depth < NEAR {
+            return None;
+        }
+        let focal = Self::focal(rect);
+        Some(Pos2::new(
+            rect.center().x + relative[0] * focal / depth,
+            rect.center().y - relative[1] * focal / depth,
+        ))
+    }
+    fn focal(rect: Rect) -> f32 {
+        rect.height() / (2.0 * (FOV_Y / 2.0).tan())
+    }
+    ///画面上の位置を通る視線。カメラの位置と向き
+    fn ray(&self, rect: Rect, position: Pos2) -> (Vec3, Vec3) {
+        let rotation = self.camera_rotation();
+        let focal = Self::focal(rect);
+        let direction = [
+            (position.x - rect.center().x) / focal,
+            (rect.center().y - position.y) / focal,
+            1.0,
+        ];
+        (
+            add(
+                self.target,
+                quat_rotate(rotation, [0.0, 0.0, -self.distance]),
+            ),
+            normalize(quat_rotate(rotation, direction)),
+        )
+    }
+    ///ワールドでの向きが画面の奥を向いていれば1、手前なら-1
+    fn depth_sign(&self, direction: Vec3) -> f32 {
+        let relative = quat_rotate(quat_conjugate(self.camera_rotation()), direction);
+        if relative[2] >= 0.0 {
+            1.0
+        } else {
+            -1.0
+        }
+    }
+    fn handle_input(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        document: &PmxDocument,
+        bone_view: &mut EguiBoneView,
+        manipulator: Option<&Manipulator>,
+        screen: &[Option<Pos2>],
+    ) {
+        let rect = response.rect;
+        let pointer = ui.input().pointer.interact_pos();
+        if response.drag_started() && response.dragged_by(PointerButton::Primary) {
+            self.grabbed = match (manipulator, pointer) {
+                (Some(manipulator), Some(pointer)) => self.hit_handle(rect, manipulator, pointer),
+                _ => None,
+            };
+        }
+        if !response.dragged() {
+            self.grabbed = None;
+        }
+        match (self.grabbed, manipulator, pointer) {
+            (Some(handle), Some(manipulator), Some(pointer)) => {
+                let delta = response.drag_delta();
+                self.drag_handle(rect, manipulator, handle, pointer, delta, bone_view);
+            }
+            _ => self.move_camera(
+                ui,
+                response,
+                PointerButton::Primary,
+                PointerButton::Secondary,
+            ),
+        }
+        if let (true, Some(pointer)) = (response.clicked(), pointer) {
+            if let Some(index) = self.pick_bone(&document.bones, screen, pointer) {
+                bone_view.select_bone(document, index as i32);
+            }
+        }
+    }
+    fn move_camera(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        orbit: PointerButton,
+        pan: PointerButton,
+    ) {
+        let delta = response.drag_delta();
+        if response.dragged_by(orbit) {
+            self.yaw += delta.x * 0.01;
+            self.pitch = (self.pitch + delta.y * 0.01).clamp(-1.5, 1.5);
+        } else if response.dragged_by(pan) {
+            let scale = self.distance * 0.002;
+            let rotation = self.camera_rotation();
+            let right = quat_rotate(rotation, [-delta.x * scale, 0.0, 0.0]);
+            let up = quat_rotate(rotation, [0.0, delta.y * scale, 0.0]);
+            self.target = add(add(self.target, right), up);
+        }
+        if response.hovered() {
+            let scroll = ui.input().scroll_delta.y;
+            self.distance = (self.distance * (1.0 - scroll * 0.001)).max(1.0);
+        }
+    }
+    ///頂点の選択ツールでは左ドラッグで範囲選択し、カメラは右ドラッグで回転、中ドラッグで移動する
+    fn handle_vertex_input(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        document: &PmxDocument,
+        vertex_view: &mut PMXVertexView,
+        positions: &[Vec3],
+        screen: &[Option<Pos2>],
+    ) {
+        self.move_camera(
+            ui,
+            response,
+            PointerButton::Secondary,
+            PointerButton::Middle,
+        );
+        let pointer = ui.input().pointer.interact_pos();
+        let mode = SelectionMode::from_modifiers(ui.input().modifiers);
+        if response.drag_started() && response.dragged_by(PointerButton::Primary) {
+            self.selecting = pointer.map(|pointer| vec![pointer]);
+        }
+        if let (Some(path), Some(pointer)) = (&mut self.selecting, pointer) {
+            if path.last().is_some_and(|last| last.distance(pointer) > 2.0) {
+                path.push(pointer);
+            }
+        }
+        if !response.dragged() {
+            if let Some(path) = self.selecting.take() {
+                let selected = self.vertices_in(&path, screen);
+                vertex_view.select_vertices(document.vertices.len(), &selected, mode);
+            }
+        }
+        if let (true, Some(pointer)) = (response.clicked(), pointer) {
+            let picked =
+                self.pick_vertices(response.rect, pointer, &document.faces, positions, screen);
+            vertex_view.select_vertices(document.vertices.len(), &picked, mode);
+        }
+    }
+    ///ウェイト塗りでは左ドラッグで塗り、カメラは右ドラッグで回転、中ドラッグで移動する
+    fn handle_paint_input(
+        &mut self,
+        ui: &egui::Ui,
+        response: &egui::Response,
+        document: &mut PmxDocument,
+        vertex_view: &mut PMXVertexView,
+        bone_view: &EguiBoneView,
+        positions: &[Vec3],
+    ) {
+        self.move_camera(
+            ui,
+            response,
+            PointerButton::Secondary,
+            PointerButton::Middle,
+        );
+        let rect = response.rect;
+        self.brush_hit = ui
+            .input()
+            .pointer
+            .hover_pos()
+            .filter(|&pointer| rect.contains(pointer))
+            .and_then(|pointer| {
+                let (origin, direction) = self.ray(rect, pointer);
+                self.hit_face(rect, pointer, &document.faces, positions)
+                    .map(|(_, distance)| add(origin, scale(direction, distance)))
+            });
+        let center = match self.brush_hit {
+            Some(center) if response.dragged_by(PointerButton::Primary) || response.clicked() => {
+                center
+            }
+            _ => return,
+        };
+        let bone = bone_view.current_displaying_bone;
+        if bone < 0 || bone as usize >= document.bones.len() {
+            return;
+        }
+        if self.neighbors.len() != document.vertices.len() {
+            self.neighbors = vertex_neighbors(document.vertices.len(), &document.faces);
+        }
+        //1回のドラッグで塗った分を1つの編集にする
+        vertex_view.edit_vertices(
+            document,
+            "ウェイトペイント",
+            Some("paint"),
+            |_, document| {
+                let mirrored = [-center[0], center[1], center[2]];
+                let mirrored_bone = mirrored_bone(&document.bones, bone);
+                let vertices = &mut document.vertices;
+                //中央のボーンは左右のブラシをまとめ、重なった頂点を2回塗らない
+                let centers: &[Vec3] = if self.brush.mirror_x && mirrored_bone == bone {
+                    &[center, mirrored]
+                } else {
+                    &[center]
+                };
+                paint(
+                    vertices,
+                    positions,
+                    &self.neighbors,
+                    centers,
+                    bone,
+                    &self.brush,
+                );
+                if self.brush.mirror_x && mirrored_bone != bone {
+                    paint(
+                        vertices,
+                        positions,
+                        &self.neighbors,
+                        &[mirrored],
+                        mirrored_bone,
+                        &self.brush,
+                    );
+                }
+            },
+        );
+    }
+    ///ドラッグの軌跡で囲まれた頂点
+    fn vertices_in(&self, path: &[Pos2], screen: &[Option<Pos2>]) -> Vec<usize> {
+        let (first, last) = match (path.first(), path.last()) {
+            (Some(&first), Some(&last)) => (first, last),
+            _ => return vec![],
+        };
+        let rect = Rect::from_two_pos(first, last);
+        screen
+            .iter()
+            .enumerate()
+            .filter_map(|(index, position)| position.map(|position| (index, position)))
+            .filter(|&(_, position)| match self.tool {
+                Tool::Lasso => point_in_polygon(position, path),
+                _ => rect.contains(position),
+            })
+            .map(|(index, _)| index)
+            .collect()
+    }
+    ///クリック位置の頂点を選ぶ。近くに頂点がなければ視線が最初に当たる面の3頂点
+    ///
+    /// 面の奥に隠れた頂点は選ばない
+    fn pick_vertices(
+        &self,
+        rect: Rect,
+        pointer: Pos2,
+        faces: &[Face],
+        positions: &[Vec3],
+        screen: &[Option<Pos2>],
+    ) -> Vec<usize> {
+        let (origin, direction) = self.ray(rect, pointer);
+        let face_hit = self.hit_face(rect, pointer, faces, positions);
+        let limit = face_hit.map_or(f32::INFINITY, |(_, distance)| distance * 1.001 + NEAR);
+        let vertex = screen
+            .iter()
+            .enumerate()
+            .filter_map(|(index, screen)| screen.map(|screen| (index, screen.distance(pointer))))
+            .filter(|&(index, distance)| {
+                distance < PICK_RADIUS && dot(sub(positions[index], origin), direction) <= limit
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1));
+        match (vertex, face_hit) {
+            (Some((index, _)), _) => vec![index],
+            (None, Some((face, _))) => face.vertices.iter().map(|&index| index as usize).collect(),
+            (None, None) => vec![],
+        }
+    }
+    ///視線が最初に当たる面と、そこまでの距離
+    fn hit_face<'a>(
+        &self,
+        rect: Rect,
+        pointer: Pos2,
+        faces: &'a [Face],
+        positions: &[Vec3],
+    ) -> Option<(&'a Face, f32)> {
+        let (origin, direction) = self.ray(rect, pointer);
+        let position = |index: i32| positions.get(index as usize).copied();
+        faces
+            .iter()
+            .filter_map(|face| {
+                let [a, b, c] = face.vertices;
+                let (a, b, c) = (position(a)?, position(b)?, position(c)?);
+                ray_triangle(origin, direction, [a, b, c]).map(|distance| (face, distance))
+            })
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+    }
+    fn is_bone_shown(&self, bone: &Bone) -> bool {
+        self.show_invisible || bone.display_bone_in_viewer
+    }
+    ///クリック位置に最も近いボーンの根元を選ぶ
+    fn pick_bone(&self, bones: &[Bone], screen: &[Option<Pos2>], pointer: Pos2) -> Option<usize> {
+        bones
+            .iter()
+            .zip(screen.iter())
+            .enumerate()
+            .filter(|(_, (bone, _))| self.is_bone_shown(bone))
+            .filter_map(|(index, (_, position))| {
+                position.map(|position| (index, position.distance(pointer)))
+            })
+            .filter(|&(_, distance)| distance < PICK_RADIUS * 2.0)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(index, _)| index)
+    }
+    ///選択中のボーンのマニピュレータ。操作できないボーンならNone
+    fn manipulator(
+        &self,
+        document: &PmxDocument,
+        bone_view: &EguiBoneView,
+        matrices: &[Mat4],
+    ) -> Option<Manipulator> {
+        let index = bone_view.current_displaying_bone;
+        let bone = document.bones.get(index as usize).filter(|_| index >= 0)?;
+        if !bone.controllable_in_viewer
+            || !(bone.rotatable_in_viewer || bone.translatable_in_viewer)
+        {
+            return None;
+        }
+        let parent = matrices
+            .get(bone.parent as usize)
+            .filter(|_| bone.parent >= 0);
+        let to_world = |axis: Vec3| match parent {
+            Some(parent) => normalize(mat4_transform_vector(parent, axis)),
+            None => axis,
+        };
+        let axes = match bone.local_axis {
+            Some((x, z)) => {
+                let x = normalize(x);
+                let y = normalize(cross(z, x));
+                vec![x, y, cross(x, y)]
+            }
+            None => vec![[1.0, 0.0, 0.0], [0.0, 1.0, 0.0], [0.0, 0.0, 1.0]],
+        };
+        let rotate_axes = match bone.fixed_axis {
+            Some(axis) => vec![normalize(axis)],
+            None => axes.clone(),
+        };
+        let with_world = |axes: Vec<Vec3>| -> Vec<(Vec3, Vec3)> {
+            axes.into_iter()
+                .map(|axis| (axis, to_world(axis)))
+                .collect()
+        };
+        Some(Manipulator {
+            bone: index as usize,
+            origin: mat4_transform_point(&matrices[index as usize], [0.0; 3]),
+            size: self.distance * 0.08,
+            translate_axes: if bone.translatable_in_viewer {
+                with_world(axes)
+            } else {
+                vec![]
+            },
+            rotate_axes: if bone.rotatable_in_viewer {
+                with_world(rotate_axes)
+            } else {
+                vec![]
+            },
+        })
+    }
+    ///回転のつまみの輪を画面上の折れ線にする
+    fn ring(&self, rect: Rect, manipulator: &Manipulator, axis: Vec3) -> Vec<Pos2> {
+        let helper = if axis[1].abs() < 0.9 {
+            [0.0, 1.0, 0.0]
+        } else {
+            [1.0, 0.0, 0.0]
+        };
+        let u = scale(normalize(cross(axis, helper)), manipulator.size);
+        let v = scale(normalize(cross(axis, u)), manipulator.size);
+        (0..=32)
+            .filter_map(|step| {
+                let angle = step as f32 / 32.0 * std::f32::consts::TAU;
+                let offset = add(scale(u, angle.cos()), scale(v, angle.sin()));
+                self.project(rect, add(manipulator.origin, offset))
+            })
+            .collect()
+    }
+    fn translate_tip(&self, rect: Rect, manipulator: &Manipulator, axis: Vec3) -> Option<Pos2> {
+        self.project(
+            rect,
+            add(manipulator.origin, scale(axis, manipulator.size * 1.5)),
+        )
+    }
+    fn hit_handle(&self, rect: Rect, manipulator: &Manipulator, pointer: Pos2) -> Option<Handle> {
+        let origin = self.project(rect, manipulator.origin)?;
+        let mut hits = vec![];
+        for (index, &(_, axis)) in manipulator.translate_axes.iter().enumerate() {
+            if let Some(tip) = self.translate_tip(rect, manipulator, axis) {
+                hits.push((
+                    Handle::Translate(index),
+                    segment_distance(pointer, origin, tip),
+                ));
+            }
+        }
+        for (index, &(_, axis)) in manipulator.rotate_axes.iter().enumerate() {
+            let ring = self.ring(rect, manipulator, axis);
+            let distance = ring
+                .windows(2)
+                .map(|segment| segment_distance(pointer, segment[0], segment[1]))
+                .fold(f32::INFINITY, f32::min);
+            hits.push((Handle::Rotate(index), distance));
+        }
+        hits.into_iter()
+            .filter(|&(_, distance)| distance < PICK_RADIUS)
+            .min_by(|a, b| a.1.total_cmp(&b.1))
+            .map(|(handle, _)| handle)
+    }
+    ///つまみのドラッグ量をポーズの移動/回転に変える
+    fn drag_handle(
+        &self,
+        rect: Rect,
+        manipulator: &Manipulator,
+        handle: Handle,
+        pointer: Pos2,
+        delta: egui::Vec2,
+        bone_view: &mut EguiBoneView,
+    ) {
+        let origin = match self.project(rect, manipulator.origin) {
+            Some(origin) => origin,
+            None => return,
+        };
+        let bone_pose = match bone_view.pose.bones.get_mut(manipulator.bone) {
+            Some(bone_pose) => bone_pose,
+            None => return,
+        };
+        match handle {
+            Handle::Translate(index) => {
+                let (local, world) = manipulator.translate_axes[index];
+                let tip = match self.translate_tip(rect, manipulator, world) {
+                    Some(tip) => tip,
+                    None => return,
+                };
+                let axis = tip - origin;
+                if axis.length_sq() < 1.0 {
+                    return;
+                }
+                let amount = dot2(delta, axis) / axis.length_sq() * manipulator.size * 1.5;
+                bone_pose.translation = add(bone_pose.translation, scale(local, amount));
+            }
+            Handle::Rotate(index) => {
+                let (local, world) = manipulator.rotate_axes[index];
+                //画面上で反時計回りの角度
+                let angle_of =
+                    |position: Pos2| (origin.y - position.y).atan2(position.x - origin.x);
+                let mut angle = angle_of(pointer) - angle_of(pointer - delta);
+                if angle > std::f32::consts::PI {
+                    angle -= std::f32::consts::TAU;
+                } else if angle < -std::f32::consts::PI {
+                    angle += std::f32::consts::TAU;
+                }
+                let angle = angle * self.depth_sign(world);
+                bone_pose.rotation = quat_normalize(quat_mul(
+                    quat_from_axis_angle(local, angle),
+                    bone_pose.rotation,
+                ));
+            }
+        }
+    }
+    fn display_physics_settings(&mut self, ui: &mut egui::Ui, document: &PmxDocument) {
+        ui.checkbox(
+            &mut self.physics_enabled,
+            format!(
+                "物理演算 (剛体{} ジョイント{})",
+                document.rigids.len(),
+                document.joints.len()
+            ),
+        );
+        ui.horizontal(|ui| {
+            let label = if self.playing {
+                "一時停止"
+            } else {
+                "再生"
+            };
+            if ui.button(label).clicked() {
+                self.playing = !self.playing;
+            }
+            if ui.button("リセット").clicked() {
+                if let Some(physics) = &mut self.physics {
+                    physics.reset();
+                }
+            }
+        });
+        let settings = &mut self.physics_settings;
+        egui::Grid::new("physics settings").show(ui, |ui| {
+            ui.label("fps");
+            let mut fps = 1.0 / settings.fixed_timestep;
+            if ui
+                .add(egui::DragValue::new(&mut fps).clamp_range(10.0..=480.0))
+                .changed()
+            {
+                settings.fixed_timestep = 1.0 / fps;
+            }
+            ui.end_row();
+            ui.label("最大サブステップ");
+            ui.add(egui::DragValue::new(&mut settings.max_substeps).clamp_range(1..=20));
+            ui.end_row();
+            ui.label("ウォームアップ");
+            ui.add(egui::DragValue::new(&mut settings.warm_up_frames).clamp_range(0..=600));
+            ui.end_row();
+            ui.label("反復回数");
+            ui.add(egui::DragValue::new(&mut settings.iterations).clamp_range(1..=50));
+            ui.end_row();
+            ui.label("重力");
+            ui.horizontal(|ui| {
+                for value in settings.gravity.iter_mut() {
+                    ui.add(egui::DragValue::new(value).speed(0.5));
+                }
+            });
+            ui.end_row();
+        });
+    }
+    ///物理演算が有効なら経過時間分シミュレーションしたポーズを返す
+    fn evaluate(
+        &mut self,
+        ui: &egui::Ui,
+        document: &PmxDocument,
+        bone_view: &EguiBoneView,
+    ) -> Vec<Mat4> {
+        if !self.physics_enabled {
+            return evaluate_global_matrices(&document.bones, &document.bone_tree, &bone_view.pose);
+        }
+        let physics = self.physics.get_or_insert_with(|| {
+            PhysicsWorld::new(&document.rigids, &document.joints, &document.bones)
+        });
+        physics.settings = self.physics_settings;
+        let elapsed = if self.playing {
+            ui.ctx().request_repaint();
+            ui.input().unstable_dt.min(0.1)
+        } else {
+            0.0
+        };
+        let mut evaluator = PoseEvaluator::new(&document.bones, &document.bone_tree);
+        evaluator.evaluate_with_physics(&bone_view.pose, physics, elapsed);
+        evaluator.global_matrices()
+    }
+    pub fn display(
+        &mut self,
+        ui: &mut egui::Ui,
+        document: &mut PmxDocument,
+        bone_view: &mut EguiBoneView,
+        vertex_view: &mut PMXVertexView,
+    ) {
+        egui::SidePanel::left("Viewport settings").show_inside(ui, |ui| {
+            ui.horizontal(|ui| {
+                ui.selectable_value(&mut self.tool, Tool::Bone, "ボーン");
+                ui.selectable_value(&mut self.tool, Tool::Rectangle, "矩形選択");
+                ui.selectable_value(&mut self.tool, Tool::Lasso, "投げ縄");
+                ui.selectable_value(&mut self.tool, Tool::Paint, "ウェイト塗り");
+            });
+            match self.tool {
+                Tool::Bone => {}
+                Tool::Rectangle | Tool::Lasso => {
+                    ui.label("Shift:追加 Ctrl:除外 右ドラッグ:回転 中ドラッグ:移動");
+                }
+                Tool::Paint => self.display_brush_settings(ui, document, bone_view),
+            }
+            ui.checkbox(&mut self.show_ik, "IK表示");
+            ui.checkbox(&mut self.show_invisible, "非表示ボーン");
+            ui.checkbox(&mut self.show_vertices, "頂点表示");
+            self.display_vertex_color_settings(ui);
+            ui.separator();
+            self.display_physics_settings(ui, document);
+            ui.separator();
+            ui.label("IK");
+            egui::ScrollArea::vertical().show(ui, |ui| {
+                for (index, bone) in document.bones.iter().enumerate() {
+                    if bone.ik_info.is_none() {
+                        continue;
+                    }
+                    let name = match bone_view.lang {
+                        Lang::English => &bone.english_name,
+                        Lang::Japanese => &bone.name,
+                    };
+                    if let Some(enabled) = bone_view.pose.ik_enabled.get_mut(index) {
+                        ui.checkbox(enabled, format!("{}:{}", index, name));
+                    }
+                }
+            });
+        });
+        egui::CentralPanel::default().show_inside(ui, |ui| {
+            let (response, painter) =
+                ui.allocate_painter(ui.available_size(), Sense::click_and_drag());
+            let rect = response.rect;
+            painter.rect_filled(rect, 0.0, Color32::from_gray(32));
+
+            let matrices = self.evaluate(ui, document, bone_view);
+            let screen: Vec<Option<Pos2>> = matrices
+                .iter()
+                .map(|matrix| self.project(rect, mat4_transform_point(matrix, [0.0; 3])))
+                .collect();
+            let vertex_tool = self.tool != Tool::Bone;
+            let positions =
+                if vertex_tool || self.show_vertices || self.vertex_color != VertexColorMode::Plain
+                {
+                    skin_vertices(&document.vertices, &document.bones, &matrices)
+                } else {
+                    vec![]
+                };
+            let vertex_screen: Vec<Option<Pos2>> = positions
+                .iter()
+                .map(|&position| self.project(rect, position))
+                .collect();
+            let manipulator = if vertex_tool {
+                None
+            } else {
+                self.manipulator(document, bone_view, &matrices)
+            };
+            match self.tool {
+                Tool::Bone => self.handle_input(
+                    ui,
+                    &response,
+                    document,
+                    bone_view,
+                    manipulator.as_ref(),
+                    &screen,
+                ),
+                Tool::Rectangle | Tool::Lasso => self.handle_vertex_input(
+                    ui,
+                    &response,
+                    document,
+                    vertex_view,
+                    &positions,
+                    &vertex_screen,
+                ),
+                Tool::Paint => self.handle_paint_input(
+                    ui,
+                    &response,
+                    document,
+                    vertex_view,
+                    bone_view,
+                    &positions,
+                ),
+            }
+
+            if !vertex_screen.is_empty() {
+                let bone = bone_view.current_displaying_bone;
+                self.draw_vertices(&painter, document, vertex_view, bone, &vertex_screen);
+            }
+            self.draw_bones(&painter, rect, document, bone_view, &matrices, &screen);
+            if self.show_ik {
+                self.draw_ik_overlay(&painter, document, bone_view, &screen);
+            }
+            if let Some(manipulator) = &manipulator {
+                self.draw_manipulator(&painter, rect, manipulator);
+            }
+            self.draw_selecting(&painter);
+            if self.tool == Tool::Paint {
+                self.draw_brush(&painter, rect);
+            }
+        });
+    }
+    fn display_brush_settings(
+        &mut self,
+        ui: &mut egui::Ui,
+        document: &PmxDocument,
+        bone_view: &EguiBoneView,
+    ) {
+        let bone = bone_view.current_displaying_bone;
+        let name = match document.bones.get(bone as usize) {
+            Some(bone) => match bone_view.lang {
+                Lang::English => &bone.english_name,
+                Lang::Japanese => &bone.name,
+            },
+            None => "-",
+        };
+        ui.label(format!("塗るボーン: {}:{}", bone, name));
+        let brush = &mut self.brush;
+        ui.horizontal(|ui| {
+            for mode in BrushMode::ALL {
+                ui.selectable_value(&mut brush.mode, mode, mode.label());
+            }
+        });
+        egui::Grid::new("brush settings").show(ui, |ui| {
+            ui.label("半径");
+            ui.add(
+                egui::DragValue::new(&mut brush.radius)
+                    .speed(0.05)
+                    .clamp_range(0.01..=100.0),
+            );
+            ui.end_row();
+            ui.label("減衰");
+            ui.add(egui::Slider::new(&mut brush.falloff, 0.0..=1.0));
+            ui.end_row();
+            ui.label("強さ");
+            ui.add(egui::Slider::new(&mut brush.strength, 0.0..=1.0));
+            ui.end_row();
+            ui.label("置換のウェイト");
+            ui.add(egui::Slider::new(&mut brush.weight, 0.0..=1.0));
+            ui.end_row();
+        });
+        ui.checkbox(&mut brush.mirror_x, "X軸ミラー");
+        ui.label("左ドラッグ:塗る 右ドラッグ:回転 中ドラッグ:移動");
+    }
+    ///ブラシの範囲を当たっている位置に円で描く
+    fn draw_brush(&self, painter: &egui::Painter, rect: Rect) {
+        let center = match self.brush_hit {
+            Some(center) => center,
+            None => return,
+        };
+        let right = quat_rotate(self.camera_rotation(), [self.brush.radius, 0.0, 0.0]);
+        let (center, edge) = match (
+            self.project(rect, center),
+            self.project(rect, add(center, right)),
+        ) {
+            (Some(center), Some(edge)) => (center, edge),
+            _ => return,
+        };
+        painter.circle_stroke(
+            center,
+            center.distance(edge),
+            Stroke::new(1.0, Color32::WHITE),
+        );
+    }
+    ///頂点を点で描く。選択中は橙
+    fn draw_vertices(
+        &self,
+        painter: &egui::Painter,
+        document: &PmxDocument,
+        vertex_view: &PMXVertexView,
+        bone: i32,
+        screen: &[Option<Pos2>],
+    ) {
+        let radius = match self.vertex_color {
+            VertexColorMode::Plain => 1.0,
+            _ => 1.5,
+        };
+        for (index, position) in screen.iter().enumerate() {
+            if let Some(position) = position {
+                if !vertex_view.selection.contains(&index) {
+                    let weight = &document.vertices[index].weight_type;
+                    let color = vertex_color(self.vertex_color, weight, bone);
+                    painter.circle_filled(*position, radius, color);
+                }
+            }
+        }
+        for &index in &vertex_view.selection {
+            if let Some(Some(position)) = screen.get(index) {
+                painter.circle_filled(*position, 2.0, SELECTED_VERTEX_COLOR);
+            }
+        }
+    }
+    ///色分けの選択と凡例
+    fn display_vertex_color_settings(&mut self, ui: &mut egui::Ui) {
+        egui::ComboBox::from_label("頂点の色")
+            .selected_text(self.vertex_color.label())
+            .show_ui(ui, |ui| {
+                for mode in VertexColorMode::ALL {
+                    ui.selectable_value(&mut self.vertex_color, mode, mode.label());
+                }
+            });
+        ui.horizontal_wrapped(|ui| match self.vertex_color {
+            VertexColorMode::Plain => {}
+            VertexColorMode::BoneWeight => {
+                for weight in [0.0, 0.25, 0.5, 0.75, 1.0] {
+                    ui.colored_label(heat_color(weight), format!("{:.2}", weight));
+                }
+            }
+            VertexColorMode::WeightKind => {
+                for kind in WeightKind::ALL {
+                    ui.colored_label(weight_kind_color(kind), kind.to_string());
+                }
+            }
+            VertexColorMode::InfluenceCount => {
+                for count in 1..=4 {
+                    ui.colored_label(influence_count_color(count), format!("{}本", count));
+                }
+            }
+        });
+    }
+    fn draw_selecting(&self, painter: &egui::Painter) {
+        let path = match &self.selecting {
+            Some(path) if path.len() > 1 => path,
+            _ => return,
+        };
+        let stroke = Stroke::new(1.0, Color32::WHITE);
+        match self.tool {
+            Tool::Lasso => {
+                painter.add(egui::Shape::closed_line(path.clone(), stroke));
+            }
+            _ => {
+                let rect = Rect::from_two_pos(path[0], path[path.len() - 1]);
+                painter.rect_stroke(rect, 0.0, stroke);
+            }
+        }
+    }
+    ///ボーンを根元から先へ向かう八面体で、先のないボーンは円で描く
+    ///
+    /// 色はIK(橙)、移動可(紫)、回転(青)、非表示(灰)。選択中は赤
+    fn draw_bones(
+        &self,
+        painter: &egui::Painter,
+        rect: Rect,
+        document: &PmxDocument,
+        bone_view: &EguiBoneView,
+        matrices: &[Mat4],
+        screen: &[Option<Pos2>],
+    ) {
+        for (index, bone) in document.bones.iter().enumerate() {
+            if !self.is_bone_shown(bone) {
+                continue;
+            }
+            let head = match screen[index] {
+                Some(head) => head,
+                None => continue,
+            };
+            let color = if index as i32 == bone_view.current_displaying_bone {
+                SELECTED_COLOR
+            } else if !bone.display_bone_in_viewer {
+                INVISIBLE_COLOR
+            } else if bone.ik_info.is_some() {
+                IK_COLOR
+            } else if bone.translatable_in_viewer {
+                TRANSLATE_COLOR
+            } else {
+                ROTATE_COLOR
+            };
+            let stroke = Stroke::new(1.0, color);
+            let head_position = mat4_transform_point(&matrices[index], [0.0; 3]);
+            let tail_position = match bone.connection_display_mode {
+                ConnectionDisplayMode::OtherBone(tail) if tail >= 0 => matrices
+                    .get(tail as usize)
+                    .map(|matrix| mat4_transform_point(matrix, [0.0; 3])),
+                ConnectionDisplayMode::Offset(offset) => {
+                    Some(mat4_transform_point(&matrices[index], offset))
+                }
+                _ => None,
+            };
+            let direction = tail_position.map_or([0.0; 3], |tail| sub(tail, head_position));
+            let bone_length = length(direction);
+            if bone_length < f32::EPSILON {
+                painter.circle_stroke(head, 4.0, stroke);
+                continue;
+            }
+            let helper = if direction[1].abs() < 0.9 * bone_length {
+                [0.0, 1.0, 0.0]
+            } else {
+                [1.0, 0.0, 0.0]
+            };
+            let width = bone_length * 0.1;
+            let u = scale(normalize(cross(direction, helper)), width);
+            let v = scale(normalize(cross(direction, u)), width);
+            let middle = add(head_position, scale(direction, 0.2));
+            let ring: Vec<Option<Pos2>> = [u, v, scale(u, -1.0), scale(v, -1.0)]
+                .iter()
+                .map(|&offset| self.project(rect, add(middle, offset)))
+                .collect();
+            let tail = tail_position.and_then(|tail| self.project(rect, tail));
+            for (position, corner) in ring.iter().enumerate() {
+                let next = ring[(position + 1) % ring.len()];
+                for other in [Some(head), tail, next] {
+                    if let (Some(from), Some(to)) = (*corner, other) {
+                        painter.line_segment([from, to], stroke);
+                    }
+                }
+            }
+            if bone.translatable_in_viewer {
+                painter.rect_stroke(
+                    Rect::from_center_size(head, egui::vec2(6.0, 6.0)),
+                    0.0,
+                    stroke,
+                );
+            } else {
+                painter.circle_stroke(head, 3.0, stroke);
+            }
+        }
+    }
+    ///移動は軸の矢印、回転は軸周りの輪で描く。固定軸のボーンは輪が1つ
+    fn draw_manipulator(&self, painter: &egui::Painter, rect: Rect, manipulator: &Manipulator) {
+        let origin = match self.project(rect, manipulator.origin) {
+            Some(origin) => origin,
+            None => return,
+        };
+        let color = |handle: Handle, index: usize, count: usize| {
+            if self.grabbed == Some(handle) {
+                Color32::YELLOW
+            } else if count == 1 {
+                Color32::WHITE
+            } else {
+                AXIS_COLORS[index % AXIS_COLORS.len()]
+            }
+        };
+        let count = manipulator.rotate_axes.len();
+        for (index, &(_, axis)) in manipulator.rotate_axes.iter().enumerate() {
+            let ring = self.ring(rect, manipulator, axis);
+            let stroke = Stroke::new(2.0, color(Handle::Rotate(index), index, count));
+            painter.add(egui::Shape::line(ring, stroke));
+        }
+        let count = manipulator.translate_axes.len();
+        for (index, &(_, axis)) in manipulator.translate_axes.iter().enumerate() {
+            if let Some(tip) = self.translate_tip(rect, manipulator, axis) {
+                let color = color(Handle::Translate(index), index, count);
+                painter.arrow(origin, tip - origin, Stroke::new(2.0, color));
+            }
+        }
+    }
+    ///IKの鎖(黄)、エフェクタ(赤)、IKボーンの目標位置(橙)を重ねて描く
+    fn draw_ik_overlay(
+        &self,
+        painter: &egui::Painter,
+        document: &PmxDocument,
+        bone_view: &EguiBoneView,
+        screen: &[Option<Pos2>],
+    ) {
+        let position = |index: i32| -> Option<Pos2> {
+            if index < 0 {
+                None
+            } else {
+                screen.get(index as usize).copied().flatten()
+            }
+        };
+        for (index, bone) in document.bones.iter().enumerate() {
+            let ik_info = match &bone.ik_info {
+                Some(ik_info) => ik_info,
+                None => continue,
+            };
+            let enabled = bone_view
+                .pose
+                .ik_enabled
+                .get(index)
+                .copied()
+                .unwrap_or(true);
+            let selected = index as i32 == bone_view.current_displaying_bone;
+            let alpha = if selected { 255 } else { 128 };
+            let chain_color = if enabled {
+                Color32::from_rgba_unmultiplied(255, 220, 0, alpha)
+            } else {
+                Color32::from_rgba_unmultiplied(128, 128, 128, alpha)
+            };
+            let mut previous = position(ik_info.ik_target_bone_index);
+            for link in &ik_info.ik_links {
+                let current = position(link.ik_bone_index);
+                if let (Some(from), Some(to)) = (previous, current) {
+                    painter.line_segment([from, to], Stroke::new(2.0, chain_color));
+                }
+                if let Some(current) = current {
+                    painter.circle_stroke(current, 4.0, Stroke::new(1.0, chain_color));
+                }
+                previous = current;
+            }
+            let target = position(index as i32);
+            let effector = position(ik_info.ik_target_bone_index);
+            if let (Some(target), Some(effector)) = (target, effector) {
+                painter.line_segment(
+                    [effector, target],
+                    Stroke::new(1.0, Color32::from_rgba_unmultiplied(255, 255, 255, alpha)),
+                );
+            }
+            if let Some(effector) = effector {
+                painter.circle_filled(
+                    effector,
+                    4.0,
+                    Color32::from_rgba_unmultiplied(255, 64, 64, alpha),
+                );
+            }
+            if let Some(target) = target {
+                painter.circle_stroke(
+                    target,
+                    6.0,
+                    Stroke::new(2.0, Color32::from_rgba_unmultiplied(255, 140, 0, alpha)),
+                );
+            }
+            if let (true, Some(target)) = (selected, target) {
+                painter.text(
+                    target + egui::vec2(8.0, -8.0),
+                    egui::Align2::LEFT_BOTTOM,
+                    &bone.name,
+                    egui::FontId::proportional(14.0),
+                    Color32::WHITE,
+                );
+            }
+        }
+    }
+}
+
+///0(青)から1(赤)へ、シアン、緑、黄を通る色
+fn heat_color(weight: f32) -> Color32 {
+    let weight = weight.clamp(0.0, 1.0) * 4.0;
+    let ramp = |value: f32| (value.clamp(0.0, 1.0) * 255.0) as u8;
+    Color32::from_rgb(
+        ramp(weight - 2.0),
+        ramp(if weight < 2.0 { weight } else { 4.0 - weight }),
+        ramp(2.0 - weight),
+    )
+}
+fn weight_kind_color(kind: WeightKind) -> Color32 {
+    match kind {
+        WeightKind::BDEF1 => Color32::from_rgb(90, 140, 255),
+        WeightKind::BDEF2 => Color32::from_rgb(80, 220, 80),
+        WeightKind::BDEF4 => Color32::from_rgb(255, 220, 0),
+        WeightKind::Sdef => Color32::from_rgb(255, 90, 255),
+        WeightKind::Qdef => Color32::from_rgb(0, 230, 230),
+    }
+}
+fn influence_count_color(count: usize) -> Color32 {
+    match count {
+        0 | 1 => Color32::from_rgb(90, 140, 255),
+        2 => Color32::from_rgb(80, 220, 80),
+        3 => Color32::from_rgb(255, 220, 0),
+        _ => Color32::from_rgb(255, 70, 70),
+    }
+}
+fn vertex_color(mode: VertexColorMode, weight: &VertexWeight, bone: i32) -> Color32 {
+    match mode {
+        VertexColorMode::Plain => Color32::from_gray(150),
+        VertexColorMode::BoneWeight => heat_color(bone_weight(weight, bone)),
+        VertexColorMode::WeightKind => weight_kind_color(WeightKind::from(*weight)),
+        VertexColorMode::InfluenceCount => influence_count_color(influences(weight).len()),
+    }
+}
+///点から線分までの画面上の距離
+fn segment_distance(point: Pos2, from: Pos2, to: Pos2) -> f32 {
+    let segment = to - from;
+    let t = if segment.length_sq() > 0.0 {
+        (dot2(point - from, segment) / segment.length_sq()).clamp(0.0, 1.0)
+    } else {
+        0.0
+    };
+    point.distance(from + segment * t)
+}
+fn dot2(a: egui::Vec2, b: egui::Vec2) -> f32 {
+    a.x * b.x + a.y * b.y
+}
+///点が多角形の内側にあるか(偶奇判定)
+fn point_in_polygon(point: Pos2, polygon: &[Pos2]) -> bool {
+    let mut inside = false;
+    for (index, &from) in polygon.iter().enumerate() {
+        let to = polygon[(index + 1) % polygon.len()];
+        if (from.y > point.y) != (to.y > point.y)
+            && point.x < from.x + (point.y - from.y) / (to.y - from.y) * (to.x - from.x)
+        {
+            inside = !inside;
+        }
+    }
+    inside
+}
+///光線と三角形の交差(Möller–Trumbore)。当たれば光線上の距離
+fn ray_triangle(origin: Vec3, direction: Vec3, triangle: [Vec3; 3]) -> Option<f32> {
+    let edge1 = sub(triangle[1], triangle[0]);
+    let edge2 = sub(triangle[2], triangle[0]);
+    let p = cross(direction, edge2);
+    let determinant = dot(edge1, p);
+    if determinant.abs() < f32::EPSILON {
+        return None;
+    }
+    let inverse = 1.0 / determinant;
+    let t = sub(origin, triangle[0]);
+    let u = dot(t, p) * inverse;
+    if !(0.0..=1.0).contains(&u) {
+        return None;
+    }
+    let q = cross(t, edge1);
+    let v = dot(direction, q) * inverse;
+    if v < 0.0 || u + v > 1.0 {
+        return None;
+    }
+    let distance = dot(edge2, q) * inverse;
+    if distance > 0.0 {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
+#[test]
+fn test_heat_color() {
+    assert_eq!(heat_color(0.0), Color32::from_rgb(0, 0, 255));
+    assert_eq!(heat_color(0.5), Color32::from_rgb(0, 255, 0));
+    assert_eq!(heat_color(1.0), Color32::from_rgb(255, 0, 0));
+    let weight = VertexWeight::BDEF2 {
+        bone_index_1: 3,
+        bone_index_2: 4,
+        bone_weight_1: 1.0,
+    };
+    //ウェイト0のボーンは数えない
+    assert_eq!(
+        vertex_color(VertexColorMode::InfluenceCount, &weight, 3),
+        influence_count_color(1)
+    );
+    assert_eq!(
+        vertex_color(VertexColorMode::BoneWeight, &weight, 4),
+        heat_color(0.0)
+    );
+}
+
+#[test]
+fn test_selection_geometry() {
+    let square = [
+        Pos2::new(0.0, 0.0),
+        Pos2::new(10.0, 0.0),
+        Pos2::new(10.0, 10.0),
+        Pos2::new(0.0, 10.0),
+    ];
+    assert!(point_in_polygon(Pos2::new(5.0, 5.0), &square));
+    assert!(!point_in_polygon(Pos2::new(15.0, 5.0), &square));
+    let triangle = [[-1.0, -1.0, 5.0], [1.0, -1.0, 5.0], [0.0, 1.0, 5.0]];
+    let hit = ray_triangle([0.0; 3], [0.0, 0.0, 1.0], triangle);
+    assert!(hit.is_some_and(|distance| (distance - 5.0).abs() < 1.0e-5));
+    assert!(ray_triangle([0.0; 3], [0.0, 0.0, -1.0], triangle).is_none());
+    assert!(ray_triangle([3.0, 0.0, 0.0], [0.0, 0.0, 1.0], triangle).is_none());
+}
@@ -0,0 +1,192 @@
+use std::io::{Read, Write};
+use std::path::Path;
+
+const VPD_SIGNATURE: &str = "Vocaloid Pose Data file";
+
+///VPDファイル1個分のポーズ
+#[derive(Debug, Clone, PartialEq, Default)]
+pub struct Vpd {
+    ///親ファイル名(通常はモデル名.osm)
+    pub model_name: String,
+    pub bones: Vec<VpdBone>,
+    pub morphs: Vec<VpdMorph>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VpdBone {
+    pub name: String,
+    pub translation: [f32; 3],
+    ///quaternion x,y,z,w
+    pub rotation: [f32; 4],
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VpdMorph {
+    pub name: String,
+    pub weight: f32,
+}
+
+#[derive(Debug)]
+pub enum VpdError {
+    IoError(std::io::Error),
+    InvalidSignature,
+    ///壊れたブロックがある行番号(1始まり)
+    InvalidSyntax(usize),
+}
+
+impl From<std::io::Error> for VpdError {
+    fn from(err: std::io::Error) -> Self {
+        Self::IoError(err)
+    }
+}
+
+impl std::fmt::Display for VpdError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            VpdError::IoError(err) => write!(f, "{}", err),
+            VpdError::InvalidSignature => write!(f, "not a VPD file"),
+            VpdError::InvalidSyntax(line) => write!(f, "invalid VPD syntax at line {}", line),
+        }
+    }
+}
+
+impl Vpd {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, VpdError> {
+        Self::from_reader(std::fs::File::open(path)?)
+    }
+    ///VPDはShift-JISで書かれているのでデコードしてから読む
+    pub fn from_reader<R: Read>(mut reader: R) -> Result<Self, VpdError> {
+        let mut bytes = vec![];
+        reader.read_to_end(&mut bytes)?;
+        let (text, _, _) = encoding_rs::SHIFT_JIS.decode(&bytes);
+        Self::parse(&text)
+    }
+    pub fn parse(text: &str) -> Result<Self, VpdError> {
+        //コメントを取り除いた行を行番号付きで持つ
+        let mut lines = text
+            .lines()
+            .enumerate()
+            .map(|(number, line)| {
+                let line = match line.find("//") {
+                    Some(comment) => &line[..comment],
+                    None => line,
+                };
+                (number + 1, line.trim())
+            })
+            .filter(|(_, line)| !line.is_empty());
+
+        match lines.next() {
+            Some((_, line)) if line == VPD_SIGNATURE => {}
+            _ => return Err(VpdError::InvalidSignature),
+        }
+        let mut vpd = Vpd::default();
+        let (_, model_name) = lines.next().ok_or(VpdError::InvalidSyntax(1))?;
+        vpd.model_name = model_name.trim_end_matches(';').to_owned();
+        //総ポーズボーン数は実際のブロック数と一致しないファイルもあるので読み飛ばす
+        lines.next();
+
+        while let Some((number, line)) = lines.next() {
+            let (kind, name) = line
+                .split_once('{')
+                .ok_or(VpdError::InvalidSyntax(number))?;
+            let name = name.trim().to_owned();
+            let mut values = vec![];
+            loop {
+                let (number, line) = lines.next().ok_or(VpdError::InvalidSyntax(number))?;
+                if line == "}" {
+                    break;
+                }
+                values.push(parse_floats(line).ok_or(VpdError::InvalidSyntax(number))?);
+            }
+            if kind.starts_with("Bone") {
+                match values.as_slice() {
+                    [translation, rotation] if translation.len() == 3 && rotation.len() == 4 => {
+                        vpd.bones.push(VpdBone {
+                            name,
+                            translation: [translation[0], translation[1], translation[2]],
+                            rotation: [rotation[0], rotation[1], rotation[2], rotation[3]],
+                        })
+                    }
+                    _ => return Err(VpdError::InvalidSyntax(number)),
+                }
+            } else if kind.starts_with("Morph") {
+                match values.as_slice() {
+                    [weight] if weight.len() == 1 => vpd.morphs.push(VpdMorph {
+                        name,
+                        weight: weight[0],
+                    }),
+                    _ => return Err(VpdError::InvalidSyntax(number)),
+                }
+            } else {
+                return Err(VpdError::InvalidSyntax(number));
+            }
+        }
+        Ok(vpd)
+    }
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), VpdError> {
+        self.write(std::fs::File::create(path)?)
+    }
+    pub fn write<W: Write>(&self, mut writer: W) -> Result<(), VpdError> {
+        let text = self.to_string();
+        let (bytes, _, _) = encoding_rs::SHIFT_JIS.encode(&text);
+        writer.write_all(&bytes)?;
+        writer.flush()?;
+        Ok(())
+    }
+}
+
+///MMDの出力に合わせてCRLFで書く
+impl std::fmt::Display for Vpd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}\r\n\r\n", VPD_SIGNATURE)?;
+        write!(f, "{};\t\t// 親ファイル名\r\n", self.model_name)?;
+        write!(
+            f,
+            "{};\t\t\t\t// 総ポーズボーン数\r\n\r\n",
+            self.bones.len()
+        )?;
+        for (index, bone) in self.bones.iter().enumerate() {
+            let [x, y, z] = bone.translation;
+            let [qx, qy, qz, qw] = bone.rotation;
+            write!(f, "Bone{}{{{}\r\n", index, bone.name)?;
+            write!(f, "  {:.6},{:.6},{:.6};\t\t\t\t// trans x,y,z\r\n", x, y, z)?;
+            write!(
+                f,
+                "  {:.6},{:.6},{:.6},{:.6};\t\t// Quaternion x,y,z,w\r\n}}\r\n\r\n",
+                qx, qy, qz, qw
+            )?;
+        }
+        for (index, morph) in self.morphs.iter().enumerate() {
+            write!(f, "Morph{}{{{}\r\n", index, morph.name)?;
+            write!(f, "  {:.6};\t\t\t\t// weight\r\n}}\r\n\r\n", morph.weight)?;
+        }
+        Ok(())
+    }
+}
+
+fn parse_floats(line: &str) -> Option<Vec<f32>> {
+    line.trim_end_matches(';')
+        .split(',')
+        .map(|value| value.trim().parse().ok())
+        .collect()
+}
+
+#[test]
+fn test_vpd_round_trip() {
+    let text = "Vocaloid Pose Data file\r\n\r\nmiku.osm;\t\t// 親ファイル名\r\n2;\r\n\r\n\
+                Bone0{右親指１\r\n  0.000000,0.500000,0.000000;\r\n  0.000000,0.000000,0.382683,0.923880;\r\n}\r\n\r\n\
+                Bone1{センター\r\n  1.0,2.0,3.0;\t// trans x,y,z\r\n  0,0,0,1;\r\n}\r\n\
+                Morph0{まばたき\r\n  0.5;\r\n}\r\n";
+    let vpd = Vpd::parse(text).unwrap();
+    assert_eq!(vpd.model_name, "miku.osm");
+    assert_eq!(vpd.bones.len(), 2);
+    assert_eq!(vpd.bones[0].name, "右親指１");
+    assert_eq!(vpd.bones[0].rotation, [0.0, 0.0, 0.382683, 0.92388]);
+    assert_eq!(vpd.bones[1].translation, [1.0, 2.0, 3.0]);
+    assert_eq!(vpd.morphs[0].name, "まばたき");
+    assert_eq!(vpd.morphs[0].weight, 0.5);
+
+    let mut written = vec![];
+    vpd.write(&mut written).unwrap();
+    assert_eq!(Vpd::from_reader(written.as_slice()).unwrap(), vpd);
+}
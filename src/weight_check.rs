@@ -0,0 +1,220 @@
+//! 頂点ウェイトの検査と修正
+
+use crate::pose::weight_influences;
+use crate::vertex_edit::{influences, set_weights, WeightKind};
+use PMXUtil::types::{Vertex, VertexWeight};
+
+///合計が1からこれ以上ずれていれば正規化されていないとみなす
+const NORMALIZE_TOLERANCE: f32 = 1.0e-3;
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum WeightIssue {
+    ///BDEF4/QDEFのウェイトの合計が1でない
+    NotNormalized,
+    NegativeWeight,
+    ///同じボーンが複数の枠にある
+    DuplicateBone,
+    ///ボーンリストの範囲外
+    InvalidBone,
+    ///SDEFのパラメータが数値でない、または2本のボーンが同じ
+    InvalidSdef,
+}
+
+impl WeightIssue {
+    pub const ALL: [WeightIssue; 5] = [
+        WeightIssue::NotNormalized,
+        WeightIssue::NegativeWeight,
+        WeightIssue::DuplicateBone,
+        WeightIssue::InvalidBone,
+        WeightIssue::InvalidSdef,
+    ];
+}
+
+impl std::fmt::Display for WeightIssue {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            WeightIssue::NotNormalized => write!(f, "ウェイトの合計が1ではありません"),
+            WeightIssue::NegativeWeight => write!(f, "負のウェイトがあります"),
+            WeightIssue::DuplicateBone => write!(f, "同じボーンが重複しています"),
+            WeightIssue::InvalidBone => write!(f, "存在しないボーンを参照しています"),
+            WeightIssue::InvalidSdef => write!(f, "SDEFのパラメータが不正です"),
+        }
+    }
+}
+
+///問題ごとの該当する頂点
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct WeightReport {
+    pub vertices: Vec<(WeightIssue, Vec<usize>)>,
+}
+
+impl WeightReport {
+    pub fn is_empty(&self) -> bool {
+        self.vertices
+            .iter()
+            .all(|(_, vertices)| vertices.is_empty())
+    }
+}
+
+///頂点1つ分の問題
+pub fn check_vertex(vertex: &Vertex, bone_count: usize) -> Vec<WeightIssue> {
+    let entries = weight_influences(&vertex.weight_type);
+    let used: Vec<(i32, f32)> = match vertex.weight_type {
+        VertexWeight::BDEF1(_) => entries[..1].to_vec(),
+        VertexWeight::BDEF2 { .. } | VertexWeight::SDEF { .. } => entries[..2].to_vec(),
+        _ => entries.to_vec(),
+    };
+    let mut issues = vec![];
+    if let VertexWeight::BDEF4 { .. } | VertexWeight::QDEF { .. } = vertex.weight_type {
+        let total: f32 = used.iter().map(|&(_, weight)| weight).sum();
+        if (total - 1.0).abs() > NORMALIZE_TOLERANCE {
+            issues.push(WeightIssue::NotNormalized);
+        }
+    }
+    if used.iter().any(|&(_, weight)| weight < 0.0) {
+        issues.push(WeightIssue::NegativeWeight);
+    }
+    let weighted: Vec<i32> = used
+        .iter()
+        .filter(|&&(_, weight)| weight != 0.0)
+        .map(|&(index, _)| index)
+        .collect();
+    if weighted
+        .iter()
+        .enumerate()
+        .any(|(position, index)| weighted[..position].contains(index))
+    {
+        issues.push(WeightIssue::DuplicateBone);
+    }
+    //ウェイト0の枠の-1は空きとして許す
+    if used.iter().any(|&(index, weight)| {
+        index >= bone_count as i32 || index < -1 || (index == -1 && weight != 0.0)
+    }) {
+        issues.push(WeightIssue::InvalidBone);
+    }
+    if let VertexWeight::SDEF {
+        bone_index_1,
+        bone_index_2,
+        sdef_c,
+        sdef_r0,
+        sdef_r1,
+        ..
+    } = vertex.weight_type
+    {
+        let finite = sdef_c
+            .iter()
+            .chain(sdef_r0.iter())
+            .chain(sdef_r1.iter())
+            .all(|value| value.is_finite());
+        if !finite || bone_index_1 == bone_index_2 {
+            issues.push(WeightIssue::InvalidSdef);
+        }
+    }
+    issues
+}
+
+pub fn check_weights(vertices: &[Vertex], bone_count: usize) -> WeightReport {
+    let mut report = WeightReport {
+        vertices: WeightIssue::ALL
+            .iter()
+            .map(|&issue| (issue, vec![]))
+            .collect(),
+    };
+    for (index, vertex) in vertices.iter().enumerate() {
+        for issue in check_vertex(vertex, bone_count) {
+            if let Some((_, found)) = report
+                .vertices
+                .iter_mut()
+                .find(|(other, _)| *other == issue)
+            {
+                found.push(index);
+            }
+        }
+    }
+    report
+}
+
+///該当する頂点の問題を直す
+///
+/// 範囲外のボーンは外し、ボーンが残らなければボーン0に割り当てる。
+/// 不正なSDEFはウェイトを保ったままBDEF2にし、ボーンが1本しか残らないSDEFはBDEF1にする
+pub fn fix_weights(
+    vertices: &mut [Vertex],
+    bone_count: usize,
+    issue: WeightIssue,
+    targets: &[usize],
+) {
+    for &index in targets {
+        let vertex = match vertices.get_mut(index) {
+            Some(vertex) => vertex,
+            None => continue,
+        };
+        let kind = match (issue, WeightKind::from(vertex.weight_type)) {
+            (WeightIssue::InvalidSdef, _) => WeightKind::BDEF2,
+            (_, kind) => kind,
+        };
+        let valid: Vec<(i32, f32)> = influences(&vertex.weight_type)
+            .into_iter()
+            .filter(|&(bone, _)| (bone as usize) < bone_count)
+            .collect();
+        if valid.is_empty() {
+            if bone_count > 0 {
+                vertex.weight_type = VertexWeight::BDEF1(0);
+            }
+            continue;
+        }
+        set_weights(vertex, kind, valid);
+    }
+}
+
+#[test]
+fn test_check_weights() {
+    use crate::vertex_edit::test_vertex;
+    let vertex = |weight_type: VertexWeight| test_vertex([0.0; 3], weight_type);
+    let mut vertices = vec![
+        vertex(VertexWeight::BDEF1(0)),
+        vertex(VertexWeight::BDEF4 {
+            bone_index_1: 0,
+            bone_index_2: 1,
+            bone_index_3: 1,
+            bone_index_4: -1,
+            bone_weight_1: 0.5,
+            bone_weight_2: 0.5,
+            bone_weight_3: 0.5,
+            bone_weight_4: 0.0,
+        }),
+        vertex(VertexWeight::BDEF2 {
+            bone_index_1: 5,
+            bone_index_2: 0,
+            bone_weight_1: 1.5,
+        }),
+        vertex(VertexWeight::SDEF {
+            bone_index_1: 5,
+            bone_index_2: 1,
+            bone_weight_1: 0.5,
+            sdef_c: [0.0; 3],
+            sdef_r0: [0.0; 3],
+            sdef_r1: [0.0; 3],
+        }),
+    ];
+    let report = check_weights(&vertices, 2);
+    let found = |issue: WeightIssue| {
+        report
+            .vertices
+            .iter()
+            .find(|(other, _)| *other == issue)
+            .map(|(_, vertices)| vertices.clone())
+            .unwrap()
+    };
+    assert_eq!(found(WeightIssue::NotNormalized), vec![1]);
+    assert_eq!(found(WeightIssue::DuplicateBone), vec![1]);
+    assert_eq!(found(WeightIssue::NegativeWeight), vec![2]);
+    assert_eq!(found(WeightIssue::InvalidBone), vec![2, 3]);
+
+    fix_weights(&mut vertices, 2, WeightIssue::DuplicateBone, &[1]);
+    fix_weights(&mut vertices, 2, WeightIssue::InvalidBone, &[2, 3]);
+    assert!(check_weights(&vertices, 2).is_empty());
+    assert_eq!(vertices[2].weight_type, VertexWeight::BDEF1(0));
+    //ボーンが1本しか残らないSDEFはBDEF1になる
+    assert_eq!(vertices[3].weight_type, VertexWeight::BDEF1(1));
+}
@@ -0,0 +1,195 @@
+//! ブラシで頂点ウェイトを塗る
+
+use crate::math::{length, sub, Vec3};
+use crate::vertex_edit::{assign_weight, bone_weight};
+use PMXUtil::types::{Face, Vertex};
+
+#[derive(Debug, Copy, Clone, Eq, PartialEq)]
+pub enum BrushMode {
+    Add,
+    Subtract,
+    ///周りの頂点の平均に近づける
+    Smooth,
+    ///`Brush::weight`に近づける
+    Replace,
+}
+impl BrushMode {
+    pub const ALL: [BrushMode; 4] = [
+        BrushMode::Add,
+        BrushMode::Subtract,
+        BrushMode::Smooth,
+        BrushMode::Replace,
+    ];
+    pub fn label(self) -> &'static str {
+        match self {
+            BrushMode::Add => "加算",
+            BrushMode::Subtract => "減算",
+            BrushMode::Smooth => "ぼかし",
+            BrushMode::Replace => "置換",
+        }
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq)]
+pub struct Brush {
+    pub mode: BrushMode,
+    ///ワールドでの半径
+    pub radius: f32,
+    ///0なら範囲内で一定、1なら縁に向かって滑らかに弱くなる
+    pub falloff: f32,
+    ///1回の塗りで変える量
+    pub strength: f32,
+    pub weight: f32,
+    ///X=0を挟んだ反対側も左右反転したボーンで塗る
+    pub mirror_x: bool,
+}
+impl Default for Brush {
+    fn default() -> Self {
+        Self {
+            mode: BrushMode::Add,
+            radius: 1.0,
+            falloff: 1.0,
+            strength: 0.1,
+            weight: 1.0,
+            mirror_x: false,
+        }
+    }
+}
+impl Brush {
+    ///中心からの距離での効き。範囲外は0
+    pub fn influence(&self, distance: f32) -> f32 {
+        if distance >= self.radius {
+            return 0.0;
+        }
+        let t = distance / self.radius;
+        let smooth = 1.0 - t * t * (3.0 - 2.0 * t);
+        self.strength * ((1.0 - self.falloff) + self.falloff * smooth)
+    }
+}
+
+///面でつながっている頂点
+pub fn vertex_neighbors(vertex_count: usize, faces: &[Face]) -> Vec<Vec<usize>> {
+    let mut neighbors = vec![vec![]; vertex_count];
+    for face in faces {
+        for corner in 0..3 {
+            let from = face.vertices[corner] as usize;
+            let to = face.vertices[(corner + 1) % 3] as usize;
+            if from >= vertex_count || to >= vertex_count {
+                continue;
+            }
+            if !neighbors[from].contains(&to) {
+                neighbors[from].push(to);
+            }
+            if !neighbors[to].contains(&from) {
+                neighbors[to].push(from);
+            }
+        }
+    }
+    neighbors
+}
+
+///`centers`の周りの頂点のボーンのウェイトを塗る。変えた頂点の数を返す
+///
+/// 距離は`positions`(変形後の位置など)で測る。
+/// ブラシが重なった頂点は最も近い中心の強さで1回だけ塗る
+pub fn paint(
+    vertices: &mut [Vertex],
+    positions: &[Vec3],
+    neighbors: &[Vec<usize>],
+    centers: &[Vec3],
+    bone: i32,
+    brush: &Brush,
+) -> usize {
+    let targets: Vec<(usize, f32)> = positions
+        .iter()
+        .enumerate()
+        .take(vertices.len())
+        .map(|(index, &position)| {
+            let factor = centers
+                .iter()
+                .map(|&center| brush.influence(length(sub(position, center))))
+                .fold(0.0, f32::max);
+            (index, factor)
+        })
+        .filter(|&(_, factor)| factor > 0.0)
+        .collect();
+    //ぼかしは塗る前の値の平均に近づける
+    let averages: Vec<f32> = match brush.mode {
+        BrushMode::Smooth => targets
+            .iter()
+            .map(|&(index, _)| {
+                let around = neighbors.get(index).map_or(&[][..], |around| &around[..]);
+                let total: f32 = around
+                    .iter()
+                    .map(|&other| bone_weight(&vertices[other].weight_type, bone))
+                    .sum();
+                if around.is_empty() {
+                    bone_weight(&vertices[index].weight_type, bone)
+                } else {
+                    total / around.len() as f32
+                }
+            })
+            .collect(),
+        _ => vec![],
+    };
+    let mut changed = 0;
+    for (position, &(index, factor)) in targets.iter().enumerate() {
+        let current = bone_weight(&vertices[index].weight_type, bone);
+        let painted = match brush.mode {
+            BrushMode::Add => current + factor,
+            BrushMode::Subtract => current - factor,
+            BrushMode::Smooth => current + (averages[position] - current) * factor.min(1.0),
+            BrushMode::Replace => current + (brush.weight - current) * factor.min(1.0),
+        }
+        .clamp(0.0, 1.0);
+        if (painted - current).abs() > 1.0e-6 {
+            //ボーン1本だけの頂点は減らした分の移し先がなく、正規化で元に戻る
+            let before = vertices[index].weight_type;
+            assign_weight(&mut vertices[index], bone, painted);
+            if vertices[index].weight_type != before {
+                changed += 1;
+            }
+        }
+    }
+    changed
+}
+
+#[test]
+fn test_paint() {
+    use crate::vertex_edit::test_vertex;
+    use PMXUtil::types::VertexWeight;
+    let vertex = |x: f32| test_vertex([x, 0.0, 0.0], VertexWeight::BDEF1(0));
+    let mut vertices = vec![vertex(0.0), vertex(5.0)];
+    let positions: Vec<Vec3> = vertices.iter().map(|vertex| vertex.position).collect();
+    let brush = Brush {
+        falloff: 0.0,
+        strength: 0.25,
+        ..Brush::default()
+    };
+    //範囲内の頂点だけ塗られ、BDEF1からBDEF2になる。左右のブラシが重なっても1回だけ塗る
+    let centers = [[0.2, 0.0, 0.0], [-0.2, 0.0, 0.0]];
+    assert_eq!(
+        paint(&mut vertices, &positions, &[], &centers, 1, &brush),
+        1
+    );
+    assert_eq!(bone_weight(&vertices[0].weight_type, 1), 0.25);
+    assert_eq!(bone_weight(&vertices[0].weight_type, 0), 0.75);
+    assert_eq!(vertices[1].weight_type, VertexWeight::BDEF1(0));
+    //BDEF1の頂点から減らしても変わらない
+    let brush = Brush {
+        mode: BrushMode::Subtract,
+        ..brush
+    };
+    assert_eq!(
+        paint(
+            &mut vertices,
+            &positions,
+            &[],
+            &[[5.0, 0.0, 0.0]],
+            0,
+            &brush
+        ),
+        0
+    );
+    assert_eq!(vertices[1].weight_type, VertexWeight::BDEF1(0));
+}